@@ -1,5 +1,8 @@
-use crate::language::Math;
-use egg::{Analysis, DidMerge, EGraph, Id};
+use crate::language::{Constant, Math};
+use crate::tower::Tower;
+use egg::{Analysis, DidMerge, EGraph, Id, Language, RecExpr};
+use num_bigint::BigInt;
+use num_traits::{One, Signed, Zero};
 use std::collections::HashMap;
 use serde::{Serialize};
 
@@ -63,13 +66,11 @@ impl FieldType {
                 .map_err(|_| format!("Invalid degree in field type: {}", s))?;
             if degree == 1 {
                 Ok(FieldType::Fp)
-            } else if degree.is_power_of_two() {
-                Ok(FieldType::FpExt(degree))
-            } else if degree.is_multiple_of(2) {
+            } else if is_tower_degree(degree) {
                 Ok(FieldType::FpExt(degree))
             }
             else {
-                Err(format!("Field degree must be a multiple of 2: {}", degree))
+                Err(format!("Field degree must be a product of 2s and 3s: {}", degree))
             }
         } else if s=="constant" {
             Ok(FieldType::Constant)
@@ -88,6 +89,58 @@ impl FieldType {
     }
 }
 
+/// Miller-Rabin with the first twelve primes as witnesses. It is exact below
+/// 3.3·10^24, above that only a composite built to fool these witnesses passes.
+pub fn is_probable_prime(n: &BigInt) -> bool {
+    const WITNESSES: [u32; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+    if *n < BigInt::from(2) {
+        return false;
+    }
+    for &w in &WITNESSES {
+        let w = BigInt::from(w);
+        if *n == w {
+            return true;
+        }
+        if (n % &w).is_zero() {
+            return false;
+        }
+    }
+
+    // n - 1 = d·2^s with d odd
+    let one = BigInt::one();
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().unwrap();
+    let d = &n_minus_one >> s;
+    'witnesses: for &w in &WITNESSES {
+        let mut x = BigInt::from(w).modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = &x * &x % n;
+            if x == n_minus_one {
+                continue 'witnesses;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+// Degrees a tower of quadratic and cubic extensions can reach.
+fn is_tower_degree(mut degree: u32) -> bool {
+    if degree == 0 {
+        return false;
+    }
+    while degree.is_multiple_of(2) {
+        degree /= 2;
+    }
+    while degree.is_multiple_of(3) {
+        degree /= 3;
+    }
+    degree == 1
+}
+
 // Helper function to compute LCM
 fn lcm(a: u32, b: u32) -> u32 {
     a * b / gcd(a, b)
@@ -108,12 +161,44 @@ impl<'de> serde::Deserialize<'de> for FieldType {
     }
 }
 
+/// What the analysis knows about an e-class.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClassData {
+    pub ty: FieldType,
+    /// The value of the class, if it is a constant, reduced modulo the
+    /// characteristic when there is one.
+    pub constant: Option<Constant>,
+}
+
 #[derive(Clone)]
 pub struct TypeAnalysis {
     /// User‐provided map: symbol → its field type
     pub symbol_types: HashMap<String, FieldType>,
     /// Upper bound on extension degree (optional clamp)
     pub max_degree: u32,
+    /// Characteristic p of the field. Without it, constants are still folded
+    /// exactly over the integers, but only ±1 can be inverted.
+    pub modulus: Option<BigInt>,
+    /// The non-residues of the tower levels.
+    pub non_residues: Vec<NonResidue>,
+}
+
+/// The non-residue of a tower level, and the field of the level if it says which.
+#[derive(Clone, Debug)]
+pub struct NonResidue {
+    pub term: RecExpr<Math>,
+    pub over: Option<FieldType>,
+}
+
+impl NonResidue {
+    fn root(&self) -> Id {
+        Id::from(self.term.as_ref().len() - 1)
+    }
+
+    // A symbol or a constant has its own type, a term gets its level's.
+    fn is_term(&self) -> bool {
+        !matches!(self.term[self.root()], Math::Symbol(_) | Math::Constant(_))
+    }
 }
 
 impl TypeAnalysis {
@@ -126,9 +211,143 @@ impl TypeAnalysis {
         TypeAnalysis {
             symbol_types,
             max_degree,
+            modulus: None,
+            non_residues: vec![],
+        }
+    }
+
+    /// Take the non-residues of `tower`, typed in the field of their level
+    /// when it says which.
+    pub fn with_tower(mut self, tower: &Tower) -> Self {
+        for level in &tower.levels {
+            if let Some(symbol) = level.non_residue_symbol() {
+                if let Some(over) = &level.over {
+                    self.symbol_types.insert(symbol.to_string(), over.clone());
+                }
+            }
+            // The tower is validated before it gets here.
+            if let Ok(term) = level.non_residue.parse() {
+                self.non_residues.push(NonResidue {
+                    term,
+                    over: level.over.clone(),
+                });
+            }
+        }
+        self
+    }
+
+    /// Whether the class `id` is the class of a non-residue of the tower,
+    /// however it is written: a symbol, a constant (modulo the characteristic)
+    /// or a term.
+    pub fn is_non_residue(egraph: &EGraph<Math, TypeAnalysis>, id: Id) -> bool {
+        let analysis = &egraph.analysis;
+        analysis
+            .non_residues
+            .iter()
+            .any(|nr| analysis.class_is(egraph, id, &nr.term, nr.root()))
+    }
+
+    /// The field of the level whose non-residue is the term `enode` builds.
+    pub fn non_residue_type(
+        egraph: &EGraph<Math, TypeAnalysis>,
+        enode: &Math,
+    ) -> Option<FieldType> {
+        let analysis = &egraph.analysis;
+        analysis
+            .non_residues
+            .iter()
+            .filter(|nr| nr.is_term())
+            .find(|nr| analysis.node_is(egraph, enode, &nr.term, nr.root()))
+            .and_then(|nr| nr.over.clone())
+    }
+
+    // Whether the class `id` holds `term` at `index`. Follows the term, so it
+    // ends even on cyclic egraphs.
+    fn class_is(
+        &self,
+        egraph: &EGraph<Math, TypeAnalysis>,
+        id: Id,
+        term: &RecExpr<Math>,
+        index: Id,
+    ) -> bool {
+        match &term[index] {
+            Math::Constant(c) => egraph[id]
+                .data
+                .constant
+                .as_ref()
+                .is_some_and(|value| value.0 == self.reduce(c.0.clone())),
+            _ => egraph[id]
+                .nodes
+                .iter()
+                .any(|node| self.node_is(egraph, node, term, index)),
+        }
+    }
+
+    // Whether `enode` builds `term` at `index` out of its children's classes.
+    fn node_is(
+        &self,
+        egraph: &EGraph<Math, TypeAnalysis>,
+        enode: &Math,
+        term: &RecExpr<Math>,
+        index: Id,
+    ) -> bool {
+        match (enode, &term[index]) {
+            (Math::Constant(a), Math::Constant(b)) => {
+                self.reduce(a.0.clone()) == self.reduce(b.0.clone())
+            }
+            (_, pattern) => {
+                enode.matches(pattern)
+                    && enode
+                        .children()
+                        .iter()
+                        .zip(pattern.children())
+                        .all(|(&child, &sub)| self.class_is(egraph, child, term, sub))
+            }
         }
     }
 
+    /// Fold constants modulo `modulus`, which should be a prime (see `is_probable_prime`).
+    pub fn with_modulus(mut self, modulus: Option<BigInt>) -> Self {
+        self.modulus = modulus;
+        self
+    }
+
+    // `value` in [0, p) if there is a characteristic.
+    pub fn reduce(&self, value: BigInt) -> BigInt {
+        match &self.modulus {
+            Some(p) => {
+                let value = value % p;
+                if value.is_negative() { value + p } else { value }
+            }
+            None => value,
+        }
+    }
+
+    fn invert(&self, value: &BigInt) -> Option<BigInt> {
+        match &self.modulus {
+            Some(p) => value.modinv(p),
+            None if value.abs().is_one() => Some(value.clone()),
+            None => None,
+        }
+    }
+
+    /// The value of `enode` if all of its children are constants.
+    fn fold(&self, egraph: &EGraph<Math, TypeAnalysis>, enode: &Math) -> Option<Constant> {
+        let value = |id: &Id| egraph[*id].data.constant.as_ref().map(|c| &c.0);
+        let folded = match enode {
+            Math::Constant(c) => c.0.clone(),
+            Math::Add([a, b]) => value(a)? + value(b)?,
+            Math::Sub([a, b]) => value(a)? - value(b)?,
+            Math::Mul([a, b]) => value(a)? * value(b)?,
+            Math::Sq(x) => value(x)? * value(x)?,
+            Math::Inv(x) => self.invert(value(x)?)?,
+            Math::Pair(_) | Math::Fst(_) | Math::Snd(_) => return None,
+            Math::Triple(_) | Math::C0(_) | Math::C1(_) | Math::C2(_) => return None,
+            Math::Symbol(_) => return None,
+        };
+        Some(Constant(self.reduce(folded)))
+    }
+
     /// If you explicitly want to clamp all LCMs at a certain maximum:
     pub fn with_max_degree(mut self, max_degree: u32) -> Self {
         self.max_degree = max_degree;
@@ -137,23 +356,25 @@ impl TypeAnalysis {
 
     /// Determine the result type of a pair operation
     fn pair_result_type(&self, a: &FieldType, b: &FieldType) -> FieldType {
-        // For now, pair(Fp, Fp) -> Fp2, pair(Fp2, Fp2) -> Fp4, etc.
-        // This assumes we're constructing extension fields by pairing
+        // pair(Fp, Fp) -> Fp2, pair(Fp2, Fp2) -> Fp4, pair(Fp6, Fp6) -> Fp12, etc.
+        // The constructed extension isn't clamped, it's the type of the element.
         match (a, b) {
             (FieldType::Fp, FieldType::Fp) => FieldType::FpExt(2),
-            (FieldType::FpExt(n1), FieldType::FpExt(n2)) if n1 == n2 => {
-                let new_degree = n1 * 2;
-                if new_degree <= self.max_degree {
-                    FieldType::FpExt(new_degree)
-                } else {
-                    // If we exceed max degree, stay in the LCM extension
-                    a.lcm_extension(b)
-                }
-            }
+            (FieldType::FpExt(n1), FieldType::FpExt(n2)) if n1 == n2 => FieldType::FpExt(n1 * 2),
             _ => a.lcm_extension(b),
         }
     }
 
+    /// Determine the result type of a triple operation
+    fn triple_result_type(&self, a: &FieldType, b: &FieldType, c: &FieldType) -> FieldType {
+        // triple(Fp, Fp, Fp) -> Fp3, triple(Fp2, Fp2, Fp2) -> Fp6, etc.
+        match (a, b, c) {
+            (FieldType::Fp, FieldType::Fp, FieldType::Fp) => FieldType::FpExt(3),
+            (FieldType::FpExt(n), _, _) if a == b && b == c => FieldType::FpExt(n * 3),
+            _ => a.lcm_extension(b).lcm_extension(c),
+        }
+    }
+
     /// Determine if a field operation should promote to a larger field
     fn operation_result_type(&self, args: &[&FieldType]) -> FieldType {
         if args.is_empty() {
@@ -166,12 +387,13 @@ impl TypeAnalysis {
             acc = acc.lcm_extension(t);
         }
 
-        // Optionally clamp to max_degree:
+        // Optionally clamp to max_degree, but never below an operand's own field:
+        let bound = args.iter().map(|t| t.degree()).fold(self.max_degree, u32::max);
         match &acc {
             FieldType::Fp => FieldType::Fp,
             FieldType::FpExt(d) => {
                 let raw = *d;
-                let clamped = u32::min(raw, self.max_degree);
+                let clamped = u32::min(raw, bound);
                 // If you want the “next power‐of‐two below max_degree,” you'd need
                 // additional logic here. In this template we do a hard clamp.
                 if clamped == 1 {
@@ -186,21 +408,79 @@ impl TypeAnalysis {
 }
 
 impl Analysis<Math> for TypeAnalysis {
-    type Data = FieldType;
+    type Data = ClassData;
+
+    fn make(egraph: &mut EGraph<Math, TypeAnalysis>, enode: &Math) -> ClassData {
+        ClassData {
+            ty: Self::make_type(egraph, enode),
+            constant: egraph.analysis.fold(egraph, enode),
+        }
+    }
+
+    fn merge(&mut self, to: &mut ClassData, from: ClassData) -> DidMerge {
+        let DidMerge(ty_to, ty_from) = self.merge_types(&mut to.ty, from.ty);
+        let constant = match (&to.constant, from.constant) {
+            (None, Some(c)) => {
+                to.constant = Some(c);
+                DidMerge(true, false)
+            }
+            (Some(_), None) => DidMerge(false, true),
+            (Some(a), Some(b)) => {
+                // Only an unsound rule can make two constants equal.
+                if *a != b {
+                    log::warn!("Merged classes with different constants {} and {}", a, b);
+                }
+                DidMerge(false, false)
+            }
+            (None, None) => DidMerge(false, false),
+        };
+        DidMerge(ty_to || constant.0, ty_from || constant.1)
+    }
+
+    fn modify(egraph: &mut EGraph<Math, TypeAnalysis>, id: Id) {
+        // Add the folded literal, so rules and the cost model see the constant.
+        if let Some(c) = egraph[id].data.constant.clone() {
+            let literal = egraph.add(Math::Constant(c));
+            egraph.union(id, literal);
+        }
+    }
+}
 
-    fn make(egraph: &mut EGraph<Math, TypeAnalysis>, enode: &Math) -> FieldType {
+impl TypeAnalysis {
+    fn make_type(egraph: &mut EGraph<Math, TypeAnalysis>, enode: &Math) -> FieldType {
+        // A non-residue written as a term, say (triple 0 1 0), is in its level's field.
+        if let Some(over) = Self::non_residue_type(egraph, enode) {
+            return over;
+        }
         match enode {
 
             // Pair operations create extension fields
             Math::Pair([a, b]) => {
-                let type_a = &egraph[*a].data;
-                let type_b = &egraph[*b].data;
+                let type_a = &egraph[*a].data.ty;
+                let type_b = &egraph[*b].data.ty;
                 egraph.analysis.pair_result_type(type_a, type_b)
             }
 
+            // Triple operations create cubic extension fields
+            Math::Triple([a, b, c]) => {
+                let type_a = &egraph[*a].data.ty;
+                let type_b = &egraph[*b].data.ty;
+                let type_c = &egraph[*c].data.ty;
+                egraph.analysis.triple_result_type(type_a, type_b, type_c)
+            }
+
+            // Coefficient projections from cubic extensions (a third of the degree)
+            Math::C0(id) | Math::C1(id) | Math::C2(id) => {
+                match &egraph[*id].data.ty {
+                    FieldType::FpExt(3) => FieldType::Fp,
+                    FieldType::FpExt(n) if n % 3 == 0 => FieldType::FpExt(n / 3),
+                    _ => FieldType::Fp,
+                }
+            }
+
             // First/second projections from extension fields
             Math::Fst(id) | Math::Snd(id) => {
-                let input_type = &egraph[*id].data;
+                let input_type = &egraph[*id].data.ty;
                 match input_type {
                     FieldType::FpExt(n) if *n > 1 => {
                         // Project to a smaller field (half the degree)
@@ -217,13 +497,13 @@ impl Analysis<Math> for TypeAnalysis {
             
             // Binary ops: take the LCM of operand types
             Math::Add([a, b]) | Math::Sub([a, b]) | Math::Mul([a, b]) => {
-                let t1 = &egraph[*a].data;
-                let t2 = &egraph[*b].data;
+                let t1 = &egraph[*a].data.ty;
+                let t2 = &egraph[*b].data.ty;
                 egraph.analysis.operation_result_type(&[t1, t2])
             }
 
             // Unary ops: preserve the child’s type
-            Math::Inv(x) | Math::Sq(x) => egraph[*x].data.clone(),
+            Math::Inv(x) | Math::Sq(x) => egraph[*x].data.ty.clone(),
 
             // Constants always live in base field
             Math::Constant(_) => FieldType::Constant,
@@ -240,14 +520,15 @@ impl Analysis<Math> for TypeAnalysis {
         }
     }
 
-    fn merge(&mut self, to: &mut FieldType, from: FieldType) -> DidMerge {
+    fn merge_types(&self, to: &mut FieldType, from: FieldType) -> DidMerge {
         if *to != from {
             // take LCM
             let mut new_ty = to.lcm_extension(&from);
-            // clamp if needed:
+            // clamp if needed, but not below either side:
+            let bound = self.max_degree.max(to.degree()).max(from.degree());
             if let FieldType::FpExt(d) = &new_ty {
-                if *d > self.max_degree {
-                    new_ty = FieldType::FpExt(self.max_degree);
+                if *d > bound {
+                    new_ty = FieldType::FpExt(bound);
                 }
             }
             if new_ty != *to {
@@ -260,10 +541,6 @@ impl Analysis<Math> for TypeAnalysis {
             DidMerge(false, false)
         }
     }
-
-    fn modify(_egraph: &mut EGraph<Math, TypeAnalysis>, _id: Id) {
-        // no extra “post‐merge” rewriting
-    }
 }
 
 impl Default for TypeAnalysis {
@@ -271,6 +548,8 @@ impl Default for TypeAnalysis {
         TypeAnalysis {
             symbol_types: HashMap::new(),
             max_degree: 8, // default upper bound
+            modulus: None,
+            non_residues: vec![],
         }
     }
 }
@@ -286,7 +565,12 @@ mod tests {
         assert_eq!(FieldType::from_str("fp4").unwrap(), FieldType::FpExt(4));
         assert_eq!(FieldType::from_str("fp8").unwrap(), FieldType::FpExt(8));
 
-        assert!(FieldType::from_str("fp3").is_err()); // Not power of 2
+        assert_eq!(FieldType::from_str("fp3").unwrap(), FieldType::FpExt(3));
+        assert_eq!(FieldType::from_str("fp6").unwrap(), FieldType::FpExt(6));
+        assert_eq!(FieldType::from_str("fp12").unwrap(), FieldType::FpExt(12));
+
+        assert!(FieldType::from_str("fp5").is_err()); // Not a tower of 2s and 3s
+        assert!(FieldType::from_str("fp0").is_err());
         assert!(FieldType::from_str("invalid").is_err());
     }
 
@@ -314,4 +598,63 @@ mod tests {
         assert_eq!(fp2.lcm_extension(&fp4), fp4);
         assert_eq!(fp2.lcm_extension(&FieldType::FpExt(8)), FieldType::FpExt(8));
     }
+
+    #[test]
+    fn test_cubic_extension_types() {
+        let symbols = ["a0", "a1", "a2"]
+            .iter()
+            .map(|s| (s.to_string(), FieldType::FpExt(2)))
+            .collect();
+        let mut egraph = EGraph::new(TypeAnalysis::new(symbols));
+        let fp6 = egraph.add_expr(&"(triple a0 a1 a2)".parse().unwrap());
+        let fp12 = egraph.add_expr(&"(pair (triple a0 a1 a2) (triple a2 a1 a0))".parse().unwrap());
+        let coefficient = egraph.add_expr(&"(c1 (triple a0 a1 a2))".parse().unwrap());
+        let sum = egraph.add_expr(&"(+ (triple a0 a1 a2) (triple a2 a1 a0))".parse().unwrap());
+
+        assert_eq!(egraph[fp6].data.ty, FieldType::FpExt(6));
+        assert_eq!(egraph[fp12].data.ty, FieldType::FpExt(12));
+        assert_eq!(egraph[coefficient].data.ty, FieldType::FpExt(2));
+        assert_eq!(egraph[sum].data.ty, FieldType::FpExt(6));
+    }
+
+    #[test]
+    fn test_constant_folding_modulo_p() {
+        let symbols = HashMap::from([("a".to_string(), FieldType::Fp)]);
+        let analysis = TypeAnalysis::new(symbols).with_modulus(Some(BigInt::from(7)));
+        let mut egraph = EGraph::new(analysis);
+
+        let product = egraph.add_expr(&"(* 3 5)".parse().unwrap());
+        let one = egraph.add_expr(&"1".parse().unwrap());
+        egraph.rebuild();
+        assert_eq!(egraph.find(product), egraph.find(one));
+
+        let inverse = egraph.add_expr(&"(inv 3)".parse().unwrap());
+        egraph.rebuild();
+        assert_eq!(egraph[inverse].data.constant, Some(Constant::from(5)));
+
+        let scaled = egraph.add_expr(&"(* 2 (* 3 a))".parse().unwrap());
+        let runner = egg::Runner::default()
+            .with_egraph(egraph)
+            .with_iter_limit(5)
+            .run(&crate::rules::rules(&Tower::default())
+                .into_iter()
+                .map(|rule| rule.rewrite)
+                .collect::<Vec<_>>());
+        let six_a = runner.egraph.lookup_expr(&"(* 6 a)".parse().unwrap());
+        assert_eq!(six_a.map(|id| runner.egraph.find(id)), Some(runner.egraph.find(scaled)));
+    }
+
+    #[test]
+    fn test_modulus_primality() {
+        let bn254: BigInt = crate::soundness::DEFAULT_CHARACTERISTIC.parse().unwrap();
+        for p in [BigInt::from(2), BigInt::from(7), BigInt::from(1_000_000_007), bn254.clone()] {
+            assert!(is_probable_prime(&p), "{} is a prime", p);
+        }
+        // 561 is a Carmichael number, it fools Fermat's test for every coprime base.
+        for n in [0i64, 1, 15, 561, 1_000_000_007 * 3] {
+            assert!(!is_probable_prime(&BigInt::from(n)), "{} isn't a prime", n);
+        }
+        assert!(!is_probable_prime(&(&bn254 * &bn254)));
+        assert!(!is_probable_prime(&(&bn254 * BigInt::from(1_000_000_007))));
+    }
 }
@@ -0,0 +1,603 @@
+/*
+Produces a dag-cost optimal extraction of an Egraph without an external solver.
+
+It's a depth-first branch and bound over the choice of node in each class:
+1) The greedy DAG extractor provides the first incumbent, so only extractions that beat
+it are explored.
+2) The search keeps a set of "pending" classes, the classes some selected node depends upon
+that don't have a node selected yet. It repeatedly picks the pending class with the fewest
+candidates and tries each of its nodes, cheapest first.
+3) Because each class is only paid for once in the DAG cost, the cost so far plus the cheapest
+node of every pending class is a lower bound on any completion. Branches whose bound can't
+beat the incumbent are cut.
+4) A class that is a child of every candidate node of a pending class must be selected too,
+so it is made pending straight away, which tightens the bound early.
+5) When a node is selected, we check that none of its children can already reach its class
+through the selected nodes, so the extraction never contains a cycle.
+6) Forbidden nodes are never candidates, and a node is only selected if that keeps every
+count cap of the constraints.
+
+If the timeout is hit, the best extraction found so far is returned.
+*/
+
+use std::time::{Duration, Instant};
+
+use egraph_serialize::*;
+use indexmap::IndexMap;
+use rustc_hash::FxHashSet;
+
+use crate::constraints::NodeConstraints;
+use crate::extractor_structures::*;
+use crate::faster_greedy_dag::FasterGreedyDagExtractor;
+
+// How many search nodes are expanded between looking at the clock.
+const CLOCK_INTERVAL: u64 = 1024;
+
+pub struct BranchAndBoundDagExtractor {
+    pub timeout_in_seconds: u32,
+    pub constraints: NodeConstraints,
+}
+
+impl Extractor for BranchAndBoundDagExtractor {
+    fn extract(&self, egraph: &EGraph, roots: &[ClassId]) -> ExtractionResult {
+        let initial_result = FasterGreedyDagExtractor {
+            constraints: self.constraints.clone(),
+        }
+        .extract(egraph, roots);
+        // Without a valid greedy extraction, start from no incumbent at all.
+        let initial_result_cost = initial_result
+            .dag_cost(egraph, roots)
+            .map_or(f64::INFINITY, |cost| cost.into_inner());
+
+        let mut search = Search::new(egraph, roots, &self.constraints, self.timeout_in_seconds);
+        if search.infeasible {
+            log::info!("Infeasible, a root has no possible nodes");
+            return ExtractionResult::infeasible();
+        }
+        search.best_cost = initial_result_cost + EPSILON_ALLOWANCE;
+        search.run();
+
+        log::info!(
+            "Branch and bound expanded {} nodes, timed out: {}",
+            search.expanded,
+            search.timed_out
+        );
+
+        match search.best {
+            Some(best) => {
+                let mut result = ExtractionResult::default();
+                for (class, node) in best.iter().enumerate() {
+                    if let Some(node) = node {
+                        result.choose(
+                            search.class_ids[class].clone(),
+                            search.candidates[class][*node].id.clone(),
+                        );
+                    }
+                }
+                result.status = if search.timed_out {
+                    ExtractionStatus::ImprovedOnTimeout
+                } else {
+                    ExtractionStatus::Optimal
+                };
+                result
+            }
+            // Nothing beat the greedy extraction, or nothing meets the constraints
+            // if the greedy extraction didn't either, which the search proves.
+            None if !search.timed_out => match initial_result.status {
+                ExtractionStatus::GreedyGaveUp => ExtractionResult::infeasible(),
+                _ => ExtractionResult {
+                    status: ExtractionStatus::Optimal,
+                    ..initial_result
+                },
+            },
+            None => initial_result,
+        }
+    }
+}
+
+struct Candidate {
+    id: NodeId,
+    cost: f64,
+    children: Vec<usize>,
+    // The count caps the node counts towards.
+    caps: Vec<usize>,
+}
+
+// A class taken out of the pending ones to branch on.
+struct Frame {
+    class: usize,
+    // Where the class was in `pending`.
+    position: usize,
+    // The next candidate to try.
+    next: usize,
+    // While candidate `next - 1` is selected, the classes it made pending.
+    added: Option<Vec<usize>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Untouched,
+    Pending,
+    Chosen,
+}
+
+struct Search {
+    class_ids: Vec<ClassId>,
+    // Candidate nodes of each class, cheapest (including cheapest children) first.
+    candidates: Vec<Vec<Candidate>>,
+    // Cheapest candidate of each class.
+    lower_bound: Vec<f64>,
+    // Classes that are children of every candidate of the class.
+    mandatory: Vec<Vec<usize>>,
+    cap_max: Vec<usize>,
+
+    state: Vec<State>,
+    choice: Vec<Option<usize>>,
+    pending: Vec<usize>,
+    cost: f64,
+    pending_bound: f64,
+    cap_count: Vec<usize>,
+
+    best: Option<Vec<Option<usize>>>,
+    best_cost: f64,
+
+    infeasible: bool,
+    deadline: Instant,
+    timed_out: bool,
+    expanded: u64,
+
+    // Scratch space for the reachability check.
+    visit_stamp: Vec<u64>,
+    stamp: u64,
+}
+
+impl Search {
+    fn new(
+        egraph: &EGraph,
+        roots: &[ClassId],
+        constraints: &NodeConstraints,
+        timeout_in_seconds: u32,
+    ) -> Self {
+        let n2c = |nid: &NodeId| egraph.nid_to_cid(nid);
+
+        // Only the classes reachable from the roots matter.
+        let mut index: IndexMap<ClassId, usize> = IndexMap::new();
+        let mut todo: Vec<ClassId> = roots.to_vec();
+        while let Some(cid) = todo.pop() {
+            if index.contains_key(&cid) {
+                continue;
+            }
+            index.insert(cid.clone(), index.len());
+            for node in &egraph.classes()[&cid].nodes {
+                for child in &egraph[node].children {
+                    todo.push(n2c(child).clone());
+                }
+            }
+        }
+
+        let mut candidates: Vec<Vec<Candidate>> = index
+            .keys()
+            .enumerate()
+            .map(|(class, cid)| {
+                egraph.classes()[cid]
+                    .nodes
+                    .iter()
+                    .filter_map(|nid| {
+                        let node = &egraph[nid];
+                        let mut children: Vec<usize> =
+                            node.children.iter().map(|c| index[n2c(c)]).collect();
+                        children.sort();
+                        children.dedup();
+                        // A node that needs its own class can never be selected.
+                        if children.contains(&class) || !constraints.allows(nid) {
+                            return None;
+                        }
+                        Some(Candidate {
+                            id: nid.clone(),
+                            cost: node.cost.into_inner(),
+                            children,
+                            caps: (0..constraints.caps.len())
+                                .filter(|&c| constraints.caps[c].nodes.contains(nid))
+                                .collect(),
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Nodes pointing to an empty class can't be selected either.
+        loop {
+            let empty: FxHashSet<usize> = (0..candidates.len())
+                .filter(|&c| candidates[c].is_empty())
+                .collect();
+            let mut removed = false;
+            for class in candidates.iter_mut() {
+                let before = class.len();
+                class.retain(|n| !n.children.iter().any(|c| empty.contains(c)));
+                removed |= class.len() != before;
+            }
+            if !removed {
+                break;
+            }
+        }
+
+        let lower_bound: Vec<f64> = candidates
+            .iter()
+            .map(|class| class.iter().map(|n| n.cost).fold(f64::INFINITY, f64::min))
+            .collect();
+
+        for class in candidates.iter_mut() {
+            class.sort_by(|a, b| {
+                let a_cost = a.cost + a.children.iter().map(|&c| lower_bound[c]).sum::<f64>();
+                let b_cost = b.cost + b.children.iter().map(|&c| lower_bound[c]).sum::<f64>();
+                a_cost.total_cmp(&b_cost)
+            });
+        }
+
+        let mandatory = candidates
+            .iter()
+            .map(|class| match class.split_first() {
+                None => vec![],
+                Some((first, rest)) => first
+                    .children
+                    .iter()
+                    .copied()
+                    .filter(|c| rest.iter().all(|n| n.children.contains(c)))
+                    .collect(),
+            })
+            .collect();
+
+        let num_classes = index.len();
+        let mut roots: Vec<usize> = roots.iter().map(|r| index[r]).collect();
+        roots.sort();
+        roots.dedup();
+        let infeasible = roots.iter().any(|&r| candidates[r].is_empty());
+
+        let timeout = Duration::from_secs(timeout_in_seconds.into());
+        let mut search = Search {
+            class_ids: index.into_keys().collect(),
+            candidates,
+            lower_bound,
+            mandatory,
+            cap_max: constraints.caps.iter().map(|cap| cap.max).collect(),
+            state: vec![State::Untouched; num_classes],
+            choice: vec![None; num_classes],
+            pending: vec![],
+            cost: 0.0,
+            pending_bound: 0.0,
+            cap_count: vec![0; constraints.caps.len()],
+            best: None,
+            best_cost: f64::INFINITY,
+            infeasible,
+            deadline: Instant::now().checked_add(timeout).unwrap_or_else(far_future),
+            timed_out: false,
+            expanded: 0,
+            visit_stamp: vec![0; num_classes],
+            stamp: 0,
+        };
+        if !infeasible {
+            let mut added = vec![];
+            for r in roots {
+                search.make_pending(r, &mut added);
+            }
+        }
+        search
+    }
+
+    // The depth-first search, one frame per class being branched on. The stack
+    // is explicit, as a saturated egraph can have thousands of classes to choose.
+    fn run(&mut self) {
+        let mut stack: Vec<Frame> = vec![];
+        let mut expand = true;
+        loop {
+            if expand {
+                expand = false;
+                self.expanded += 1;
+                if self.expanded % CLOCK_INTERVAL == 0 && Instant::now() >= self.deadline {
+                    self.timed_out = true;
+                }
+                if self.timed_out {
+                    // Only the incumbent is used from here on, so the selections
+                    // on the stack are left as they are.
+                    return;
+                }
+
+                if self.pending.is_empty() {
+                    if self.cost < self.best_cost {
+                        self.best_cost = self.cost;
+                        self.best = Some(self.choice.clone());
+                    }
+                } else {
+                    // Branch on the pending class with the fewest candidates.
+                    let position = (0..self.pending.len())
+                        .min_by_key(|&i| self.candidates[self.pending[i]].len())
+                        .unwrap();
+                    let class = self.pending.swap_remove(position);
+                    self.pending_bound -= self.lower_bound[class];
+                    stack.push(Frame {
+                        class,
+                        position,
+                        next: 0,
+                        added: None,
+                    });
+                }
+            }
+
+            let Some(frame) = stack.last_mut() else {
+                return;
+            };
+            if let Some(added) = frame.added.take() {
+                self.deselect(frame.class, frame.next - 1, added);
+            }
+            match self.next_candidate(frame.class, frame.next) {
+                Some(node) => {
+                    frame.next = node + 1;
+                    frame.added = Some(self.select(frame.class, node));
+                    expand = true;
+                }
+                None => {
+                    // Put the class back where it was.
+                    let (class, position) = (frame.class, frame.position);
+                    stack.pop();
+                    self.pending_bound += self.lower_bound[class];
+                    self.pending.push(class);
+                    let last = self.pending.len() - 1;
+                    self.pending.swap(position, last);
+                }
+            }
+        }
+    }
+
+    // The first candidate of the class, from `start` on, that can still beat the
+    // incumbent, keeps the count caps and doesn't close a cycle.
+    fn next_candidate(&mut self, class: usize, start: usize) -> Option<usize> {
+        (start..self.candidates[class].len()).find(|&node| {
+            let node_cost = self.candidates[class][node].cost;
+            let new_children: f64 = self.candidates[class][node]
+                .children
+                .iter()
+                .filter(|&&c| self.state[c] == State::Untouched)
+                .map(|&c| self.lower_bound[c])
+                .sum();
+            if self.cost + node_cost + self.pending_bound + new_children
+                >= self.best_cost - EPSILON_ALLOWANCE
+            {
+                return false;
+            }
+            if self.candidates[class][node]
+                .caps
+                .iter()
+                .any(|&c| self.cap_count[c] >= self.cap_max[c])
+            {
+                return false;
+            }
+            !self.closes_cycle(class, node)
+        })
+    }
+
+    // Selects the node for its class. Returns the classes made pending by it.
+    fn select(&mut self, class: usize, node: usize) -> Vec<usize> {
+        for &c in &self.candidates[class][node].caps {
+            self.cap_count[c] += 1;
+        }
+        self.state[class] = State::Chosen;
+        self.choice[class] = Some(node);
+        self.cost += self.candidates[class][node].cost;
+        let mut added = vec![];
+        for i in 0..self.candidates[class][node].children.len() {
+            let child = self.candidates[class][node].children[i];
+            self.make_pending(child, &mut added);
+        }
+        added
+    }
+
+    // Undoes `select`, given the classes it made pending.
+    fn deselect(&mut self, class: usize, node: usize, added: Vec<usize>) {
+        for c in added.into_iter().rev() {
+            let last = self.pending.pop();
+            debug_assert_eq!(last, Some(c));
+            self.state[c] = State::Untouched;
+            self.pending_bound -= self.lower_bound[c];
+        }
+        self.cost -= self.candidates[class][node].cost;
+        self.choice[class] = None;
+        self.state[class] = State::Pending;
+        for &c in &self.candidates[class][node].caps {
+            self.cap_count[c] -= 1;
+        }
+    }
+
+    // Marks the class, and the classes it can't do without, as pending.
+    fn make_pending(&mut self, class: usize, added: &mut Vec<usize>) {
+        let mut todo = vec![class];
+        while let Some(c) = todo.pop() {
+            if self.state[c] != State::Untouched {
+                continue;
+            }
+            self.state[c] = State::Pending;
+            self.pending.push(c);
+            self.pending_bound += self.lower_bound[c];
+            added.push(c);
+            todo.extend(self.mandatory[c].iter().copied());
+        }
+    }
+
+    // Would selecting the node let one of its children reach its class?
+    fn closes_cycle(&mut self, class: usize, node: usize) -> bool {
+        self.stamp += 1;
+        let mut todo: Vec<usize> = self.candidates[class][node]
+            .children
+            .iter()
+            .copied()
+            .filter(|&c| self.state[c] == State::Chosen)
+            .collect();
+        while let Some(c) = todo.pop() {
+            if c == class {
+                return true;
+            }
+            if self.visit_stamp[c] == self.stamp {
+                continue;
+            }
+            self.visit_stamp[c] = self.stamp;
+            if let Some(n) = self.choice[c] {
+                todo.extend(self.candidates[c][n].children.iter().copied());
+            }
+        }
+        false
+    }
+}
+
+fn far_future() -> Instant {
+    Instant::now() + Duration::from_secs(60 * 60 * 24 * 365)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraints::{OpConstraints, OpLabel};
+    use crate::extractor_structures::add_test_node;
+
+    fn extract(egraph: &EGraph, constraints: NodeConstraints) -> ExtractionResult {
+        BranchAndBoundDagExtractor {
+            timeout_in_seconds: 10,
+            constraints,
+        }
+        .extract(egraph, &egraph.root_eclasses)
+    }
+
+    fn cost(egraph: &EGraph, result: &ExtractionResult) -> f64 {
+        assert_eq!(result.check(egraph), Ok(()));
+        result.dag_cost(egraph, &egraph.root_eclasses).unwrap().into_inner()
+    }
+
+    // x is cheapest on its own as a leaf, but (g s) shares s with y.
+    fn shared_subterm() -> EGraph {
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "s.0", "s", &[], 5.0);
+        add_test_node(&mut egraph, "x.0", "x", &[], 5.0);
+        add_test_node(&mut egraph, "x.1", "g", &["s"], 1.0);
+        add_test_node(&mut egraph, "y.0", "h", &["s"], 1.0);
+        add_test_node(&mut egraph, "r.0", "+", &["x", "y"], 1.0);
+        egraph.root_eclasses.push(ClassId::from("r".to_string()));
+        egraph
+    }
+
+    // (f b) and (g a) are the cheapest nodes of a and b, but can't both be selected.
+    fn cyclic() -> EGraph {
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "a.0", "f", &["b"], 1.0);
+        add_test_node(&mut egraph, "a.1", "a", &[], 10.0);
+        add_test_node(&mut egraph, "b.0", "g", &["a"], 1.0);
+        add_test_node(&mut egraph, "b.1", "b", &[], 10.0);
+        add_test_node(&mut egraph, "r.0", "h", &["a", "b"], 1.0);
+        egraph.root_eclasses.push(ClassId::from("r".to_string()));
+        egraph
+    }
+
+    // Products are cheaper than their alternatives, but only one is allowed.
+    fn capped() -> (EGraph, NodeConstraints) {
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "x.0", "*", &[], 3.0);
+        add_test_node(&mut egraph, "x.1", "+", &[], 5.0);
+        add_test_node(&mut egraph, "y.0", "*", &[], 3.0);
+        add_test_node(&mut egraph, "y.1", "+", &[], 5.0);
+        add_test_node(&mut egraph, "r.0", "+", &["x", "y"], 1.0);
+        egraph.root_eclasses.push(ClassId::from("r".to_string()));
+
+        let mut constraints = OpConstraints::default();
+        constraints.limit("fp:*".parse().unwrap(), 1);
+        let labels: IndexMap<NodeId, OpLabel> = egraph
+            .nodes
+            .iter()
+            .map(|(id, node)| (id.clone(), format!("fp:{}", node.op).parse().unwrap()))
+            .collect();
+        let constraints = NodeConstraints::new(&constraints, &labels);
+        (egraph, constraints)
+    }
+
+    #[test]
+    fn test_beats_greedy_on_shared_subterm() {
+        let egraph = shared_subterm();
+        let greedy = FasterGreedyDagExtractor::default().extract(&egraph, &egraph.root_eclasses);
+        assert_eq!(cost(&egraph, &greedy), 12.0);
+
+        let result = extract(&egraph, NodeConstraints::default());
+        assert_eq!(cost(&egraph, &result), 8.0);
+        assert_eq!(result.status, ExtractionStatus::Optimal);
+    }
+
+    #[test]
+    fn test_skips_cycle_closing_choice() {
+        let egraph = cyclic();
+        let result = extract(&egraph, NodeConstraints::default());
+        assert_eq!(cost(&egraph, &result), 12.0);
+        assert_eq!(result.status, ExtractionStatus::Optimal);
+    }
+
+    #[test]
+    fn test_keeps_op_cap() {
+        let (egraph, constraints) = capped();
+        assert_eq!(cost(&egraph, &extract(&egraph, NodeConstraints::default())), 7.0);
+
+        let result = extract(&egraph, constraints.clone());
+        assert_eq!(cost(&egraph, &result), 9.0);
+        assert!(constraints.satisfied_by(&result, &egraph, &egraph.root_eclasses));
+    }
+
+    #[test]
+    fn test_deep_search_keeps_the_stack() {
+        // Beating greedy means selecting (g s) for x, and then a node in each of the
+        // many w classes, one search level each: deeper than a recursive search's stack.
+        let width = 5_000;
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "s.0", "s", &[], 5.0);
+        add_test_node(&mut egraph, "x.0", "x", &[], 5.0);
+        add_test_node(&mut egraph, "x.1", "g", &["s"], 1.0);
+        add_test_node(&mut egraph, "y.0", "h", &["s"], 1.0);
+        let ws: Vec<String> = (0..width).map(|i| format!("w{}", i)).collect();
+        for w in &ws {
+            add_test_node(&mut egraph, &format!("{}.0", w), "w", &[], 1.0);
+            add_test_node(&mut egraph, &format!("{}.1", w), "v", &[], 10.0);
+        }
+        let mut children = vec!["x", "y"];
+        children.extend(ws.iter().map(|w| w.as_str()));
+        add_test_node(&mut egraph, "r.0", "+", &children, 1.0);
+        egraph.root_eclasses.push(ClassId::from("r".to_string()));
+
+        // No deadline, so that a slow build still finishes the search.
+        let result = BranchAndBoundDagExtractor {
+            timeout_in_seconds: u32::MAX,
+            constraints: NodeConstraints::default(),
+        }
+        .extract(&egraph, &egraph.root_eclasses);
+        assert_eq!(result.status, ExtractionStatus::Optimal);
+        assert_eq!(cost(&egraph, &result), 8.0 + width as f64);
+    }
+
+    #[cfg(feature = "cbc")]
+    #[test]
+    fn test_matches_ilp() {
+        use crate::faster_ilp_cbc::{Config, FasterCbcExtractorWithTimeout};
+
+        let (capped, constraints) = capped();
+        let cases = [
+            (shared_subterm(), NodeConstraints::default()),
+            (cyclic(), NodeConstraints::default()),
+            (capped, constraints),
+        ];
+        for (egraph, constraints) in cases {
+            let ilp = FasterCbcExtractorWithTimeout {
+                timeout_in_seconds: 10,
+                config: Config::default(),
+                constraints: constraints.clone(),
+                levels: vec![],
+                export: None,
+            }
+            .extract(&egraph, &egraph.root_eclasses);
+            let bnb = extract(&egraph, constraints);
+            assert_eq!(ilp.status, ExtractionStatus::Optimal);
+            assert_eq!(bnb.status, ExtractionStatus::Optimal);
+            assert_eq!(cost(&egraph, &bnb), cost(&egraph, &ilp));
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+/// A wall-clock budget shared by the whole run: the pair simplification,
+/// saturation and both DAG extractions of every test case draw from it.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeBudget {
+    start: Instant,
+    limit: Option<Duration>,
+}
+
+impl TimeBudget {
+    /// Start a budget of `limit`, or an unlimited one for `None`.
+    pub fn new(limit: Option<Duration>) -> Self {
+        TimeBudget {
+            start: Instant::now(),
+            limit,
+        }
+    }
+
+    /// Time left, or `None` if the budget is unlimited.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.limit
+            .map(|limit| limit.saturating_sub(self.start.elapsed()))
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Shrink a stage's own limit so it can't outlive the budget.
+    pub fn clamp(&self, limit: Duration) -> Duration {
+        match self.remaining() {
+            Some(left) => limit.min(left),
+            None => limit,
+        }
+    }
+
+    /// Whether less than a whole second is left, which `clamp_secs` rounds
+    /// down to a timeout of zero.
+    pub fn is_under_a_second(&self) -> bool {
+        self.clamp_secs(1) == 0
+    }
+
+    /// Same as `clamp`, for limits given in whole seconds (the CBC timeout).
+    pub fn clamp_secs(&self, seconds: u32) -> u32 {
+        match self.remaining() {
+            Some(left) => seconds.min(left.as_secs().try_into().unwrap_or(u32::MAX)),
+            None => seconds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_under_a_second_left() {
+        let budget = TimeBudget::new(Some(Duration::from_millis(500)));
+        assert!(!budget.is_exhausted());
+        assert_eq!(budget.clamp_secs(180), 0);
+        assert!(budget.is_under_a_second());
+
+        let budget = TimeBudget::new(Some(Duration::from_secs(60)));
+        assert!((58..=60).contains(&budget.clamp_secs(180)));
+        assert!(!budget.is_under_a_second());
+
+        let budget = TimeBudget::new(Some(Duration::ZERO));
+        assert!(budget.is_exhausted() && budget.is_under_a_second());
+
+        let budget = TimeBudget::new(None);
+        assert_eq!(budget.clamp_secs(180), 180);
+        assert!(!budget.is_under_a_second());
+    }
+}
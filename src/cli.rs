@@ -0,0 +1,165 @@
+use std::str::FromStr;
+
+use crate::config::ExtractorKind;
+use crate::constraints::{parse_op_limit, OpLabel};
+use crate::language::Constant;
+
+/// Command line options of the optimizer.
+///
+/// The three input files are positional (as `wrapper.py` passes them), every
+/// other setting is a `--flag value` pair. Flags override the run configuration.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub symbol_types_file: String,
+    pub cost_model_file: String,
+    pub test_case_file: String,
+    /// Run configuration, instead of the one beside the cost model.
+    pub run_config_file: Option<String>,
+    pub extractor: Option<ExtractorKind>,
+    /// Timeout of each DAG extraction, in seconds.
+    pub timeout: Option<u32>,
+    /// Random seed of the local search extractor.
+    pub seed: Option<u64>,
+    /// Characteristic of the field, constants are folded modulo it.
+    pub modulus: Option<Constant>,
+    /// Wall-clock budget for the whole run, in seconds.
+    pub budget: Option<u64>,
+    /// Rules file whose rules are added to the built-in ones.
+    pub rules_file: Option<String>,
+    /// Names or tags of the rules to saturate with, instead of the run configuration's.
+    pub rules: Vec<String>,
+    /// Saturation profile of the run configuration.
+    pub profile: Option<String>,
+    /// Also print the k cheapest distinct DAG programs.
+    pub top_k: Option<usize>,
+    /// Also print the (DAG cost, critical-path latency) Pareto frontier.
+    pub pareto: bool,
+    /// Operations that must not be extracted, added to the run configuration's.
+    pub forbid: Vec<OpLabel>,
+    /// Caps on operation counts, added to the run configuration's.
+    pub max_ops: Vec<(OpLabel, usize)>,
+    /// Write the ILP model of each test case to this `.lp` or `.mps` file,
+    /// numbered by test case.
+    pub export_model: Option<String>,
+    /// Read the optimized DAG of each test case from this solution file of
+    /// the exported model, numbered the same way, instead of extracting it.
+    pub read_solution: Option<String>,
+    /// Write the saturated egraph of each test case as egraph-serialize JSON,
+    /// numbered by test case.
+    pub dump_egraph: Option<String>,
+    /// Only run the DAG extractor on this egraph-serialize JSON file.
+    pub load_egraph: Option<String>,
+    /// Render the saturated egraph of each test case, with the extracted DAG
+    /// highlighted, to this Graphviz file, numbered by test case.
+    pub dot: Option<String>,
+    /// Only render the classes of the extracted DAG.
+    pub dot_reachable: bool,
+    /// Check the selected rules on random instances, instead of optimizing.
+    pub check_rules: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            symbol_types_file: "inputs/symbol_types.json".to_string(),
+            cost_model_file: "inputs/cost_model.json".to_string(),
+            test_case_file: "inputs/tests.txt".to_string(),
+            run_config_file: None,
+            extractor: None,
+            timeout: None,
+            seed: None,
+            modulus: None,
+            budget: None,
+            rules_file: None,
+            rules: vec![],
+            profile: None,
+            top_k: None,
+            pareto: false,
+            forbid: vec![],
+            max_ops: vec![],
+            export_model: None,
+            read_solution: None,
+            dump_egraph: None,
+            load_egraph: None,
+            dot: None,
+            dot_reachable: false,
+            check_rules: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn usage(program: &str) -> String {
+        format!(
+            "Usage: {} [<symbol_types.json> <cost_model.json> <tests.txt>] \
+             [--run-config <run_config.json>] [--extractor greedy|ilp|bnb|local] \
+             [--timeout <seconds>] [--seed <n>] [--modulus <p>] [--budget <seconds>] [--top-k <k>] [--pareto] \
+             [--rules-file <rules.json>] [--rules <name|tag>]... [--profile <name>] \
+             [--forbid <field>:<op>]... [--max-ops <field>:<op>=<count>]... \
+             [--export-model <model.lp|model.mps>] [--read-solution <model.sol>] \
+             [--dump-egraph <egraph.json>] [--load-egraph <egraph.json>] \
+             [--dot <egraph.dot>] [--dot-reachable] [--check-rules]",
+            program
+        )
+    }
+
+    /// Parse `std::env::args()`, including the program name.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut positional = Vec::new();
+
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--run-config" => options.run_config_file = Some(parse_value(arg, iter.next())?),
+                "--extractor" => options.extractor = Some(parse_value(arg, iter.next())?),
+                "--timeout" => options.timeout = Some(parse_value(arg, iter.next())?),
+                "--seed" => options.seed = Some(parse_value(arg, iter.next())?),
+                "--modulus" => options.modulus = Some(parse_value(arg, iter.next())?),
+                "--budget" => options.budget = Some(parse_value(arg, iter.next())?),
+                "--rules-file" => options.rules_file = Some(parse_value(arg, iter.next())?),
+                "--rules" => options.rules.push(parse_value(arg, iter.next())?),
+                "--profile" => options.profile = Some(parse_value(arg, iter.next())?),
+                "--top-k" => options.top_k = Some(parse_value(arg, iter.next())?),
+                "--pareto" => options.pareto = true,
+                "--export-model" => options.export_model = Some(parse_value(arg, iter.next())?),
+                "--dump-egraph" => options.dump_egraph = Some(parse_value(arg, iter.next())?),
+                "--load-egraph" => options.load_egraph = Some(parse_value(arg, iter.next())?),
+                "--dot" => options.dot = Some(parse_value(arg, iter.next())?),
+                "--dot-reachable" => options.dot_reachable = true,
+                "--check-rules" => options.check_rules = true,
+                "--read-solution" => {
+                    options.read_solution = Some(parse_value(arg, iter.next())?)
+                }
+                "--forbid" => options.forbid.push(parse_value(arg, iter.next())?),
+                "--max-ops" => {
+                    let value = iter
+                        .next()
+                        .ok_or_else(|| format!("Missing value for {}", arg))?;
+                    options.max_ops.push(parse_op_limit(value)?);
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ => positional.push(arg.clone()),
+            }
+        }
+
+        match positional.len() {
+            0 => {}
+            3 => {
+                options.symbol_types_file = positional[0].clone();
+                options.cost_model_file = positional[1].clone();
+                options.test_case_file = positional[2].clone();
+            }
+            _ => return Err("Expected either no input files or all three".to_string()),
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
@@ -0,0 +1,325 @@
+use crate::analysis::TypeAnalysis;
+use crate::budget::TimeBudget;
+use crate::constraints::OpConstraints;
+#[cfg(feature = "cbc")]
+use crate::faster_ilp_cbc;
+use crate::language::{Constant, Math};
+use crate::local_search_dag;
+use crate::soundness;
+use crate::tower::Tower;
+use egg::{BackoffScheduler, Runner, SimpleScheduler};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Name of the run configuration looked up beside the cost model.
+pub const RUN_CONFIG_FILE_NAME: &str = "run_config.json";
+
+/// Default ILP extraction timeout, in seconds.
+pub const DEFAULT_TIMEOUT: u32 = 180;
+
+/// Everything about a run that isn't the cost model or the inputs, so that
+/// experiments can be reproduced without recompiling. Every field is optional.
+///
+/// JSON format example:
+///
+/// {
+///   "extractor": "ilp",
+///   "timeout": 180,
+///   "budget": 600,
+///   "modulus": "0x30644e72e131a029b85045b68181585d97816a916871ca8d3c208c16d87cfd47",
+///   "runner": {
+///     "iter_limit": 30, "node_limit": 10000, "time_limit": 5.0,
+///     "scheduler": { "type": "backoff", "match_limit": 1000, "ban_length": 5 }
+///   },
+///   "extraction": { "pull_up_costs": true, "cycle_limit": 1000, ... },
+///   "local_search": { "seed": 0, "max_stale_rounds": 200 },
+///   "soundness": { "samples": 20, "seed": 0 },
+///   "rules_file": "inputs/rules.json", "rules": ["builtin", "squares"],
+///   "stages": [{ "name": "expand", "rules": ["dist", "karatsuba"], "runner": { "iter_limit": 10 } }, ...],
+///   "profiles": { "fp12_mul": [...] }, "profile": "fp12_mul",
+///   "tower": [{ "over": "fp", "degree": 2, "non_residue": "-1" }, { "over": "fp2", "degree": 3, "non_residue": "xi" }],
+///   "constraints": { "forbidden": { "fp2": ["inv"] }, "max_count": { "fp2": { "*": 3 } } }
+/// }
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RunConfig {
+    /// Which DAG extractor to run.
+    pub extractor: ExtractorKind,
+    /// Timeout of each DAG extraction, in seconds.
+    pub timeout: u32,
+    /// Wall-clock budget for the whole run, in seconds.
+    pub budget: Option<u64>,
+    /// Characteristic of the field, constants are folded modulo it.
+    pub modulus: Option<Constant>,
+    pub runner: RunnerConfig,
+    /// Pruning and solver switches of the ILP extractor.
+    #[cfg(feature = "cbc")]
+    pub extraction: faster_ilp_cbc::Config,
+    /// Seed and stopping rule of the local search extractor.
+    pub local_search: local_search_dag::Config,
+    /// Samples and seed of `--check-rules`.
+    pub soundness: soundness::Config,
+    /// Operations the DAG extractors must avoid or only use a few times.
+    pub constraints: OpConstraints,
+    /// The non-residue of each extension level `pair` and `triple` are over.
+    pub tower: Tower,
+    /// Rules file whose rules are added to the built-in ones.
+    pub rules_file: Option<String>,
+    /// Names or tags of the rules to saturate with, all of them if empty.
+    pub rules: Vec<String>,
+    /// Saturation stages run one after the other on the same egraph. Without
+    /// any, a single stage runs all the rules with `runner`.
+    pub stages: Vec<StageConfig>,
+    /// Named lists of stages, used instead of `stages` when selected.
+    pub profiles: HashMap<String, Vec<StageConfig>>,
+    pub profile: Option<String>,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig {
+            extractor: ExtractorKind::default(),
+            timeout: DEFAULT_TIMEOUT,
+            budget: None,
+            modulus: None,
+            runner: RunnerConfig::default(),
+            #[cfg(feature = "cbc")]
+            extraction: faster_ilp_cbc::Config::default(),
+            local_search: local_search_dag::Config::default(),
+            soundness: soundness::Config::default(),
+            constraints: OpConstraints::default(),
+            tower: Tower::default(),
+            rules_file: None,
+            rules: vec![],
+            stages: vec![],
+            profiles: HashMap::new(),
+            profile: None,
+        }
+    }
+}
+
+impl RunConfig {
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let data = fs::read_to_string(path)?;
+        let config: RunConfig = serde_json::from_str(&data)?;
+        Ok(config)
+    }
+
+    /// The saturation stages of the selected profile, or the configured ones.
+    pub fn schedule(&self) -> Result<Vec<StageConfig>, String> {
+        let stages = match &self.profile {
+            Some(name) => self.profiles.get(name).cloned().ok_or_else(|| {
+                let mut names: Vec<&String> = self.profiles.keys().collect();
+                names.sort();
+                format!("Unknown profile {}, the run configuration has {:?}", name, names)
+            })?,
+            None => self.stages.clone(),
+        };
+        if stages.is_empty() {
+            return Ok(vec![StageConfig {
+                name: "saturate".to_string(),
+                rules: vec![],
+                runner: self.runner.clone(),
+            }]);
+        }
+        Ok(stages)
+    }
+
+    /// Load `path` if given, otherwise `run_config.json` in the cost model's
+    /// directory if there is one, otherwise the defaults.
+    pub fn load(
+        path: Option<&str>,
+        cost_model_file: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(path) = path {
+            return RunConfig::from_file(path);
+        }
+        let beside = Path::new(cost_model_file).with_file_name(RUN_CONFIG_FILE_NAME);
+        if beside.is_file() {
+            RunConfig::from_file(&beside.to_string_lossy())
+        } else {
+            Ok(RunConfig::default())
+        }
+    }
+}
+
+/// The DAG extractors that can be selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtractorKind {
+    /// `FasterGreedyDagExtractor`, fast but not optimal.
+    Greedy,
+    /// `FasterCbcExtractorWithTimeout`, needs the `cbc` feature.
+    Ilp,
+    /// `BranchAndBoundDagExtractor`, exact without an external solver.
+    Bnb,
+    /// `LocalSearchDagExtractor`, improves the greedy result until the timeout.
+    Local,
+}
+
+impl ExtractorKind {
+    pub fn is_available(&self) -> bool {
+        *self != ExtractorKind::Ilp || cfg!(feature = "cbc")
+    }
+}
+
+impl Default for ExtractorKind {
+    fn default() -> Self {
+        if cfg!(feature = "cbc") {
+            ExtractorKind::Ilp
+        } else {
+            ExtractorKind::Bnb
+        }
+    }
+}
+
+impl FromStr for ExtractorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "greedy" => Ok(ExtractorKind::Greedy),
+            "ilp" => Ok(ExtractorKind::Ilp),
+            "bnb" => Ok(ExtractorKind::Bnb),
+            "local" => Ok(ExtractorKind::Local),
+            _ => Err(format!("Unknown extractor: {}", s)),
+        }
+    }
+}
+
+/// One saturation stage: a subset of the rules, by name or tag as `--rules`
+/// takes them (with `!` to leave some out), and its own runner.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StageConfig {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub rules: Vec<String>,
+    #[serde(default)]
+    pub runner: RunnerConfig,
+}
+
+/// Limits and scheduler of the egg `Runner`s that saturate the egraph.
+/// The defaults are egg's own.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RunnerConfig {
+    pub iter_limit: usize,
+    pub node_limit: usize,
+    /// In seconds.
+    pub time_limit: f64,
+    pub scheduler: SchedulerConfig,
+}
+
+impl Default for RunnerConfig {
+    fn default() -> Self {
+        RunnerConfig {
+            iter_limit: 30,
+            node_limit: 10_000,
+            time_limit: 5.0,
+            scheduler: SchedulerConfig::default(),
+        }
+    }
+}
+
+impl RunnerConfig {
+    /// Apply the limits and scheduler to `runner`, keeping its time limit
+    /// inside the budget.
+    pub fn apply(
+        &self,
+        runner: Runner<Math, TypeAnalysis>,
+        budget: &TimeBudget,
+    ) -> Runner<Math, TypeAnalysis> {
+        let runner = runner
+            .with_iter_limit(self.iter_limit)
+            .with_node_limit(self.node_limit)
+            .with_time_limit(budget.clamp(Duration::from_secs_f64(self.time_limit)));
+        match self.scheduler {
+            SchedulerConfig::Simple => runner.with_scheduler(SimpleScheduler),
+            SchedulerConfig::Backoff {
+                match_limit,
+                ban_length,
+            } => runner.with_scheduler(
+                BackoffScheduler::default()
+                    .with_initial_match_limit(match_limit)
+                    .with_ban_length(ban_length),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SchedulerConfig {
+    Simple,
+    Backoff {
+        #[serde(default = "default_match_limit")]
+        match_limit: usize,
+        #[serde(default = "default_ban_length")]
+        ban_length: usize,
+    },
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig::Backoff {
+            match_limit: default_match_limit(),
+            ban_length: default_ban_length(),
+        }
+    }
+}
+
+fn default_match_limit() -> usize {
+    1_000
+}
+
+fn default_ban_length() -> usize {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(stages: &[StageConfig]) -> Vec<&str> {
+        stages.iter().map(|stage| stage.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut run_config: RunConfig = serde_json::from_str(
+            r#"{
+                "runner": { "iter_limit": 7 },
+                "stages": [{ "name": "expand", "rules": ["dist"] }, { "name": "shrink" }],
+                "profiles": { "fp12_mul": [{ "name": "karatsuba", "runner": { "iter_limit": 3 } }] }
+            }"#,
+        )
+        .unwrap();
+        let stages = run_config.schedule().unwrap();
+        assert_eq!(names(&stages), ["expand", "shrink"]);
+        assert_eq!(stages[0].rules, ["dist"]);
+        // Stages without a runner get egg's limits, not the top-level runner.
+        assert_eq!(stages[1].runner.iter_limit, 30);
+
+        run_config.profile = Some("fp12_mul".to_string());
+        let stages = run_config.schedule().unwrap();
+        assert_eq!(names(&stages), ["karatsuba"]);
+        assert_eq!(stages[0].runner.iter_limit, 3);
+
+        run_config.profile = Some("fp6_mul".to_string());
+        let error = run_config.schedule().unwrap_err();
+        assert!(error.starts_with("Unknown profile fp6_mul"), "{}", error);
+
+        // Without stages, one stage runs every rule with the top-level runner.
+        run_config.profile = None;
+        run_config.stages.clear();
+        let stages = run_config.schedule().unwrap();
+        assert_eq!(names(&stages), ["saturate"]);
+        assert!(stages[0].rules.is_empty());
+        assert_eq!(stages[0].runner.iter_limit, 7);
+    }
+}
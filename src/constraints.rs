@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use egraph_serialize::{ClassId, EGraph, NodeId};
+use indexmap::IndexMap;
+use rustc_hash::FxHashSet;
+use serde::Deserialize;
+
+use crate::extractor_structures::ExtractionResult;
+
+/// An operation of the cost model: the field type it works in and its
+/// operation string, as used in the `costs` table. Written `fp2:*` on the
+/// command line.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OpLabel {
+    pub field: String,
+    pub op: String,
+}
+
+impl fmt::Display for OpLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.field, self.op)
+    }
+}
+
+impl FromStr for OpLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((field, op)) if !field.is_empty() && !op.is_empty() => Ok(OpLabel {
+                field: field.to_string(),
+                op: op.to_string(),
+            }),
+            _ => Err(format!("Expected <field>:<op>, got: {}", s)),
+        }
+    }
+}
+
+/// Limits on the operations a DAG extraction may use, laid out like the cost
+/// model's `costs` table.
+///
+/// JSON format example:
+///
+/// {
+///   "forbidden": { "fp": ["inv"], "fp2": ["inv"] },
+///   "max_count": { "fp2": { "*": 3 } }
+/// }
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OpConstraints {
+    /// Operations that must not be extracted, by field type.
+    pub forbidden: HashMap<String, Vec<String>>,
+    /// How many times an operation may appear in the DAG, by field type.
+    pub max_count: HashMap<String, HashMap<String, usize>>,
+}
+
+impl OpConstraints {
+    pub fn is_empty(&self) -> bool {
+        self.forbidden.values().all(Vec::is_empty) && self.max_count.values().all(HashMap::is_empty)
+    }
+
+    pub fn forbid(&mut self, label: OpLabel) {
+        self.forbidden.entry(label.field).or_default().push(label.op);
+    }
+
+    pub fn limit(&mut self, label: OpLabel, max: usize) {
+        self.max_count.entry(label.field).or_default().insert(label.op, max);
+    }
+
+    fn is_forbidden(&self, label: &OpLabel) -> bool {
+        self.forbidden
+            .get(&label.field)
+            .is_some_and(|ops| ops.contains(&label.op))
+    }
+}
+
+/// Parse a `--max-ops` value, `<field>:<op>=<count>`.
+pub fn parse_op_limit(s: &str) -> Result<(OpLabel, usize), String> {
+    let (label, max) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected <field>:<op>=<count>, got: {}", s))?;
+    let max = max
+        .parse()
+        .map_err(|_| format!("Invalid count in: {}", s))?;
+    Ok((label.parse()?, max))
+}
+
+/// `OpConstraints` resolved against the nodes of one serialized egraph, which
+/// is what the extractors work with.
+#[derive(Debug, Clone, Default)]
+pub struct NodeConstraints {
+    pub forbidden: FxHashSet<NodeId>,
+    pub caps: Vec<NodeCap>,
+}
+
+/// At most `max` of `nodes` may be selected.
+#[derive(Debug, Clone)]
+pub struct NodeCap {
+    pub label: OpLabel,
+    pub nodes: FxHashSet<NodeId>,
+    pub max: usize,
+}
+
+impl NodeConstraints {
+    /// `labels` gives the operation of every node, see `serialized_node_labels`.
+    pub fn new(constraints: &OpConstraints, labels: &IndexMap<NodeId, OpLabel>) -> Self {
+        let forbidden = labels
+            .iter()
+            .filter(|(_, label)| constraints.is_forbidden(label))
+            .map(|(node, _)| node.clone())
+            .collect();
+
+        let mut caps: Vec<NodeCap> = constraints
+            .max_count
+            .iter()
+            .flat_map(|(field, ops)| {
+                ops.iter().map(move |(op, &max)| NodeCap {
+                    label: OpLabel {
+                        field: field.clone(),
+                        op: op.clone(),
+                    },
+                    nodes: labels
+                        .iter()
+                        .filter(|(_, label)| label.field == *field && label.op == *op)
+                        .map(|(node, _)| node.clone())
+                        .collect(),
+                    max,
+                })
+            })
+            .collect();
+        // Keep the order stable, so repeated runs build the same models.
+        caps.sort_by(|a, b| a.label.to_string().cmp(&b.label.to_string()));
+
+        NodeConstraints { forbidden, caps }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forbidden.is_empty() && self.caps.is_empty()
+    }
+
+    pub fn allows(&self, node: &NodeId) -> bool {
+        !self.forbidden.contains(node)
+    }
+
+    /// Whether the extraction of `roots` is valid and meets every constraint.
+    pub fn satisfied_by(
+        &self,
+        result: &ExtractionResult,
+        egraph: &EGraph,
+        roots: &[ClassId],
+    ) -> bool {
+        let Ok(nodes) = result.dag_nodes(egraph, roots) else {
+            return false;
+        };
+        nodes.iter().all(|node| self.allows(node))
+            && self
+                .caps
+                .iter()
+                .all(|cap| nodes.iter().filter(|n| cap.nodes.contains(n)).count() <= cap.max)
+    }
+}
+
+/// The nodes of each level of the cost model's `lexicographic` list. An entry
+/// is either `<op>`, matching the operation in every field, or `<field>:<op>`.
+pub fn resolve_levels(
+    levels: &[Vec<String>],
+    labels: &IndexMap<NodeId, OpLabel>,
+) -> Vec<FxHashSet<NodeId>> {
+    let matches = |entry: &str, label: &OpLabel| match entry.split_once(':') {
+        Some((field, op)) => label.field == field && label.op == op,
+        None => label.op == entry,
+    };
+    levels
+        .iter()
+        .map(|level| {
+            labels
+                .iter()
+                .filter(|(_, label)| level.iter().any(|entry| matches(entry, label)))
+                .map(|(node, _)| node.clone())
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_constraints() {
+        let (label, max) = parse_op_limit("fp2:*=3").unwrap();
+        assert_eq!(label.to_string(), "fp2:*");
+        assert_eq!(max, 3);
+        assert!(parse_op_limit("fp2:*").is_err());
+        assert!("inv".parse::<OpLabel>().is_err());
+
+        let mut constraints = OpConstraints::default();
+        assert!(constraints.is_empty());
+        constraints.forbid("fp2:inv".parse().unwrap());
+        constraints.limit(label, max);
+
+        let labels: IndexMap<NodeId, OpLabel> = [
+            ("0.0", "fp2:inv"),
+            ("1.0", "fp2:*"),
+            ("1.1", "fp:*"),
+            ("2.0", "fp2:*"),
+        ]
+        .into_iter()
+        .map(|(node, label)| (NodeId::from(node.to_string()), label.parse().unwrap()))
+        .collect();
+        let resolved = NodeConstraints::new(&constraints, &labels);
+
+        assert!(!resolved.allows(&NodeId::from("0.0".to_string())));
+        assert!(resolved.allows(&NodeId::from("1.1".to_string())));
+        assert_eq!(resolved.caps.len(), 1);
+        assert_eq!(resolved.caps[0].nodes.len(), 2);
+        assert_eq!(resolved.caps[0].max, 3);
+
+        let levels = resolve_levels(
+            &[vec!["*".to_string()], vec!["fp2:inv".to_string()]],
+            &labels,
+        );
+        assert_eq!(levels[0].len(), 3);
+        assert_eq!(levels[1].len(), 1);
+    }
+}
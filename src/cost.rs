@@ -1,7 +1,9 @@
 use crate::analysis::{FieldType, TypeAnalysis};
+use crate::constraints::OpLabel;
 use crate::language::Math;
 use egg::{CostFunction, EGraph, Id, Language};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
 use serde::{Deserialize, Serialize};
 
@@ -15,14 +17,36 @@ use serde::{Deserialize, Serialize};
 ///   },
 ///   "default_costs": {
 ///     "+": 1, "-": 1, "*": 3, "*const": 2, "inv": 10, "sq": 2, "const": 0, "symbol": 0
-///   }
+///   },
+///   "latencies": {
+///     "fp":  { "+": 1, "-": 1, "*": 4, "*const": 3, "inv": 40, "sq": 3 }
+///   },
+///   "default_latencies": { "+": 1, "*": 4 },
+///   "lexicographic": [["*", "sq"], ["*const"], ["+", "-"]]
 /// }
+///
+/// The latency tables are optional, missing latencies are 0.
+///
+/// "*nr" is a product by the non-residue of a tower level, in that level's
+/// field. Models without it use the "*const" cost.
+///
+/// `lexicographic` is optional too. Each entry is a level of operations,
+/// either "op" in any field or "field:op", and the ILP extractor minimises
+/// how many operations of each level are used, in order, before the cost.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CostModel {
     /// Mapping e.g. "fp" → { "+": 1, "*": 3, ... }
     pub costs: HashMap<String, HashMap<String, f64>>,
     /// Fallbacks if a field‐type or operation is missing
     pub default_costs: HashMap<String, f64>,
+    /// Same layout as `costs`, for the latency of each operation.
+    #[serde(default)]
+    pub latencies: HashMap<String, HashMap<String, f64>>,
+    #[serde(default)]
+    pub default_latencies: HashMap<String, f64>,
+    /// Ordered levels of operations to minimise the count of.
+    #[serde(default)]
+    pub lexicographic: Vec<Vec<String>>,
 }
 
 impl CostModel {
@@ -32,16 +56,34 @@ impl CostModel {
         Ok(model)
     }
 
-    /// Look up “costs[field_type_str][operation]”, or fallback to default_costs[operation], or 1.
+    /// Look up “costs[field_type_str][operation]”, or fallback to default_costs[operation], or 0.
     pub fn get_cost(&self, field_type: &FieldType, operation: &str) -> f64 {
-        let key = field_type.to_string();
-        if let Some(field_costs) = self.costs.get(&key) {
-            if let Some(&c) = field_costs.get(operation) {
-                return c;
-            }
-        }
-        self.default_costs.get(operation).copied().unwrap_or(0.0)
+        lookup(&self.costs, &self.default_costs, field_type, operation)
     }
+
+    /// Look up “latencies[field_type_str][operation]”, same fallbacks as `get_cost`.
+    pub fn get_latency(&self, field_type: &FieldType, operation: &str) -> f64 {
+        lookup(&self.latencies, &self.default_latencies, field_type, operation)
+    }
+}
+
+fn lookup(
+    table: &HashMap<String, HashMap<String, f64>>,
+    defaults: &HashMap<String, f64>,
+    field_type: &FieldType,
+    operation: &str,
+) -> f64 {
+    let find = |operation: &str| {
+        table
+            .get(&field_type.to_string())
+            .and_then(|field_costs| field_costs.get(operation))
+            .or_else(|| defaults.get(operation))
+            .copied()
+    };
+    // Cost models without "*nr" price products by a non-residue as by a constant.
+    find(operation)
+        .or_else(|| (operation == "*nr").then(|| find("*const")).flatten())
+        .unwrap_or(0.0)
 }
 
 /// A single struct that implements both `CostFunction<Math>` (for tree‐extraction)
@@ -60,23 +102,28 @@ impl MathCostFn {
         egraph: &EGraph<Math, TypeAnalysis>,
         path: &str,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let cost_model = CostModel::from_file(path)?;
-        let symbol_types = egraph.analysis.symbol_types.clone();
-        let egraph_clone = egraph.clone();
-        Ok(MathCostFn {
+        Ok(Self::new(egraph, CostModel::from_file(path)?))
+    }
+
+    /// Build from an existing EGraph and a loaded cost model; clones the EGraph.
+    pub fn new(egraph: &EGraph<Math, TypeAnalysis>, cost_model: CostModel) -> Self {
+        MathCostFn {
             cost_model,
-            symbol_types,
-            egraph: egraph_clone,
-        })
+            symbol_types: egraph.analysis.symbol_types.clone(),
+            egraph: egraph.clone(),
+        }
     }
 
     /// Recompute the “field‐type” of a given enode (Add/Sub/Mul/Inv/Sq/Const/Symbol),
     /// exactly as TypeAnalysis did during `make(...)`.
     fn determine_enode_type(&self, enode: &Math) -> FieldType {
+        if let Some(over) = TypeAnalysis::non_residue_type(&self.egraph, enode) {
+            return over;
+        }
         match enode {
             Math::Pair([a, b]) => {
-                let type_a = &self.egraph[*a].data;
-                let type_b = &self.egraph[*b].data;
+                let type_a = &self.egraph[*a].data.ty;
+                let type_b = &self.egraph[*b].data.ty;
                 // Result type is determined by pairing logic
                 match (type_a, type_b) {
                     (FieldType::Fp, FieldType::Fp) => FieldType::FpExt(2),
@@ -86,9 +133,30 @@ impl MathCostFn {
                     _ => type_a.lcm_extension(type_b),
                 }
             }
-            
+
+            Math::Triple([a, b, c]) => {
+                let type_a = &self.egraph[*a].data.ty;
+                let type_b = &self.egraph[*b].data.ty;
+                let type_c = &self.egraph[*c].data.ty;
+                match (type_a, type_b, type_c) {
+                    (FieldType::Fp, FieldType::Fp, FieldType::Fp) => FieldType::FpExt(3),
+                    (FieldType::FpExt(n), _, _) if type_a == type_b && type_b == type_c => {
+                        FieldType::FpExt(n * 3)
+                    }
+                    _ => type_a.lcm_extension(type_b).lcm_extension(type_c),
+                }
+            }
+
+            Math::C0(id) | Math::C1(id) | Math::C2(id) => {
+                match &self.egraph[*id].data.ty {
+                    FieldType::FpExt(3) => FieldType::Fp,
+                    FieldType::FpExt(n) if n % 3 == 0 => FieldType::FpExt(n / 3),
+                    _ => FieldType::Fp,
+                }
+            }
+
             Math::Fst(id) | Math::Snd(id) => {
-                let input_type = &self.egraph[*id].data;
+                let input_type = &self.egraph[*id].data.ty;
                 match input_type {
                     FieldType::FpExt(n) if *n > 1 => {
                         let new_degree = n / 2;
@@ -104,11 +172,11 @@ impl MathCostFn {
 
             Math::Add([a, b]) | Math::Sub([a, b]) | Math::Mul([a, b]) => {
                 // Take LCM of both children’s types
-                let type_a = &self.egraph[*a].data;
-                let type_b = &self.egraph[*b].data;
+                let type_a = &self.egraph[*a].data.ty;
+                let type_b = &self.egraph[*b].data.ty;
                 type_a.lcm_extension(type_b)
             }
-            Math::Inv(x) | Math::Sq(x) => self.egraph[*x].data.clone(),
+            Math::Inv(x) | Math::Sq(x) => self.egraph[*x].data.ty.clone(),
             Math::Constant(_) => FieldType::Constant,
             Math::Symbol(sym) => {
                 let name = sym.as_str().to_string();
@@ -117,23 +185,26 @@ impl MathCostFn {
         }
     }
 
-    /// Decide the operation‐string (e.g. "+", "-", "*", "*const", "inv", "sq", "const", "symbol").
+    /// Decide the operation‐string (e.g. "+", "-", "*", "*const", "*nr", "inv", "sq", "const", "symbol").
+    /// "*nr" is a product by a tower non-residue, in the field of its level.
     fn get_operation_string(&self, enode: &Math) -> String {
         match enode {
             Math::Add(_) => "+".to_string(),
             Math::Sub(_) => "-".to_string(),
+            Math::Mul([a, b])
+                if TypeAnalysis::is_non_residue(&self.egraph, *a)
+                    || TypeAnalysis::is_non_residue(&self.egraph, *b) =>
+            {
+                "*nr".to_string()
+            }
             Math::Mul([a, b]) => {
-                let type_a = &self.egraph[*a].data;
-                let type_b = &self.egraph[*b].data;
-                // If either child’s eclass has a Math::Constant(_), call it "*const"
-                let child_a_const = self.egraph[*a]
-                    .nodes
-                    .iter()
-                    .any(|n| matches!(n, Math::Constant(_))) || (*type_a == FieldType::Constant);
-                let child_b_const = self.egraph[*b]
-                    .nodes
-                    .iter()
-                    .any(|n| matches!(n, Math::Constant(_))) || (*type_b == FieldType::Constant);
+                let type_a = &self.egraph[*a].data.ty;
+                let type_b = &self.egraph[*b].data.ty;
+                // If either child’s eclass is a (folded) constant, call it "*const"
+                let child_a_const =
+                    self.egraph[*a].data.constant.is_some() || (*type_a == FieldType::Constant);
+                let child_b_const =
+                    self.egraph[*b].data.constant.is_some() || (*type_b == FieldType::Constant);
                 if *type_a != *type_b {
                     "*const".to_string()
                 } else if child_a_const || child_b_const {
@@ -149,6 +220,10 @@ impl MathCostFn {
             Math::Pair(_) => "pair".to_string(),
             Math::Fst(_) => "fst".to_string(),
             Math::Snd(_) => "snd".to_string(),
+            Math::Triple(_) => "triple".to_string(),
+            Math::C0(_) => "c0".to_string(),
+            Math::C1(_) => "c1".to_string(),
+            Math::C2(_) => "c2".to_string(),
         }
     }
 
@@ -161,6 +236,21 @@ impl MathCostFn {
         // 3. Look up numeric cost
         self.cost_model.get_cost(&enode_type, &op) as f64
     }
+
+    /// The field type and operation string the cost of `enode` is looked up by.
+    pub fn enode_label(&self, enode: &Math) -> OpLabel {
+        OpLabel {
+            field: self.determine_enode_type(enode).to_string(),
+            op: self.get_operation_string(enode),
+        }
+    }
+
+    /// Same as `calc_enode_cost`, but for the latency table.
+    pub fn calc_enode_latency(&mut self, enode: &Math) -> f64 {
+        let enode_type = self.determine_enode_type(enode);
+        let op = self.get_operation_string(enode);
+        self.cost_model.get_latency(&enode_type, &op)
+    }
 }
 
 /// === IMPLEMENT THE `CostFunction<Math>` TRAIT SO Extractor::new(...) COMPILES ===
@@ -177,25 +267,101 @@ impl CostFunction<Math> for MathCostFn {
     }
 }
 
+/// Cost of a term in the pair simplifier: how deep the `pair`s and `triple`s sit, then
+/// ties broken by the number of distinct products, so that Karatsuba and
+/// complex squaring beat the schoolbook forms that expand to the same pairs.
+#[derive(Debug, Clone)]
+pub struct PairCost {
+    pub pairs: usize,
+    /// The distinct `*`, `sq` and `inv` e-nodes of the term, shared ones once.
+    pub products: BTreeSet<Math>,
+}
+
+impl PartialEq for PairCost {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for PairCost {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some((self.pairs, self.products.len()).cmp(&(other.pairs, other.products.len())))
+    }
+}
+
 pub struct PairCostFn;
 
 impl CostFunction<Math> for PairCostFn {
-    type Cost = usize;
+    type Cost = PairCost;
 
-    fn cost<C>(&mut self, enode: &Math, mut child_costs: C) -> usize
+    fn cost<C>(&mut self, enode: &Math, mut child_costs: C) -> PairCost
     where
-        C: FnMut(Id) -> usize,
+        C: FnMut(Id) -> PairCost,
     {
         let op_cost = match enode {
             Math::Constant(_) => 0,
             Math::Symbol(_) => 0,
-            Math::Pair(_) => 10,
+            Math::Pair(_) | Math::Triple(_) => 10,
             _ => 2,
         };
-        match enode {
-            Math::Pair(_) => enode.fold(10, |sum: usize, id| sum + op_cost * child_costs(id)),
-            _ => enode.fold(0, |sum: usize, id| sum + op_cost * child_costs(id))
-
+        let mut products = BTreeSet::new();
+        if matches!(enode, Math::Mul(_) | Math::Sq(_) | Math::Inv(_)) {
+            products.insert(enode.clone());
         }
+        let init = match enode {
+            Math::Pair(_) | Math::Triple(_) => 10,
+            _ => 0,
+        };
+        let pairs = enode.fold(init, |sum: usize, id| {
+            let child = child_costs(id);
+            products.extend(child.products);
+            sum + op_cost * child.pairs
+        });
+        PairCost { pairs, products }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tower::Tower;
+
+    #[test]
+    fn test_non_residue_term_cost() {
+        // The BN254 tower of the README, with Fp12 = Fp6[w]/(w² - v).
+        let tower: Tower = serde_json::from_str(
+            r#"[
+                { "over": "fp",  "degree": 2, "non_residue": "-1" },
+                { "over": "fp2", "degree": 3, "non_residue": "xi" },
+                { "over": "fp6", "degree": 2, "non_residue": "(triple 0 1 0)" }
+            ]"#,
+        )
+        .unwrap();
+        let cost_model: CostModel = serde_json::from_str(
+            r#"{
+                "costs": { "fp6": { "*": 18, "*const": 12, "*nr": 3 } },
+                "default_costs": {}
+            }"#,
+        )
+        .unwrap();
+        let symbol_types = HashMap::from([("a".to_string(), FieldType::FpExt(6))]);
+        let analysis = TypeAnalysis::new(symbol_types.clone())
+            .with_modulus(Some(7.into()))
+            .with_tower(&tower);
+        let mut egraph = EGraph::new(analysis);
+        let v = egraph.add_expr(&"(triple 0 1 0)".parse().unwrap());
+        // Folded constants are the same class, so (triple 0 (- 2 1) 7) is v as well.
+        let product = egraph.add_expr(&"(* (triple 0 (- 2 1) 7) a)".parse().unwrap());
+        egraph.rebuild();
+        assert_eq!(egraph[v].data.ty, FieldType::FpExt(6));
+
+        let mut costfn = MathCostFn {
+            cost_model,
+            symbol_types,
+            egraph: egraph.clone(),
+        };
+        let node = egraph[product].nodes[0].clone();
+        assert!(TypeAnalysis::is_non_residue(&egraph, node.children()[0]));
+        assert_eq!(costfn.enode_label(&node).to_string(), "fp6:*nr");
+        assert_eq!(costfn.calc_enode_cost(&node), 3.0);
+    }
+}
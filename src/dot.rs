@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use egg::{EGraph, Id, Language};
+use egraph_serialize::{ClassId, NodeId};
+
+use crate::analysis::TypeAnalysis;
+use crate::cost::MathCostFn;
+use crate::extractor_structures::ExtractionResult;
+use crate::language::Math;
+
+const CHOSEN_COLOR: &str = "palegreen";
+
+/// Renders the egraph in Graphviz DOT: each e-class is a cluster labelled with
+/// its id, `FieldType` and folded constant if any, each e-node shows its operation and `MathCostFn`
+/// cost, and an edge goes from an e-node to each of its children's clusters.
+///
+/// The nodes `result` selects (by the node ids of `egg_to_serialized_egraph`)
+/// are filled and their edges drawn bold, and the root clusters are bold.
+/// With `reachable_only`, only the classes the extracted DAG uses are drawn,
+/// with all of their nodes, so the alternatives to each choice stay visible.
+pub fn egraph_to_dot(
+    egraph: &EGraph<Math, TypeAnalysis>,
+    mut costfn: MathCostFn,
+    result: &ExtractionResult,
+    roots: &[ClassId],
+    reachable_only: bool,
+) -> String {
+    let is_chosen = |class: Id, i: usize| {
+        result
+            .choices
+            .get(&ClassId::from(class.to_string()))
+            .is_some_and(|node| *node == NodeId::from(format!("{}.{}", class, i)))
+    };
+
+    let shown: HashSet<Id> = if reachable_only {
+        let mut shown = HashSet::new();
+        let mut todo: Vec<Id> = roots
+            .iter()
+            .filter_map(|root| root.to_string().parse::<usize>().ok().map(Id::from))
+            .collect();
+        while let Some(class) = todo.pop() {
+            let class = egraph.find(class);
+            if !shown.insert(class) {
+                continue;
+            }
+            for (i, node) in egraph[class].nodes.iter().enumerate() {
+                if is_chosen(class, i) {
+                    todo.extend(node.children());
+                }
+            }
+        }
+        shown
+    } else {
+        egraph.classes().map(|class| class.id).collect()
+    };
+    let is_root = |class: Id| roots.iter().any(|root| root.to_string() == class.to_string());
+
+    let mut out = String::new();
+    writeln!(out, "digraph egraph {{").unwrap();
+    writeln!(out, "  compound=true").unwrap();
+    writeln!(out, "  clusterrank=local").unwrap();
+    writeln!(out, "  node [shape=box, style=rounded]").unwrap();
+
+    let mut classes: Vec<Id> = shown.iter().copied().collect();
+    classes.sort();
+    for &class in &classes {
+        let eclass = &egraph[class];
+        writeln!(out, "  subgraph cluster_{} {{", class).unwrap();
+        let style = if is_root(class) { "bold" } else { "dotted" };
+        writeln!(out, "    style={}", style).unwrap();
+        let value = match &eclass.data.constant {
+            Some(c) => format!(" = {}", c),
+            None => String::new(),
+        };
+        writeln!(out, "    label=\"{}: {}{}\"", class, eclass.data.ty.to_string(), value).unwrap();
+        for (i, node) in eclass.nodes.iter().enumerate() {
+            let cost = costfn.calc_enode_cost(node);
+            let fill = if is_chosen(class, i) {
+                format!(", style=\"rounded,filled\", fillcolor={}", CHOSEN_COLOR)
+            } else {
+                String::new()
+            };
+            writeln!(
+                out,
+                "    \"{}.{}\" [label=\"{}\\ncost {}\"{}]",
+                class,
+                i,
+                escape(&node.to_string()),
+                cost,
+                fill
+            )
+            .unwrap();
+        }
+        writeln!(out, "  }}").unwrap();
+    }
+
+    for &class in &classes {
+        for (i, node) in egraph[class].nodes.iter().enumerate() {
+            let chosen = is_chosen(class, i);
+            for child in node.children() {
+                let child = egraph.find(*child);
+                if !shown.contains(&child) {
+                    continue;
+                }
+                // Edges go to the child's cluster, through its first node.
+                let mut attributes = vec![];
+                if child != class {
+                    attributes.push(format!("lhead=cluster_{}", child));
+                }
+                if chosen {
+                    attributes.push("style=bold".to_string());
+                }
+                writeln!(
+                    out,
+                    "  \"{}.{}\" -> \"{}.0\" [{}]",
+                    class,
+                    i,
+                    child,
+                    attributes.join(", ")
+                )
+                .unwrap();
+            }
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::FieldType;
+    use crate::extractor_structures::Extractor;
+    use crate::faster_greedy_dag::FasterGreedyDagExtractor;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_dot_highlights_extraction() {
+        let symbols: HashMap<String, FieldType> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|s| (s.to_string(), FieldType::FpExt(2)))
+            .collect();
+        let mut egraph = EGraph::new(TypeAnalysis::new(symbols));
+        let root = egraph.add_expr(&"(+ (* a b) c)".parse().unwrap());
+        let ab = egraph.add_expr(&"(* a b)".parse().unwrap());
+        let ba = egraph.add_expr(&"(* b a)".parse().unwrap());
+        egraph.union(ab, ba);
+        let unused = egraph.add_expr(&"d".parse().unwrap());
+        egraph.rebuild();
+        let (root, unused) = (egraph.find(root), egraph.find(unused));
+
+        let costfn = || MathCostFn::from_file(&egraph, "inputs/cost_model.json").unwrap();
+        let mut serialized = crate::egg_to_serialized_egraph(&egraph, costfn());
+        let roots = vec![ClassId::from(root.to_string())];
+        serialized.root_eclasses = roots.clone();
+        let result = FasterGreedyDagExtractor::default().extract(&serialized, &roots);
+
+        let dot = egraph_to_dot(&egraph, costfn(), &result, &roots, false);
+        assert_eq!(dot.matches("subgraph cluster_").count(), egraph.number_of_classes());
+        for class in egraph.classes() {
+            assert!(dot.contains(&format!("subgraph cluster_{} {{", class.id)));
+        }
+        for node in result.choices.values() {
+            let line = dot
+                .lines()
+                .find(|line| line.trim_start().starts_with(&format!("\"{}\" [", node)))
+                .unwrap();
+            assert!(line.contains("fillcolor=palegreen"));
+        }
+        assert_eq!(dot.matches("fillcolor=palegreen").count(), result.choices.len());
+        let root_cluster = format!("subgraph cluster_{} {{\n    style=bold\n", root);
+        assert!(dot.contains(&root_cluster));
+
+        // The unused class isn't part of the extracted DAG.
+        let reachable = egraph_to_dot(&egraph, costfn(), &result, &roots, true);
+        assert!(!reachable.contains(&format!("subgraph cluster_{} {{", unused)));
+        assert_eq!(
+            reachable.matches("subgraph cluster_").count(),
+            egraph.number_of_classes() - 1
+        );
+        assert!(reachable.contains(&root_cluster));
+    }
+}
@@ -7,6 +7,8 @@ use ordered_float::NotNan;
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::collections::HashMap;
 pub const INFINITY: Cost = unsafe { NotNan::new_unchecked(std::f64::INFINITY) };
+/// Slack for comparing costs that went through a solver or different sums.
+pub const EPSILON_ALLOWANCE: f64 = 0.00001;
 
 /** A data structure to maintain a queue of unique elements.
 
@@ -70,6 +72,11 @@ where
 pub trait Extractor: Sync {
     fn extract(&self, egraph: &EGraph, roots: &[ClassId]) -> ExtractionResult;
 
+    /// `extract`, for extractors that also write files and can fail to.
+    fn try_extract(&self, egraph: &EGraph, roots: &[ClassId]) -> Result<ExtractionResult, String> {
+        Ok(self.extract(egraph, roots))
+    }
+
     fn boxed(self) -> Box<dyn Extractor>
     where
         Self: Sized + 'static,
@@ -109,9 +116,135 @@ where
     }
 }
 
+/// Where an extraction came from, so callers can tell a proven optimum
+/// apart from a heuristic answer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExtractionStatus {
+    /// Nothing has been extracted: an extractor hasn't filled the result in,
+    /// or an exact extractor stopped before finding any extraction.
+    #[default]
+    NoSolution,
+    /// Produced by the greedy DAG extractor (or the ILP fell back to it).
+    Greedy,
+    /// An exact extractor (ILP or branch and bound) finished and proved the
+    /// extraction optimal.
+    Optimal,
+    /// An exact extractor timed out, but its incumbent beat the greedy result.
+    ImprovedOnTimeout,
+    /// Local search found a cheaper extraction than the greedy one.
+    LocalSearch,
+    /// Read from the solution file of another solver, nothing is known about it.
+    External,
+    /// The time budget cut saturation or the extraction short: the best
+    /// extraction found in time, not proven optimal.
+    TimedOut,
+    /// A root has no acyclic term, or no extraction meets the operation
+    /// constraints, as proven by an exact extractor. Nothing is selected.
+    Infeasible,
+    /// The greedy extractor found no extraction that meets the operation
+    /// constraints, which doesn't mean there is none. Nothing is selected.
+    GreedyGaveUp,
+}
+
+impl ExtractionStatus {
+    pub fn is_optimal(&self) -> bool {
+        *self == ExtractionStatus::Optimal
+    }
+
+    /// Whether the result selects anything at all.
+    pub fn has_extraction(&self) -> bool {
+        !matches!(
+            self,
+            ExtractionStatus::NoSolution
+                | ExtractionStatus::Infeasible
+                | ExtractionStatus::GreedyGaveUp
+        )
+    }
+}
+
+impl std::fmt::Display for ExtractionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtractionStatus::NoSolution => write!(f, "no extraction found"),
+            ExtractionStatus::Greedy => write!(f, "greedy"),
+            ExtractionStatus::Optimal => write!(f, "proven optimal"),
+            ExtractionStatus::ImprovedOnTimeout => {
+                write!(f, "improved on timeout (not proven optimal)")
+            }
+            ExtractionStatus::LocalSearch => write!(f, "local search (not proven optimal)"),
+            ExtractionStatus::External => write!(f, "external solution file"),
+            ExtractionStatus::TimedOut => {
+                write!(f, "time budget exhausted (best found so far, not proven optimal)")
+            }
+            ExtractionStatus::Infeasible => write!(f, "infeasible under the operation constraints"),
+            ExtractionStatus::GreedyGaveUp => write!(
+                f,
+                "greedy found nothing under the operation constraints (not proven infeasible)"
+            ),
+        }
+    }
+}
+
+/// Why an extraction isn't a valid program for its roots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The egraph has no roots to extract.
+    NoRoots,
+    /// A root class has no node selected.
+    MissingRoot(ClassId),
+    /// A selected node needs a class that has no node selected.
+    UnselectedDependency { class: ClassId, parent: ClassId },
+    /// The node selected for a class isn't in the egraph.
+    UnknownNode { class: ClassId, node: NodeId },
+    /// The node selected for a class belongs to another class.
+    WrongClass {
+        class: ClassId,
+        node: NodeId,
+        node_class: ClassId,
+    },
+    /// The selected nodes form a cycle, given as the classes along it (the
+    /// first and last are the same).
+    Cycle(Vec<ClassId>),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::NoRoots => write!(f, "no root eclasses"),
+            ValidationError::MissingRoot(class) => {
+                write!(f, "root class {} has no selected node", class)
+            }
+            ValidationError::UnselectedDependency { class, parent } => write!(
+                f,
+                "class {} is needed by class {} but has no selected node",
+                class, parent
+            ),
+            ValidationError::UnknownNode { class, node } => {
+                write!(f, "class {} selects node {}, which isn't in the egraph", class, node)
+            }
+            ValidationError::WrongClass {
+                class,
+                node,
+                node_class,
+            } => write!(
+                f,
+                "class {} selects node {}, which belongs to class {}",
+                class, node, node_class
+            ),
+            ValidationError::Cycle(path) => {
+                let path: Vec<String> = path.iter().map(|c| c.to_string()).collect();
+                write!(f, "selected nodes form a cycle: {}", path.join(" -> "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
 #[derive(Default, Clone)]
 pub struct ExtractionResult {
     pub choices: IndexMap<ClassId, NodeId>,
+    pub status: ExtractionStatus,
 }
 
 #[derive(Clone, Copy)]
@@ -121,36 +254,46 @@ enum Status {
 }
 
 impl ExtractionResult {
-    pub fn check(&self, egraph: &EGraph) {
-        // should be a root
-        assert!(!egraph.root_eclasses.is_empty());
-
-        // All roots should be selected.
-        for cid in egraph.root_eclasses.iter() {
-            assert!(self.choices.contains_key(cid));
+    /// Validate the extraction of `egraph.root_eclasses`: every root and every
+    /// class they depend upon is selected, selected nodes belong to their
+    /// class, and the selection is acyclic.
+    pub fn check(&self, egraph: &EGraph) -> Result<(), ValidationError> {
+        if egraph.root_eclasses.is_empty() {
+            return Err(ValidationError::NoRoots);
         }
 
-        // No cycles
-        assert!(self.find_cycles(&egraph, &egraph.root_eclasses).is_empty());
-
         // Nodes should match the class they are selected into.
         for (cid, nid) in &self.choices {
-            let node = &egraph[nid];
-            assert!(node.eclass == *cid);
+            let Some(node) = egraph.nodes.get(nid) else {
+                return Err(ValidationError::UnknownNode {
+                    class: cid.clone(),
+                    node: nid.clone(),
+                });
+            };
+            if node.eclass != *cid {
+                return Err(ValidationError::WrongClass {
+                    class: cid.clone(),
+                    node: nid.clone(),
+                    node_class: node.eclass.clone(),
+                });
+            }
         }
 
-        // All the nodes the roots depend upon should be selected.
-        let mut todo: Vec<ClassId> = egraph.root_eclasses.to_vec();
-        let mut visited: FxHashSet<ClassId> = Default::default();
-        while let Some(cid) = todo.pop() {
-            if !visited.insert(cid.clone()) {
-                continue;
-            }
-            assert!(self.choices.contains_key(&cid));
+        self.dependency_order(egraph, &egraph.root_eclasses)
+            .map(|_| ())
+    }
 
-            for child in &egraph[&self.choices[&cid]].children {
-                todo.push(egraph.nid_to_cid(child).clone());
-            }
+    pub fn infeasible() -> Self {
+        ExtractionResult {
+            choices: IndexMap::new(),
+            status: ExtractionStatus::Infeasible,
+        }
+    }
+
+    pub fn greedy_gave_up() -> Self {
+        ExtractionResult {
+            choices: IndexMap::new(),
+            status: ExtractionStatus::GreedyGaveUp,
         }
     }
 
@@ -192,91 +335,210 @@ impl ExtractionResult {
         }
     }
 
-    pub fn tree_cost(&self, egraph: &EGraph, roots: &[ClassId]) -> Cost {
-        let node_roots = roots
+    /// The classes the roots depend upon, children before their parents.
+    /// Fails if one of them isn't selected or the selection has a cycle.
+    fn dependency_order(
+        &self,
+        egraph: &EGraph,
+        roots: &[ClassId],
+    ) -> Result<Vec<ClassId>, ValidationError> {
+        let mut status = IndexMap::<ClassId, Status>::default();
+        let mut path = vec![];
+        let mut order = vec![];
+        for root in roots {
+            self.dependency_dfs(egraph, root, None, &mut status, &mut path, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    fn dependency_dfs(
+        &self,
+        egraph: &EGraph,
+        class_id: &ClassId,
+        parent: Option<&ClassId>,
+        status: &mut IndexMap<ClassId, Status>,
+        path: &mut Vec<ClassId>,
+        order: &mut Vec<ClassId>,
+    ) -> Result<(), ValidationError> {
+        match status.get(class_id) {
+            Some(Status::Done) => return Ok(()),
+            Some(Status::Doing) => {
+                let start = path.iter().position(|c| c == class_id).unwrap();
+                let mut cycle = path[start..].to_vec();
+                cycle.push(class_id.clone());
+                return Err(ValidationError::Cycle(cycle));
+            }
+            None => (),
+        }
+
+        let Some(node_id) = self.choices.get(class_id) else {
+            return Err(match parent {
+                None => ValidationError::MissingRoot(class_id.clone()),
+                Some(parent) => ValidationError::UnselectedDependency {
+                    class: class_id.clone(),
+                    parent: parent.clone(),
+                },
+            });
+        };
+        let Some(node) = egraph.nodes.get(node_id) else {
+            return Err(ValidationError::UnknownNode {
+                class: class_id.clone(),
+                node: node_id.clone(),
+            });
+        };
+
+        status.insert(class_id.clone(), Status::Doing);
+        path.push(class_id.clone());
+        for child in &node.children {
+            let child_cid = egraph.nid_to_cid(child);
+            self.dependency_dfs(egraph, child_cid, Some(class_id), status, path, order)?;
+        }
+        path.pop();
+        status.insert(class_id.clone(), Status::Done);
+        order.push(class_id.clone());
+        Ok(())
+    }
+
+    /// Cost of the extraction as a tree, paying for shared classes at every use.
+    pub fn tree_cost(&self, egraph: &EGraph, roots: &[ClassId]) -> Result<Cost, ValidationError> {
+        let mut costs: FxHashMap<ClassId, Cost> = FxHashMap::default();
+        for cid in self.dependency_order(egraph, roots)? {
+            let node = &egraph[&self.choices[&cid]];
+            let children: Cost = node
+                .children
+                .iter()
+                .map(|child| costs[egraph.nid_to_cid(child)])
+                .sum();
+            costs.insert(cid, node.cost + children);
+        }
+        Ok(roots.iter().map(|root| costs[root]).sum())
+    }
+
+    /// The selected nodes the roots depend upon, each once.
+    pub fn dag_nodes(&self, egraph: &EGraph, roots: &[ClassId]) -> Result<Vec<NodeId>, ValidationError> {
+        Ok(self
+            .dependency_order(egraph, roots)?
             .iter()
             .map(|cid| self.choices[cid].clone())
-            .collect::<Vec<NodeId>>();
-        self.tree_cost_rec(egraph, &node_roots, &mut HashMap::new())
+            .collect())
     }
 
-    fn tree_cost_rec(
+    /// Cost of the extraction as a DAG, paying for each class once.
+    pub fn dag_cost(&self, egraph: &EGraph, roots: &[ClassId]) -> Result<Cost, ValidationError> {
+        Ok(self
+            .dependency_order(egraph, roots)?
+            .iter()
+            .map(|cid| egraph[&self.choices[cid]].cost)
+            .sum())
+    }
+
+    /// Length of the longest root-to-leaf path, summing each selected node's
+    /// latency (nodes missing from `latencies` count as 0). Assumes no cycles.
+    pub fn critical_path(
         &self,
         egraph: &EGraph,
-        roots: &[NodeId],
-        memo: &mut HashMap<NodeId, Cost>,
-    ) -> Cost {
-        let mut cost = Cost::default();
-        for root in roots {
-            if let Some(c) = memo.get(root) {
-                cost += *c;
-                continue;
+        roots: &[ClassId],
+        latencies: &IndexMap<NodeId, f64>,
+    ) -> f64 {
+        fn finish(
+            result: &ExtractionResult,
+            egraph: &EGraph,
+            cid: &ClassId,
+            latencies: &IndexMap<NodeId, f64>,
+            memo: &mut FxHashMap<ClassId, f64>,
+        ) -> f64 {
+            if let Some(&t) = memo.get(cid) {
+                return t;
             }
-            let class = egraph.nid_to_cid(root);
-            let node = &egraph[&self.choices[class]];
-            let inner = node.cost + self.tree_cost_rec(egraph, &node.children, memo);
-            memo.insert(root.clone(), inner);
-            cost += inner;
+            let node_id = &result.choices[cid];
+            let slowest_child = egraph[node_id]
+                .children
+                .iter()
+                .map(|child| finish(result, egraph, egraph.nid_to_cid(child), latencies, memo))
+                .fold(0.0, f64::max);
+            let t = latencies.get(node_id).copied().unwrap_or(0.0) + slowest_child;
+            memo.insert(cid.clone(), t);
+            t
         }
-        cost
+
+        let mut memo = FxHashMap::default();
+        roots
+            .iter()
+            .map(|root| finish(self, egraph, root, latencies, &mut memo))
+            .fold(0.0, f64::max)
     }
 
-    // this will loop if there are cycles
-    pub fn dag_cost(&self, egraph: &EGraph, roots: &[ClassId]) -> Cost {
-        let mut costs: IndexMap<ClassId, Cost> = IndexMap::new();
-        let mut todo: Vec<ClassId> = roots.to_vec();
+    /// The extraction as a straight-line program, in topological order.
+    ///
+    /// Every selected class is computed exactly once: a class used more than
+    /// once gets a temporary named after it (`t3 = (* a0 b0)`), a class used
+    /// once is inlined into its only user. Leaves (symbols and constants) are
+    /// operands, not ops, so they are always inlined. The last statements bind
+    /// the roots to `out` (or `out0`, `out1`, ... for several roots). Adding up
+    /// the cost of each class computed gives exactly `dag_cost`. Assumes no
+    /// cycles.
+    pub fn dag_program(&self, egraph: &EGraph, roots: &[ClassId]) -> Vec<String> {
+        // How many times each class is used, by the roots or by a selected node.
+        let mut uses: IndexMap<ClassId, usize> = IndexMap::new();
+        let mut todo: Vec<ClassId> = vec![];
+        for root in roots {
+            *uses.entry(root.clone()).or_default() += 1;
+            todo.push(root.clone());
+        }
+        let mut visited: FxHashSet<ClassId> = Default::default();
         while let Some(cid) = todo.pop() {
-            let node_id = &self.choices[&cid];
-            let node = &egraph[node_id];
-            if costs.insert(cid.clone(), node.cost).is_some() {
+            if !visited.insert(cid.clone()) {
                 continue;
             }
-            for child in &node.children {
-                todo.push(egraph.nid_to_cid(child).clone());
+            for child in &egraph[&self.choices[&cid]].children {
+                let child_cid = egraph.nid_to_cid(child);
+                *uses.entry(child_cid.clone()).or_default() += 1;
+                todo.push(child_cid.clone());
             }
         }
-        costs.values().sum()
-    }
-
-    pub fn dag_extracted_exprs(&self, egraph: &EGraph, roots: &[ClassId]) -> Vec<String> {
-        use std::collections::HashSet;
-        let mut exprs = Vec::new();
 
-        fn build_expr(
+        fn emit(
             cid: &ClassId,
             egraph: &EGraph,
             result: &ExtractionResult,
-            seen: &mut HashSet<ClassId>,
+            uses: &IndexMap<ClassId, usize>,
+            names: &mut FxHashMap<ClassId, String>,
+            program: &mut Vec<String>,
         ) -> String {
-            // Check if we have already seen this class ID to avoid infinite recursion
-            // if !seen.insert(cid.clone()) {
-            //     // Already visited this class (shared subexpr), just use its symbol
-            //     return format!("${}", cid);
-            // }
-            let node_id = &result.choices[cid];
-            let node = &egraph[node_id];
+            if let Some(name) = names.get(cid) {
+                return name.clone();
+            }
+            let node = &egraph[&result.choices[cid]];
             if node.children.is_empty() {
-                // Leaf node: print its value
-                format!("{}", node.op)
-            } else {
-                let children_exprs: Vec<String> = node
-                    .children
-                    .iter()
-                    .map(|child| {
-                        let child_cid = egraph.nid_to_cid(child);
-                        build_expr(child_cid, egraph, result, seen)
-                    })
-                    .collect();
-                // Print as S-expression: (op child1 child2 ...)
-                format!("({} {})", node.op, children_exprs.join(" "))
+                return node.op.clone();
+            }
+            let children: Vec<String> = node
+                .children
+                .iter()
+                .map(|child| emit(egraph.nid_to_cid(child), egraph, result, uses, names, program))
+                .collect();
+            let expr = format!("({} {})", node.op, children.join(" "));
+            if uses[cid] < 2 {
+                return expr;
             }
+            let name = format!("t{}", cid);
+            program.push(format!("{} = {}", name, expr));
+            names.insert(cid.clone(), name.clone());
+            name
         }
 
-        for root in roots {
-            let mut seen = HashSet::new();
-            exprs.push(build_expr(root, egraph, self, &mut seen));
+        let mut names = FxHashMap::default();
+        let mut program = vec![];
+        for (i, root) in roots.iter().enumerate() {
+            let expr = emit(root, egraph, self, &uses, &mut names, &mut program);
+            let out = if roots.len() == 1 {
+                "out".to_string()
+            } else {
+                format!("out{}", i)
+            };
+            program.push(format!("{} = {}", out, expr));
         }
-        exprs
+        program
     }
 
     pub fn node_sum_cost<M>(&self, egraph: &EGraph, node: &Node, costs: &M) -> Cost
@@ -294,3 +556,89 @@ impl ExtractionResult {
                 .sum::<Cost>()
     }
 }
+
+/// Adds the node `node` (named `<class>.<i>`) to a test egraph. Children are
+/// given by class, and stand for that class's first node, `<child>.0`.
+#[cfg(test)]
+pub(crate) fn add_test_node(
+    egraph: &mut EGraph,
+    node: &str,
+    op: &str,
+    children: &[&str],
+    cost: f64,
+) {
+    let class = node.split('.').next().unwrap();
+    egraph.add_node(
+        node.to_string(),
+        Node {
+            op: op.to_string(),
+            children: children.iter().map(|c| NodeId::from(format!("{}.0", c))).collect(),
+            eclass: ClassId::from(class.to_string()),
+            cost: Cost::new(cost).unwrap(),
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dag_program_shares_classes() {
+        // (+ (* a b) (* a b)), with the product in a single class
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "0.0", "a", &[], 0.0);
+        add_test_node(&mut egraph, "1.0", "b", &[], 0.0);
+        add_test_node(&mut egraph, "2.0", "*", &["0", "1"], 5.0);
+        add_test_node(&mut egraph, "3.0", "+", &["2", "2"], 1.0);
+        let roots = vec![ClassId::from("3".to_string())];
+
+        let mut result = ExtractionResult::default();
+        for class in ["0", "1", "2", "3"] {
+            result.choose(class.to_string().into(), format!("{}.0", class).into());
+        }
+
+        assert_eq!(
+            result.dag_program(&egraph, &roots),
+            vec!["t2 = (* a b)", "out = (+ t2 t2)"]
+        );
+        assert_eq!(result.dag_cost(&egraph, &roots).unwrap().into_inner(), 6.0);
+    }
+
+    #[test]
+    fn test_cycles_are_reported() {
+        // 0 selects (f 1) and 1 selects (g 0)
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "0.0", "f", &["1"], 1.0);
+        add_test_node(&mut egraph, "1.0", "g", &["0"], 1.0);
+        egraph.root_eclasses.push(ClassId::from("0".to_string()));
+        let roots = egraph.root_eclasses.clone();
+
+        let mut result = ExtractionResult::default();
+        result.choose("0".to_string().into(), "0.0".to_string().into());
+        result.choose("1".to_string().into(), "1.0".to_string().into());
+
+        let cycle = ValidationError::Cycle(vec![
+            "0".to_string().into(),
+            "1".to_string().into(),
+            "0".to_string().into(),
+        ]);
+        assert_eq!(result.check(&egraph), Err(cycle.clone()));
+        assert_eq!(result.dag_cost(&egraph, &roots), Err(cycle.clone()));
+        assert_eq!(result.tree_cost(&egraph, &roots), Err(cycle));
+
+        // Neither class has an acyclic term, so nothing can be extracted, but
+        // only branch and bound proves it.
+        let greedy = crate::faster_greedy_dag::FasterGreedyDagExtractor::default()
+            .extract(&egraph, &roots);
+        assert_eq!(greedy.status, ExtractionStatus::GreedyGaveUp);
+        assert!(!greedy.status.has_extraction());
+        let bnb = crate::bnb_dag::BranchAndBoundDagExtractor {
+            timeout_in_seconds: 10,
+            constraints: crate::constraints::NodeConstraints::default(),
+        }
+        .extract(&egraph, &roots);
+        assert_eq!(bnb.status, ExtractionStatus::Infeasible);
+        assert_eq!(ExtractionResult::default().status, ExtractionStatus::NoSolution);
+    }
+}
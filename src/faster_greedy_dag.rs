@@ -9,6 +9,7 @@ use indexmap::IndexMap;
 use ordered_float::NotNan;
 use rustc_hash::FxHashMap;
 
+use crate::constraints::NodeConstraints;
 use crate::extractor_structures::*;
 
 pub type Cost = NotNan<f64>;
@@ -21,7 +22,12 @@ struct CostSet {
     choice: NodeId,
 }
 
-pub struct FasterGreedyDagExtractor;
+/// Forbidden nodes are never selected. Count caps aren't taken into account
+/// while extracting, an extraction that breaks one is given up on.
+#[derive(Default)]
+pub struct FasterGreedyDagExtractor {
+    pub constraints: NodeConstraints,
+}
 
 impl FasterGreedyDagExtractor {
     fn calculate_cost_set(
@@ -98,7 +104,7 @@ impl FasterGreedyDagExtractor {
 }
 
 impl Extractor for FasterGreedyDagExtractor {
-    fn extract(&self, egraph: &EGraph, _roots: &[ClassId]) -> ExtractionResult {
+    fn extract(&self, egraph: &EGraph, roots: &[ClassId]) -> ExtractionResult {
         let mut parents = IndexMap::<ClassId, Vec<NodeId>>::with_capacity(egraph.classes().len());
         let n2c = |nid: &NodeId| egraph.nid_to_cid(nid);
         let mut analysis_pending = UniqueQueue::default();
@@ -115,7 +121,7 @@ impl Extractor for FasterGreedyDagExtractor {
                 }
 
                 // start the analysis from leaves
-                if egraph[node].is_leaf() {
+                if egraph[node].is_leaf() && self.constraints.allows(node) {
                     analysis_pending.insert(node.clone());
                 }
             }
@@ -130,6 +136,9 @@ impl Extractor for FasterGreedyDagExtractor {
         while let Some(node_id) = analysis_pending.pop() {
             let class_id = n2c(&node_id);
             let node = &egraph[&node_id];
+            if !self.constraints.allows(&node_id) {
+                continue;
+            }
             if node.children.iter().all(|c| costs.contains_key(n2c(c))) {
                 let lookup = costs.get(class_id);
                 let mut prev_cost = INFINITY;
@@ -149,6 +158,14 @@ impl Extractor for FasterGreedyDagExtractor {
             result.choose(cid, cost_set.choice);
         }
 
+        // A root without a cost has no term made of allowed nodes.
+        if roots.iter().any(|root| !result.choices.contains_key(root)) {
+            return ExtractionResult::greedy_gave_up();
+        }
+        if !self.constraints.is_empty() && !self.constraints.satisfied_by(&result, egraph, roots) {
+            return ExtractionResult::greedy_gave_up();
+        }
+        result.status = ExtractionStatus::Greedy;
         result
     }
 }
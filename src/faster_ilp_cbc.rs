@@ -44,25 +44,50 @@ and ban each cycle that we find, then try solving again, we'll get a new solutio
 cycles, will not contain any of the cycles we've previously seen. We repeat this until timeout, or until
 we get an optimal solution without cycles.
 
+The second approach (lazy cycle blocking) is the default. The first can be selected with
+`Config::acyclicity`, it rules out every cycle in a single model, so the solver is only called once.
+
+With `export`, the model is also written as an LP or MPS file once it is solved, blocked
+cycles included, and `read_solution_file` turns another solver's solution of it back into
+an extraction. Columns are named after what they select: `c_<class>` and `n_<node>`.
+
+Nodes of forbidden operations are left out of the model, and each operation count cap is
+one extra row. With caps, the pruning that keeps only the cheapest way of computing a class is
+turned off, since that way might be the capped one.
 
 */
 #![allow(dead_code)]
 
+use std::collections::HashMap;
 use std::fmt;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use coin_cbc::{Col, Model};
+use coin_cbc::Col;
 use egraph_serialize::*;
 use indexmap::{IndexMap, IndexSet};
 use ordered_float::NotNan;
 use rustc_hash::FxHashSet;
+use serde::Deserialize;
 
+use crate::constraints::NodeConstraints;
+use crate::lp_file::{read_solution, RecordingModel};
 use crate::extractor_structures::*;
 
 pub type Cost = NotNan<f64>;
-pub const EPSILON_ALLOWANCE: f64 = 0.00001;
 
-#[derive(Debug)]
+/// How the extraction is kept acyclic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Acyclicity {
+    /// Block the cycles found in each solution and solve again.
+    Lazy,
+    /// Give every class an integer level that must decrease along selected edges.
+    TopologicalOrder,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub pull_up_costs: bool,
     pub remove_self_loops: bool,
@@ -76,10 +101,13 @@ pub struct Config {
     pub remove_empty_classes: bool,
     pub return_improved_on_timeout: bool,
     pub remove_single_zero_cost: bool,
+    /// Maximum number of cycles collected from one solver solution.
+    pub cycle_limit: usize,
+    pub acyclicity: Acyclicity,
 }
 
 impl Config {
-    pub const fn default() -> Self {
+    pub const fn new() -> Self {
         Self {
             pull_up_costs: true,
             remove_self_loops: true,
@@ -93,10 +121,19 @@ impl Config {
             remove_empty_classes: true,
             return_improved_on_timeout: true,
             remove_single_zero_cost: true,
+            cycle_limit: CYCLE_LIMIT,
+            acyclicity: Acyclicity::Lazy,
         }
     }
 }
 
+// Needed by serde for fields missing from the run configuration.
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
+
 struct NodeILP {
     variable: Col,
     cost: Cost,
@@ -179,14 +216,44 @@ impl ClassILP {
     }
 }
 
-pub struct FasterCbcExtractorWithTimeout<const TIMEOUT_IN_SECONDS: u32>;
+pub struct FasterCbcExtractorWithTimeout {
+    pub timeout_in_seconds: u32,
+    pub config: Config,
+    pub constraints: NodeConstraints,
+    /// Node sets whose counts are minimised in turn before the cost, see
+    /// `extract_lexicographic`. Empty for a plain cost extraction.
+    pub levels: Vec<FxHashSet<NodeId>>,
+    /// Where to write the solved model, a `.lp` or `.mps` file.
+    pub export: Option<PathBuf>,
+}
 
 // Some problems take >36,000 seconds to optimise.
-impl<const TIMEOUT_IN_SECONDS: u32> Extractor
-    for FasterCbcExtractorWithTimeout<TIMEOUT_IN_SECONDS>
-{
+impl Extractor for FasterCbcExtractorWithTimeout {
     fn extract(&self, egraph: &EGraph, roots: &[ClassId]) -> ExtractionResult {
-        return extract(egraph, roots, &Config::default(), TIMEOUT_IN_SECONDS);
+        self.try_extract(egraph, roots).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Fails if the model can't be written to `export`.
+    fn try_extract(&self, egraph: &EGraph, roots: &[ClassId]) -> Result<ExtractionResult, String> {
+        if !self.levels.is_empty() {
+            return extract_lexicographic(
+                egraph,
+                roots,
+                &self.config,
+                &self.constraints,
+                self.timeout_in_seconds,
+                &self.levels,
+                self.export.as_deref(),
+            );
+        }
+        return extract(
+            egraph,
+            roots,
+            &self.config,
+            &self.constraints,
+            self.timeout_in_seconds,
+            self.export.as_deref(),
+        );
     }
 }
 
@@ -194,327 +261,748 @@ pub struct FasterCbcExtractor;
 
 impl Extractor for FasterCbcExtractor {
     fn extract(&self, egraph: &EGraph, roots: &[ClassId]) -> ExtractionResult {
-        return extract(egraph, roots, &Config::default(), std::u32::MAX);
+        // Without a model to export, nothing can fail.
+        return extract(
+            egraph,
+            roots,
+            &Config::default(),
+            &NodeConstraints::default(),
+            std::u32::MAX,
+            None,
+        )
+        .unwrap();
     }
 }
 
+// Fails if the solved model can't be written to `export`.
 fn extract(
     egraph: &EGraph,
     roots_slice: &[ClassId],
     config: &Config,
+    constraints: &NodeConstraints,
     timeout: u32,
-) -> ExtractionResult {
-    // todo from now on we don't use roots_slice - be good to prevent using it any more.
-    let mut roots = roots_slice.to_vec();
-    roots.sort();
-    roots.dedup();
-
-    let simp_start_time = std::time::Instant::now();
-
-    let mut model = Model::default();
-    //silence verbose stdout output
-    model.set_parameter("loglevel", "0");
-
-    let n2c = |nid: &NodeId| egraph.nid_to_cid(nid);
-
-    let mut vars: IndexMap<ClassId, ClassILP> = egraph
-        .classes()
-        .values()
-        .map(|class| {
-            let cvars = ClassILP {
-                active: model.add_binary(),
-                variables: class.nodes.iter().map(|_| model.add_binary()).collect(),
-                costs: class.nodes.iter().map(|n| egraph[n].cost).collect(),
-                members: class.nodes.clone(),
-                childrens_classes: class
-                    .nodes
-                    .iter()
-                    .map(|n| {
-                        egraph[n]
-                            .children
-                            .iter()
-                            .map(|c| n2c(c).clone())
-                            .collect::<IndexSet<ClassId>>()
-                    })
-                    .collect(),
-            };
-            (class.id.clone(), cvars)
-        })
-        .collect();
+    export: Option<&Path>,
+) -> Result<ExtractionResult, String> {
+    match IlpProblem::build(egraph, roots_slice, config, constraints) {
+        Some(mut problem) => {
+            let result = problem.solve(config, timeout);
+            problem.export(export)?;
+            Ok(result)
+        }
+        None => {
+            log::info!("Infeasible while simplifying, no model to export");
+            Ok(ExtractionResult::infeasible())
+        }
+    }
+}
 
-    let initial_result = super::faster_greedy_dag::FasterGreedyDagExtractor.extract(egraph, &roots);
-    initial_result.check(egraph);
-    let initial_result_cost = initial_result.dag_cost(egraph, &roots);
+/// Reads a solution of the model that `extract` builds with the same arguments,
+/// e.g. from a model file written with `export` and solved by another solver.
+pub fn read_solution_file(
+    egraph: &EGraph,
+    roots: &[ClassId],
+    config: &Config,
+    constraints: &NodeConstraints,
+    path: &Path,
+) -> Result<ExtractionResult, String> {
+    let values = read_solution(path)?;
+    let problem = IlpProblem::build(egraph, roots, config, constraints)
+        .ok_or_else(|| "The problem is infeasible, there is no model to solve".to_string())?;
+    Ok(problem.result_from_values(&values))
+}
 
-    // For classes where we know the choice already, we set the nodes early.
-    let mut result = ExtractionResult::default();
-
-    //This could be much more efficient, but it only takes less than 5 seconds for all our benchmarks.
-    //The ILP solver takes the time.
-    for _i in 1..3 {
-        remove_with_loops(&mut vars, &roots, config);
-        remove_high_cost(&mut vars, initial_result_cost, &roots, config);
-        remove_more_expensive_subsumed_nodes(&mut vars, config);
-        remove_unreachable_classes(&mut vars, &roots, config);
-        pull_up_with_single_parent(&mut vars, &roots, config);
-        pull_up_costs(&mut vars, &roots, config);
-        remove_single_zero_cost(&mut vars, &mut result, &roots, config);
-        find_extra_roots(&mut vars, &mut roots, config);
-        remove_empty_classes(&mut vars, config);
+/*
+Returns up to k structurally distinct extractions, cheapest first.
+
+After each optimal solve, a "no-good" cut is added that stops the solver from selecting
+all the nodes of that extraction again, and the model is re-solved. The pruning that
+removes extractions costing the same or a little more than the best is turned off,
+because those are exactly the alternatives we're after.
+
+Stops early when there are no more extractions, or when a solve doesn't finish within
+what's left of the timeout.
+*/
+pub fn extract_top_k(
+    egraph: &EGraph,
+    roots: &[ClassId],
+    config: &Config,
+    constraints: &NodeConstraints,
+    timeout: u32,
+    k: usize,
+) -> Vec<ExtractionResult> {
+    let config = Config {
+        remove_high_cost_nodes: false,
+        remove_more_expensive_subsumed_nodes: false,
+        ..config.clone()
+    };
+
+    let mut results = vec![];
+    let Some(mut problem) = IlpProblem::build(egraph, roots, &config, constraints) else {
+        return results;
+    };
+
+    let start_time = SystemTime::now();
+    while results.len() < k {
+        let elapsed = SystemTime::now()
+            .duration_since(start_time)
+            .map(|d| d.as_secs())
+            .unwrap_or(u64::MAX);
+        let remaining = timeout.saturating_sub(elapsed.try_into().unwrap_or(u32::MAX));
+
+        let result = problem.solve(&config, remaining);
+        if !result.status.is_optimal() {
+            break;
+        }
+        problem.block_extraction(&result);
+        // The greedy extraction may be one of those just cut off.
+        problem.initial_is_bound = false;
+        results.push(result);
     }
+    results
+}
 
-    for (classid, class) in &vars {
-        if class.members() == 0 {
-            if roots.contains(classid) {
-                log::info!("Infeasible, root has no possible children, returning empty solution");
-                return ExtractionResult::default();
-            }
+/*
+Minimises the number of selected nodes of each level in turn, then the DAG cost.
+
+Each level is solved to optimality, then its count is held at that optimum with an extra row
+while the next level is solved. So with levels [multiplications, additions] the result has
+the fewest multiplications possible, and the fewest additions among those, as in "3M + 5A".
 
-            model.set_col_upper(class.active, 0.0);
-            continue;
+The pruning that keeps only the cheapest ways of computing each class is turned off, those
+aren't necessarily the ways with the fewest operations of a level.
+
+If a level doesn't solve to optimality within the timeout, its result is returned as is.
+Fails if the solved model can't be written to `export`.
+*/
+pub fn extract_lexicographic(
+    egraph: &EGraph,
+    roots: &[ClassId],
+    config: &Config,
+    constraints: &NodeConstraints,
+    timeout: u32,
+    levels: &[FxHashSet<NodeId>],
+    export: Option<&Path>,
+) -> Result<ExtractionResult, String> {
+    let config = Config {
+        remove_high_cost_nodes: false,
+        remove_more_expensive_subsumed_nodes: false,
+        remove_single_zero_cost: false,
+        ..config.clone()
+    };
+
+    let Some(mut problem) = IlpProblem::build(egraph, roots, &config, constraints) else {
+        return Ok(ExtractionResult::infeasible());
+    };
+
+    let start_time = SystemTime::now();
+    let remaining = || {
+        let elapsed = SystemTime::now()
+            .duration_since(start_time)
+            .map(|d| d.as_secs())
+            .unwrap_or(u64::MAX);
+        timeout.saturating_sub(elapsed.try_into().unwrap_or(u32::MAX))
+    };
+
+    for level in levels {
+        problem.set_objective(Objective::Count(level.clone()), &config);
+        let result = problem.solve(&config, remaining());
+        if !result.status.is_optimal() {
+            // Once the greedy extraction is no longer a bound, an unfinished
+            // solve comes back empty. The greedy extraction is still valid.
+            if result.choices.is_empty() && result.status != ExtractionStatus::Infeasible {
+                return Ok(problem.initial_result.clone());
+            }
+            return Ok(result);
         }
 
-        if class.members() == 1 && class.childrens_classes[0].is_empty() && class.costs[0] == 0.0 {
-            continue;
+        let count = problem.objective_value(&result).unwrap();
+        log::info!("Lexicographic level of {} nodes: {}", level.len(), count);
+        // The greedy extraction stops being an answer once it uses more.
+        if problem.initial_is_bound
+            && problem
+                .objective_value(&problem.initial_result)
+                .map_or(true, |initial| initial > count)
+        {
+            problem.initial_is_bound = false;
         }
+        add_count_row(&mut problem.model, &problem.vars, level, count.into_inner());
+    }
 
-        // class active == some node active
-        // sum(for node_active in class) == class_active
+    problem.set_objective(Objective::Cost, &config);
+    let result = problem.solve(&config, remaining());
+    problem.export(export)?;
+    Ok(result)
+}
 
-        let row = model.add_row();
-        model.set_row_equal(row, 0.0);
-        model.set_weight(row, class.active, -1.0);
-        for &node_active in &class.variables.iter().collect::<IndexSet<_>>() {
-            model.set_weight(row, *node_active, 1.0);
+// What the model currently minimises.
+#[derive(Clone)]
+enum Objective {
+    // The DAG cost.
+    Cost,
+    // How many of these nodes are selected.
+    Count(FxHashSet<NodeId>),
+}
+
+impl Objective {
+    // What selecting `member`, with `cost` in the simplified problem, adds to the objective.
+    fn of_node(&self, member: &NodeId, cost: Cost) -> f64 {
+        match self {
+            Objective::Cost => cost.into_inner(),
+            Objective::Count(nodes) if nodes.contains(member) => 1.0,
+            Objective::Count(_) => 0.0,
         }
+    }
+}
 
-        let childrens_classes_var =
-            |cc: &IndexSet<ClassId>| cc.iter().map(|n| vars[n].active).collect::<IndexSet<_>>();
+// The simplified problem, as sent to CBC.
+struct IlpProblem<'a> {
+    egraph: &'a EGraph,
+    model: RecordingModel,
+    vars: IndexMap<ClassId, ClassILP>,
+    roots: Vec<ClassId>,
+    // For classes where we know the choice already, we set the nodes early.
+    fixed: ExtractionResult,
+    initial_result: ExtractionResult,
+    initial_result_cost: Cost,
+    // Whether the greedy extraction is still a valid answer (and so an upper bound).
+    initial_is_bound: bool,
+    objective: Objective,
+}
 
-        let mut intersection: IndexSet<Col> = Default::default();
+impl<'a> IlpProblem<'a> {
+    // Returns None if the problem is found to be infeasible while simplifying.
+    fn build(
+        egraph: &'a EGraph,
+        roots_slice: &[ClassId],
+        config: &Config,
+        constraints: &NodeConstraints,
+    ) -> Option<Self> {
+        // todo from now on we don't use roots_slice - be good to prevent using it any more.
+        let mut roots = roots_slice.to_vec();
+        roots.sort();
+        roots.dedup();
+
+        let simp_start_time = std::time::Instant::now();
+
+        let mut model = RecordingModel::default();
+        //silence verbose stdout output
+        model.set_parameter("loglevel", "0");
+
+        let n2c = |nid: &NodeId| egraph.nid_to_cid(nid);
+
+        let mut vars: IndexMap<ClassId, ClassILP> = egraph
+            .classes()
+            .values()
+            .map(|class| {
+                let cvars = ClassILP {
+                    active: model.add_binary(),
+                    variables: class.nodes.iter().map(|_| model.add_binary()).collect(),
+                    costs: class.nodes.iter().map(|n| egraph[n].cost).collect(),
+                    members: class.nodes.clone(),
+                    childrens_classes: class
+                        .nodes
+                        .iter()
+                        .map(|n| {
+                            egraph[n]
+                                .children
+                                .iter()
+                                .map(|c| n2c(c).clone())
+                                .collect::<IndexSet<ClassId>>()
+                        })
+                        .collect(),
+                };
+                (class.id.clone(), cvars)
+            })
+            .collect();
 
-        if config.take_intersection_of_children_in_class {
-            // otherwise the intersection is empty (i.e. disabled.)
-            intersection = childrens_classes_var(&class.childrens_classes[0].clone());
+        for (class_id, class) in &vars {
+            model.set_col_name(class.active, class_col_name(class_id));
+            for (member, &node_active) in class.members.iter().zip(&class.variables) {
+                model.set_col_name(node_active, node_col_name(member));
+            }
         }
 
-        for childrens_classes in &class.childrens_classes[1..] {
-            intersection = intersection
-                .intersection(&childrens_classes_var(childrens_classes))
-                .cloned()
-                .collect();
+        // Forbidden nodes are simply left out of the model.
+        for class in vars.values_mut() {
+            for i in (0..class.members()).rev() {
+                if !constraints.allows(&class.members[i]) {
+                    class.remove(i);
+                }
+            }
         }
 
-        // A class being active implies that all in the intersection
-        // of it's children are too.
-        for c in &intersection {
-            let row = model.add_row();
-            model.set_row_upper(row, 0.0);
-            model.set_weight(row, class.active, 1.0);
-            model.set_weight(row, *c, -1.0);
+        let initial_result = super::faster_greedy_dag::FasterGreedyDagExtractor {
+            constraints: constraints.clone(),
         }
+        .extract(egraph, &roots);
+        let (initial_result_cost, initial_is_bound) = match initial_result.dag_cost(egraph, &roots) {
+            Ok(cost) => (cost, true),
+            Err(e) => {
+                log::info!("Greedy extraction is invalid ({}), solving without a bound", e);
+                (INFINITY, false)
+            }
+        };
 
-        for (childrens_classes, &node_active) in
-            class.childrens_classes.iter().zip(&class.variables)
-        {
-            for child_active in childrens_classes_var(childrens_classes) {
-                // node active implies child active, encoded as:
-                //   node_active <= child_active
-                //   node_active - child_active <= 0
-                if !intersection.contains(&child_active) {
-                    let row = model.add_row();
-                    model.set_row_upper(row, 0.0);
-                    model.set_weight(row, node_active, 1.0);
-                    model.set_weight(row, child_active, -1.0);
+        // The pruning below keeps one cheapest way of computing each class, which
+        // might be one that breaks a count cap.
+        let capped_config;
+        let config = if constraints.caps.is_empty() {
+            config
+        } else {
+            capped_config = Config {
+                remove_high_cost_nodes: config.remove_high_cost_nodes && initial_is_bound,
+                remove_more_expensive_subsumed_nodes: false,
+                remove_single_zero_cost: false,
+                ..config.clone()
+            };
+            &capped_config
+        };
+
+        let mut fixed = ExtractionResult::default();
+
+        //This could be much more efficient, but it only takes less than 5 seconds for all our benchmarks.
+        //The ILP solver takes the time.
+        for _i in 1..3 {
+            remove_with_loops(&mut vars, &roots, config);
+            remove_high_cost(&mut vars, initial_result_cost, &roots, config);
+            remove_more_expensive_subsumed_nodes(&mut vars, config);
+            remove_unreachable_classes(&mut vars, &roots, config);
+            pull_up_with_single_parent(&mut vars, &roots, config);
+            pull_up_costs(&mut vars, &roots, config);
+            remove_single_zero_cost(&mut vars, &mut fixed, &roots, config);
+            find_extra_roots(&mut vars, &mut roots, config);
+            remove_empty_classes(&mut vars, config);
+        }
+
+        for (classid, class) in &vars {
+            if class.members() == 0 {
+                if roots.contains(classid) {
+                    log::info!("Infeasible, root has no possible children, returning empty solution");
+                    return None;
                 }
+
+                model.set_col_upper(class.active, 0.0);
+                continue;
             }
-        }
-    }
 
-    for root in &roots {
-        model.set_col_lower(vars[root].active, 1.0);
-    }
+            if class.members() == 1 && class.childrens_classes[0].is_empty() && class.costs[0] == 0.0 {
+                continue;
+            }
 
-    let mut objective_fn_terms = 0;
+            // class active == some node active
+            // sum(for node_active in class) == class_active
 
-    for (_class_id, c_var) in &vars {
-        let mut min_cost = 0.0;
+            let row = model.add_row();
+            model.set_row_equal(row, 0.0);
+            model.set_weight(row, class.active, -1.0);
+            for &node_active in &class.variables.iter().collect::<IndexSet<_>>() {
+                model.set_weight(row, *node_active, 1.0);
+            }
 
-        /* Moves the minimum of all the nodes up onto the class.
-        Most helpful when the members of the class all have the same cost.
-        For example if the members' costs are [1,1,1], three terms get
-        replaced by one in the objective function.
-        */
+            let childrens_classes_var =
+                |cc: &IndexSet<ClassId>| cc.iter().map(|n| vars[n].active).collect::<IndexSet<_>>();
 
-        if config.move_min_cost_of_members_to_class {
-            min_cost = c_var
-                .costs
-                .iter()
-                .min()
-                .unwrap_or(&Cost::default())
-                .into_inner();
-        }
+            let mut intersection: IndexSet<Col> = Default::default();
 
-        if min_cost != 0.0 {
-            model.set_obj_coeff(c_var.active, min_cost);
-            objective_fn_terms += 1;
-        }
+            if config.take_intersection_of_children_in_class {
+                // otherwise the intersection is empty (i.e. disabled.)
+                intersection = childrens_classes_var(&class.childrens_classes[0].clone());
+            }
 
-        for (&node_active, &node_cost) in c_var.variables.iter().zip(c_var.costs.iter()) {
-            if *node_cost - min_cost != 0.0 {
-                model.set_obj_coeff(node_active, *node_cost - min_cost);
+            for childrens_classes in &class.childrens_classes[1..] {
+                intersection = intersection
+                    .intersection(&childrens_classes_var(childrens_classes))
+                    .cloned()
+                    .collect();
             }
-        }
-    }
 
-    log::info!("Objective function terms: {}", objective_fn_terms);
+            // A class being active implies that all in the intersection
+            // of it's children are too.
+            for c in &intersection {
+                let row = model.add_row();
+                model.set_row_upper(row, 0.0);
+                model.set_weight(row, class.active, 1.0);
+                model.set_weight(row, *c, -1.0);
+            }
 
-    if false {
-        //config.initialise_with_approx
-        // set initial solution based on a non-optimal extraction.
-        // using this causes the ILP solver to return unsound results.
-        set_initial_solution(&vars, &mut model, &initial_result);
-    }
+            for (childrens_classes, &node_active) in
+                class.childrens_classes.iter().zip(&class.variables)
+            {
+                for child_active in childrens_classes_var(childrens_classes) {
+                    // node active implies child active, encoded as:
+                    //   node_active <= child_active
+                    //   node_active - child_active <= 0
+                    if !intersection.contains(&child_active) {
+                        let row = model.add_row();
+                        model.set_row_upper(row, 0.0);
+                        model.set_weight(row, node_active, 1.0);
+                        model.set_weight(row, child_active, -1.0);
+                    }
+                }
+            }
+        }
 
-    if false {
-        return initial_result;
-    }
+        for root in &roots {
+            model.set_col_lower(vars[root].active, 1.0);
+        }
 
-    log::info!(
-        "Time spent before solving: {}ms",
-        simp_start_time.elapsed().as_millis()
-    );
+        for cap in &constraints.caps {
+            add_count_row(&mut model, &vars, &cap.nodes, cap.max as f64);
+        }
 
-    let start_time = SystemTime::now();
+        if config.acyclicity == Acyclicity::TopologicalOrder {
+            add_topological_order(&mut model, &vars);
+        }
 
-    loop {
-        // Set the solver limit based on how long has passed already.
-        if let Ok(difference) = SystemTime::now().duration_since(start_time) {
-            let seconds = timeout.saturating_sub(difference.as_secs().try_into().unwrap());
-            model.set_parameter("seconds", &seconds.to_string());
-        } else {
-            model.set_parameter("seconds", "0");
+        set_cost_objective(&mut model, &vars, config);
+
+        if false {
+            //config.initialise_with_approx
+            // set initial solution based on a non-optimal extraction.
+            // using this causes the ILP solver to return unsound results.
+            set_initial_solution(&vars, &mut model, &initial_result);
         }
 
-        //This starts from scratch solving each time. I've looked quickly
-        //at the API and didn't see how to call it incrementally.
-        let solution = model.solve();
         log::info!(
-            "CBC status {:?}, {:?}, obj = {}",
-            solution.raw().status(),
-            solution.raw().secondary_status(),
-            solution.raw().obj_value(),
+            "Time spent before solving: {}ms",
+            simp_start_time.elapsed().as_millis()
         );
 
-        if solution.raw().is_proven_infeasible() {
-            log::info!("Infeasible, returning empty solution");
-            return ExtractionResult::default();
+        Some(IlpProblem {
+            egraph,
+            model,
+            vars,
+            roots,
+            fixed,
+            initial_result,
+            initial_result_cost,
+            initial_is_bound,
+            objective: Objective::Cost,
+        })
+    }
+
+    fn set_objective(&mut self, objective: Objective, config: &Config) {
+        match &objective {
+            Objective::Cost => set_cost_objective(&mut self.model, &self.vars, config),
+            Objective::Count(nodes) => set_count_objective(&mut self.model, &self.vars, nodes),
         }
+        self.objective = objective;
+    }
 
-        let stopped_without_finishing = solution.raw().status() != coin_cbc::raw::Status::Finished;
+    // The value of the current objective for an extraction.
+    fn objective_value(&self, result: &ExtractionResult) -> Result<Cost, ValidationError> {
+        match &self.objective {
+            Objective::Cost => result.dag_cost(self.egraph, &self.roots),
+            Objective::Count(nodes) => {
+                let count = result
+                    .dag_nodes(self.egraph, &self.roots)?
+                    .iter()
+                    .filter(|n| nodes.contains(n))
+                    .count();
+                Ok(Cost::new(count as f64).unwrap())
+            }
+        }
+    }
 
-        if stopped_without_finishing {
-            log::info!("CBC stopped before finishing");
+    // What to return when the solver doesn't come up with anything better.
+    fn fallback(&self) -> ExtractionResult {
+        if self.initial_is_bound {
+            self.initial_result.clone()
+        } else {
+            ExtractionResult::default()
+        }
+    }
 
-            if !config.return_improved_on_timeout
-                || solution.raw().obj_value() > initial_result_cost.into_inner()
-            {
-                log::info!(
-                    "Unfinished CBC solution returned, solver: {}, initial: {}",
-                    solution.raw().obj_value(),
-                    initial_result_cost
-                );
-                return initial_result;
-            }
+    fn upper_bound(&self) -> Cost {
+        if !self.initial_is_bound {
+            INFINITY
+        } else if let Objective::Cost = self.objective {
+            self.initial_result_cost
+        } else {
+            self.objective_value(&self.initial_result).unwrap_or(INFINITY)
         }
+    }
 
-        let mut cost = 0.0;
-        for (id, var) in &vars {
-            let active = solution.col(var.active) > 0.0;
-
-            if active {
-                assert!(var.members() > 0);
-                let mut node_idx = 0;
-                if var.members() != 1 {
-                    assert_eq!(
-                        1,
-                        var.variables
-                            .iter()
-                            .filter(|&n| solution.col(*n) > 0.0)
-                            .count()
-                    );
+    fn solve(&mut self, config: &Config, timeout: u32) -> ExtractionResult {
+        let egraph = self.egraph;
+        let roots = self.roots.clone();
+        let upper_bound = self.upper_bound();
 
-                    node_idx = var
-                        .variables
-                        .iter()
-                        .position(|&n| solution.col(n) > 0.0)
-                        .unwrap();
-                }
+        let start_time = SystemTime::now();
+
+        loop {
+            let model = &mut self.model;
+            let vars = &self.vars;
+            let objective = &self.objective;
 
-                let node_id = var.members[node_idx].clone();
-                cost += var.costs[node_idx].into_inner();
-                result.choose(id.clone(), node_id);
+            // Set the solver limit based on how long has passed already.
+            if let Ok(difference) = SystemTime::now().duration_since(start_time) {
+                let seconds = timeout.saturating_sub(difference.as_secs().try_into().unwrap());
+                model.set_parameter("seconds", &seconds.to_string());
+            } else {
+                model.set_parameter("seconds", "0");
             }
-        }
 
-        let cycles = find_cycles_in_result(&result, &vars, &roots);
+            //This starts from scratch solving each time. I've looked quickly
+            //at the API and didn't see how to call it incrementally.
+            let solution = model.solve();
+            log::info!(
+                "CBC status {:?}, {:?}, obj = {}",
+                solution.raw().status(),
+                solution.raw().secondary_status(),
+                solution.raw().obj_value(),
+            );
+
+            if solution.raw().is_proven_infeasible() {
+                log::info!("Infeasible, returning empty solution");
+                return ExtractionResult::infeasible();
+            }
 
-        log::info!("Cost of solution {cost}");
-        log::info!("Initial result {}", initial_result_cost.into_inner());
-        log::info!("Cost of extraction {}", result.dag_cost(egraph, &roots));
-        log::info!("Cost from solver {}", solution.raw().obj_value());
+            let stopped_without_finishing =
+                solution.raw().status() != coin_cbc::raw::Status::Finished;
 
-        if stopped_without_finishing {
-            log::info!("Timed out");
-            if cycles.is_empty() {
-                // The reported cost of the solution sometimes differs to the dag cost, so we're
-                // a bit carefu..
-                let extraction_dag_cost = result.dag_cost(egraph, &roots);
+            if stopped_without_finishing {
+                log::info!("CBC stopped before finishing");
 
-                // Not sure if this will ever fail..
-                result.check(egraph);
-                if extraction_dag_cost < initial_result_cost {
+                if !config.return_improved_on_timeout
+                    || solution.raw().obj_value() > upper_bound.into_inner()
+                {
                     log::info!(
-                        "Returning result of incomplete search saving: {}",
-                        initial_result_cost - extraction_dag_cost
+                        "Unfinished CBC solution returned, solver: {}, initial: {}",
+                        solution.raw().obj_value(),
+                        upper_bound
                     );
-                    return result;
+                    return self.fallback();
+                }
+            }
+
+            let mut result = self.fixed.clone();
+            let mut cost = 0.0;
+            for (id, var) in vars {
+                let active = solution.col(var.active) > 0.0;
+
+                if active {
+                    assert!(var.members() > 0);
+                    let mut node_idx = 0;
+                    if var.members() != 1 {
+                        assert_eq!(
+                            1,
+                            var.variables
+                                .iter()
+                                .filter(|&n| solution.col(*n) > 0.0)
+                                .count()
+                        );
+
+                        node_idx = var
+                            .variables
+                            .iter()
+                            .position(|&n| solution.col(n) > 0.0)
+                            .unwrap();
+                    }
+
+                    let node_id = var.members[node_idx].clone();
+                    cost += objective.of_node(&node_id, var.costs[node_idx]);
+                    result.choose(id.clone(), node_id);
+                }
+            }
+
+            let cycles = find_cycles_in_result(&result, vars, &roots, config.cycle_limit);
+
+            log::info!("Cost of solution {cost}");
+            log::info!("Initial result {}", upper_bound.into_inner());
+            log::info!("Cost of extraction {:?}", result.dag_cost(egraph, &roots));
+            log::info!("Cost from solver {}", solution.raw().obj_value());
+
+            if stopped_without_finishing {
+                log::info!("Timed out");
+                if cycles.is_empty() {
+                    // The reported cost of the solution sometimes differs to the dag cost, so we're
+                    // a bit carefu..
+                    let extraction_dag_cost = match self.objective_value(&result) {
+                        Ok(cost) => cost,
+                        Err(e) => {
+                            log::info!("Incomplete search gave an invalid extraction: {}", e);
+                            return self.fallback();
+                        }
+                    };
+                    if extraction_dag_cost < upper_bound {
+                        log::info!(
+                            "Returning result of incomplete search saving: {}",
+                            upper_bound - extraction_dag_cost
+                        );
+                        result.status = ExtractionStatus::ImprovedOnTimeout;
+                        return result;
+                    } else {
+                        return self.fallback();
+                    }
                 } else {
-                    return initial_result;
+                    log::info!("Found cycle in solution, but solver timed out");
+                    return self.fallback();
                 }
+            }
+
+            if cycles.is_empty() {
+                assert!(cost <= upper_bound.into_inner() + EPSILON_ALLOWANCE);
+                assert!(self
+                    .objective_value(&result)
+                    .is_ok_and(|value| (value - cost).abs() < EPSILON_ALLOWANCE));
+                assert!((cost - solution.raw().obj_value()).abs() < EPSILON_ALLOWANCE);
+
+                result.status = ExtractionStatus::Optimal;
+                return result;
             } else {
-                log::info!("Found cycle in solution, but solver timed out");
-                return initial_result;
+                log::info!("Refining by blocking cycles: {}", cycles.len());
+                for c in &cycles {
+                    block_cycle(model, c, vars);
+                }
+            }
+
+            if false {
+                //config.initialise_with_previous_solution
+
+                // This is a bit complicated.
+
+                //First, The COIN-OR CBC interface has this function
+                //model.set_initial_solution(&solution);
+                //But it crashes if the model has more columns than the solution does, which
+                //happens if we've just blocked cycles.
+
+                // Second, when used before solving, the ILP solver was sometimes unsound.
+                // I didn't see unsound results from the ILP solver using this function here, but
+                // it makes me wary, plus it doesn't speed up things noticeably.
+                set_initial_solution(vars, model, &result);
             }
         }
+    }
 
-        if cycles.is_empty() {
-            assert!(cost <= initial_result_cost.into_inner() + EPSILON_ALLOWANCE);
-            assert!((result.dag_cost(egraph, &roots) - cost).abs() < EPSILON_ALLOWANCE);
-            assert!((cost - solution.raw().obj_value()).abs() < EPSILON_ALLOWANCE);
+    fn export(&self, path: Option<&Path>) -> Result<(), String> {
+        match path {
+            Some(path) => self.model.program().write(path),
+            None => Ok(()),
+        }
+    }
 
-            return result;
-        } else {
-            log::info!("Refining by blocking cycles: {}", cycles.len());
-            for c in &cycles {
-                block_cycle(&mut model, c, &vars);
+    // The extraction that the named column values of a solution select.
+    fn result_from_values(&self, values: &HashMap<String, f64>) -> ExtractionResult {
+        let is_set = |name: String| values.get(&name).is_some_and(|&value| value > 0.5);
+        let mut result = self.fixed.clone();
+        for (class_id, class) in &self.vars {
+            if !is_set(class_col_name(class_id)) {
+                continue;
+            }
+            // If no node is set, `check` reports the class as unselected.
+            if let Some(node) = class.members.iter().find(|&n| is_set(node_col_name(n))) {
+                result.choose(class_id.clone(), node.clone());
             }
         }
+        result.status = ExtractionStatus::External;
+        result
+    }
 
-        if false {
-            //config.initialise_with_previous_solution
+    // Adds a constraint so that all of the nodes the extraction selects (from the classes
+    // still in the model) can't be selected together again.
+    fn block_extraction(&mut self, result: &ExtractionResult) {
+        let mut selected: IndexSet<Col> = IndexSet::new();
+        let mut visited: FxHashSet<ClassId> = Default::default();
+        let mut todo = self.roots.clone();
+        while let Some(class_id) = todo.pop() {
+            if !visited.insert(class_id.clone()) {
+                continue;
+            }
+            // Classes fixed while simplifying are the same in every extraction.
+            let Some(class) = self.vars.get(&class_id) else {
+                continue;
+            };
+            let node_id = &result.choices[&class_id];
+            if let Some(var) = class.get_variable_for_node(node_id) {
+                selected.insert(var);
+            }
+            todo.extend(class.get_children_of_node(node_id).iter().cloned());
+        }
 
-            // This is a bit complicated.
+        let row = self.model.add_row();
+        self.model.set_row_upper(row, selected.len() as f64 - 1.0);
+        for var in selected {
+            self.model.set_weight(row, var, 1.0);
+        }
+    }
+}
 
-            //First, The COIN-OR CBC interface has this function
-            //model.set_initial_solution(&solution);
-            //But it crashes if the model has more columns than the solution does, which
-            //happens if we've just blocked cycles.
+// Names of the columns in exported models.
+fn class_col_name(class_id: &ClassId) -> String {
+    format!("c_{}", class_id)
+}
 
-            // Second, when used before solving, the ILP solver was sometimes unsound.
-            // I didn't see unsound results from the ILP solver using this function here, but
-            // it makes me wary, plus it doesn't speed up things noticeably.
-            set_initial_solution(&vars, &mut model, &result);
+fn node_col_name(node_id: &NodeId) -> String {
+    format!("n_{}", node_id)
+}
+
+// Minimise the DAG cost.
+fn set_cost_objective(model: &mut RecordingModel, vars: &IndexMap<ClassId, ClassILP>, config: &Config) {
+    let mut objective_fn_terms = 0;
+
+    for c_var in vars.values() {
+        let mut min_cost = 0.0;
+
+        /* Moves the minimum of all the nodes up onto the class.
+        Most helpful when the members of the class all have the same cost.
+        For example if the members' costs are [1,1,1], three terms get
+        replaced by one in the objective function.
+        */
+
+        if config.move_min_cost_of_members_to_class {
+            min_cost = c_var
+                .costs
+                .iter()
+                .min()
+                .unwrap_or(&Cost::default())
+                .into_inner();
+        }
+
+        // Zero coefficients are set too, the model may have had another objective.
+        model.set_obj_coeff(c_var.active, min_cost);
+        if min_cost != 0.0 {
+            objective_fn_terms += 1;
+        }
+
+        for (&node_active, &node_cost) in c_var.variables.iter().zip(c_var.costs.iter()) {
+            model.set_obj_coeff(node_active, *node_cost - min_cost);
+        }
+    }
+
+    log::info!("Objective function terms: {}", objective_fn_terms);
+}
+
+// Minimise how many of `nodes` are selected.
+fn set_count_objective(
+    model: &mut RecordingModel,
+    vars: &IndexMap<ClassId, ClassILP>,
+    nodes: &FxHashSet<NodeId>,
+) {
+    for c_var in vars.values() {
+        model.set_obj_coeff(c_var.active, 0.0);
+        for (member, &node_active) in c_var.members.iter().zip(&c_var.variables) {
+            let weight = if nodes.contains(member) { 1.0 } else { 0.0 };
+            model.set_obj_coeff(node_active, weight);
+        }
+    }
+}
+
+// At most `max` of `nodes` can be selected:
+//   sum(node_active for node in nodes) <= max
+fn add_count_row(
+    model: &mut RecordingModel,
+    vars: &IndexMap<ClassId, ClassILP>,
+    nodes: &FxHashSet<NodeId>,
+    max: f64,
+) {
+    let row = model.add_row();
+    model.set_row_upper(row, max);
+    for c_var in vars.values() {
+        for (member, &node_active) in c_var.members.iter().zip(&c_var.variables) {
+            if nodes.contains(member) {
+                model.set_weight(row, node_active, 1.0);
+            }
         }
     }
 }
@@ -524,7 +1012,7 @@ Using this caused wrong results from the solver. I don't have a good idea why.
 */
 fn set_initial_solution(
     vars: &IndexMap<ClassId, ClassILP>,
-    model: &mut Model,
+    model: &mut RecordingModel,
     initial_result: &ExtractionResult,
 ) {
     for (class, class_vars) in vars {
@@ -754,6 +1242,7 @@ fn find_extra_roots(
 
             let details = vars.get(&r).unwrap();
             if details.childrens_classes.len() == 0 {
+                i += 1;
                 continue;
             }
 
@@ -1045,7 +1534,7 @@ fn reachable(
 }
 
 // Adds constraints to stop the cycle.
-fn block_cycle(model: &mut Model, cycle: &Vec<ClassId>, vars: &IndexMap<ClassId, ClassILP>) {
+fn block_cycle(model: &mut RecordingModel, cycle: &Vec<ClassId>, vars: &IndexMap<ClassId, ClassILP>) {
     if cycle.is_empty() {
         return;
     }
@@ -1085,6 +1574,57 @@ fn block_cycle(model: &mut Model, cycle: &Vec<ClassId>, vars: &IndexMap<ClassId,
     }
 }
 
+/*
+Rules out all cycles in one model. Each class gets an integer level in [0, n-1], where n is
+the number of classes, and a selected node's class must be at a strictly higher level than
+each of its children's classes. Any acyclic extraction can be levelled this way, and a cycle
+would need a level higher than itself.
+
+This adds one row per (node, child class) edge, so the model gets a lot bigger than with lazy
+blocking, but the solver only has to be called once.
+*/
+fn add_topological_order(model: &mut RecordingModel, vars: &IndexMap<ClassId, ClassILP>) {
+    let n = vars.len() as f64;
+    let max_level = n - 1.0;
+
+    let levels: IndexMap<ClassId, Col> = vars
+        .keys()
+        .map(|class_id| {
+            let level = model.add_integer();
+            model.set_col_name(level, format!("l_{}", class_id));
+            model.set_col_lower(level, 0.0);
+            model.set_col_upper(level, max_level);
+            (class_id.clone(), level)
+        })
+        .collect();
+
+    let mut rows = 0;
+    for (class_id, class) in vars {
+        for (childrens_classes, &node_active) in
+            class.childrens_classes.iter().zip(&class.variables)
+        {
+            for child in childrens_classes {
+                if child == class_id {
+                    // A node can't be above its own class.
+                    model.set_col_upper(node_active, 0.0);
+                    continue;
+                }
+                // node active implies level[class] >= level[child] + 1, encoded as:
+                //   level[class] - level[child] + n * (1 - node_active) >= 1
+                //   level[class] - level[child] - n * node_active >= 1 - n
+                let row = model.add_row();
+                model.set_row_lower(row, 1.0 - n);
+                model.set_weight(row, levels[class_id], 1.0);
+                model.set_weight(row, levels[child], -1.0);
+                model.set_weight(row, node_active, -n);
+                rows += 1;
+            }
+        }
+    }
+
+    log::info!("Topological order rows: {rows}");
+}
+
 #[derive(Clone)]
 enum TraverseStatus {
     Doing,
@@ -1101,14 +1641,15 @@ Imagine a 20 node complete graph with one root. From the first node you have
 19 choices, then from the second 18 choices, etc.  When you get to the second
 last node you go back to the root. There are about 10^17 length 18 cycles.
 
-So we limit how many can be found.
+So we limit how many can be found (the default, overridable through Config).
 */
-const CYCLE_LIMIT: usize = 1000;
+pub const CYCLE_LIMIT: usize = 1000;
 
 fn find_cycles_in_result(
     extraction_result: &ExtractionResult,
     vars: &IndexMap<ClassId, ClassILP>,
     roots: &[ClassId],
+    cycle_limit: usize,
 ) -> Vec<Vec<ClassId>> {
     let mut status = IndexMap::<ClassId, TraverseStatus>::default();
     let mut cycles = vec![];
@@ -1121,6 +1662,7 @@ fn find_cycles_in_result(
             &mut status,
             &mut cycles,
             &mut stack,
+            cycle_limit,
         )
     }
     cycles
@@ -1133,6 +1675,7 @@ fn cycle_dfs(
     status: &mut IndexMap<ClassId, TraverseStatus>,
     cycles: &mut Vec<Vec<ClassId>>,
     stack: &mut Vec<ClassId>,
+    cycle_limit: usize,
 ) {
     match status.get(class_id).cloned() {
         Some(TraverseStatus::Done) => (),
@@ -1145,14 +1688,14 @@ fn cycle_dfs(
             cycles.push(cycle);
         }
         None => {
-            if cycles.len() > CYCLE_LIMIT {
+            if cycles.len() > cycle_limit {
                 return;
             }
             status.insert(class_id.clone(), TraverseStatus::Doing);
             stack.push(class_id.clone());
             let node_id = &extraction_result.choices[class_id];
             for child_cid in vars[class_id].get_children_of_node(node_id) {
-                cycle_dfs(extraction_result, vars, child_cid, status, cycles, stack)
+                cycle_dfs(extraction_result, vars, child_cid, status, cycles, stack, cycle_limit)
             }
             let last = stack.pop();
             assert_eq!(*class_id, last.unwrap());
@@ -1160,3 +1703,126 @@ fn cycle_dfs(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extractor_structures::add_test_node;
+
+    #[test]
+    fn test_topological_order_matches_lazy() {
+        // (f b) and (g a) are the cheapest nodes of a and b, but selecting both is a cycle.
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "a.0", "f", &["b"], 1.0);
+        add_test_node(&mut egraph, "a.1", "a", &[], 10.0);
+        add_test_node(&mut egraph, "b.0", "g", &["a"], 1.0);
+        add_test_node(&mut egraph, "b.1", "b", &[], 10.0);
+        add_test_node(&mut egraph, "r.0", "h", &["a", "b"], 1.0);
+        egraph.root_eclasses.push(ClassId::from("r".to_string()));
+        let roots = egraph.root_eclasses.clone();
+
+        let extract_with = |acyclicity: Acyclicity| {
+            FasterCbcExtractorWithTimeout {
+                timeout_in_seconds: 60,
+                config: Config {
+                    acyclicity,
+                    ..Config::default()
+                },
+                constraints: NodeConstraints::default(),
+                levels: vec![],
+                export: None,
+            }
+            .extract(&egraph, &roots)
+        };
+        let lazy = extract_with(Acyclicity::Lazy);
+        let ordered = extract_with(Acyclicity::TopologicalOrder);
+        for result in [&lazy, &ordered] {
+            assert_eq!(result.check(&egraph), Ok(()));
+            assert_eq!(result.status, ExtractionStatus::Optimal);
+        }
+        let cost =
+            |result: &ExtractionResult| result.dag_cost(&egraph, &roots).unwrap().into_inner();
+        assert_eq!(cost(&ordered), 12.0);
+        assert_eq!(cost(&ordered), cost(&lazy));
+    }
+
+    #[test]
+    fn test_lexicographic_counts_before_cost() {
+        // With m = (* a b), r is cheapest as (* m a): two products and no additions,
+        // costing 4. (+ (+ m a) (- m b)) has one product and three additions, costing
+        // 5, and (- m a) one product and one addition, costing 12.
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "a.0", "a", &[], 0.0);
+        add_test_node(&mut egraph, "b.0", "b", &[], 0.0);
+        add_test_node(&mut egraph, "m.0", "*", &["a", "b"], 2.0);
+        add_test_node(&mut egraph, "s.0", "+", &["m", "a"], 1.0);
+        add_test_node(&mut egraph, "t.0", "-", &["m", "b"], 1.0);
+        add_test_node(&mut egraph, "r.0", "*", &["m", "a"], 2.0);
+        add_test_node(&mut egraph, "r.1", "+", &["s", "t"], 1.0);
+        add_test_node(&mut egraph, "r.2", "-", &["m", "a"], 10.0);
+        let roots = vec![ClassId::from("r".to_string())];
+
+        let of_ops = |ops: &[&str]| -> FxHashSet<NodeId> {
+            egraph
+                .nodes
+                .iter()
+                .filter(|(_, node)| ops.contains(&node.op.as_str()))
+                .map(|(id, _)| id.clone())
+                .collect()
+        };
+        let levels = vec![of_ops(&["*", "sq"]), of_ops(&["+", "-"])];
+        let counts = |result: &ExtractionResult| -> Vec<usize> {
+            let nodes = result.dag_nodes(&egraph, &roots).unwrap();
+            levels
+                .iter()
+                .map(|level| nodes.iter().filter(|n| level.contains(n)).count())
+                .collect()
+        };
+        let cost =
+            |result: &ExtractionResult| result.dag_cost(&egraph, &roots).unwrap().into_inner();
+        let constraints = NodeConstraints::default();
+
+        let cheapest =
+            extract(&egraph, &roots, &Config::default(), &constraints, 60, None).unwrap();
+        assert_eq!(cost(&cheapest), 4.0);
+        assert_eq!(counts(&cheapest), vec![2, 0]);
+
+        let config = Config::default();
+        let result =
+            extract_lexicographic(&egraph, &roots, &config, &constraints, 60, &levels, None)
+                .unwrap();
+        assert_eq!(result.status, ExtractionStatus::Optimal);
+        assert_eq!(counts(&result), vec![1, 1]);
+        assert_eq!(cost(&result), 12.0);
+    }
+
+    #[test]
+    fn test_top_k_distinct_and_ordered() {
+        // Two choices for x and two for y: four programs costing 3, 4, 5 and 6.
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "x.0", "x.0", &[], 1.0);
+        add_test_node(&mut egraph, "x.1", "x.1", &[], 2.0);
+        add_test_node(&mut egraph, "y.0", "y.0", &[], 1.0);
+        add_test_node(&mut egraph, "y.1", "y.1", &[], 3.0);
+        add_test_node(&mut egraph, "r.0", "r.0", &["x", "y"], 1.0);
+        let roots = vec![ClassId::from("r".to_string())];
+
+        // Asking for more than there are only works if the cuts exclude every earlier pick.
+        let constraints = NodeConstraints::default();
+        let results = extract_top_k(&egraph, &roots, &Config::default(), &constraints, 60, 10);
+        let costs: Vec<f64> = results
+            .iter()
+            .map(|result| result.dag_cost(&egraph, &roots).unwrap().into_inner())
+            .collect();
+        assert_eq!(costs, vec![3.0, 4.0, 5.0, 6.0]);
+
+        let programs: Vec<IndexSet<NodeId>> = results
+            .iter()
+            .map(|result| result.dag_nodes(&egraph, &roots).unwrap().into_iter().collect())
+            .collect();
+        for (i, program) in programs.iter().enumerate() {
+            assert!(programs[..i].iter().all(|earlier| earlier != program));
+        }
+        assert!(results.iter().all(|result| result.status.is_optimal()));
+    }
+}
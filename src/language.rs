@@ -1,7 +1,62 @@
+use std::fmt;
+use std::str::FromStr;
+
 use egg::*;
-use ordered_float::NotNan;
+use num_bigint::BigInt;
+use num_traits::Num;
+
+/// An exact integer constant, however large, standing for its residue in the
+/// field. Written in decimal or hex (`0x...`), optionally negative, so that
+/// coefficients like non-residues or `-1 mod p` are represented exactly.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Constant(pub BigInt);
+
+impl FromStr for Constant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (radix, digits) = match digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            Some(hex) => (16, hex),
+            None => (10, digits),
+        };
+        // BigInt would accept a sign or separators here, we don't.
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(format!("Not a constant: {}", s));
+        }
+        let value = BigInt::from_str_radix(digits, radix).map_err(|e| e.to_string())?;
+        Ok(Constant(if negative { -value } else { value }))
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Written as a string in JSON, field elements don't fit in a number.
+impl<'de> serde::Deserialize<'de> for Constant {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
 
-pub type Constant = NotNan<f64>;
+impl From<i64> for Constant {
+    fn from(value: i64) -> Self {
+        Constant(BigInt::from(value))
+    }
+}
 
 define_language! {
     pub enum Math {
@@ -15,7 +70,38 @@ define_language! {
         "fst"    = Fst(Id),
         "snd"    = Snd(Id),
 
+        "triple" = Triple([Id; 3]),
+        "c0"     = C0(Id),
+        "c1"     = C1(Id),
+        "c2"     = C2(Id),
+
         Constant(Constant),
         Symbol(Symbol),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_constants() {
+        let p = "21888242871839275222246405745257275088696311157297823662689037894645226208583";
+        assert_eq!(p.parse::<Constant>().unwrap().to_string(), p);
+        assert_eq!("0x1F".parse::<Constant>().unwrap(), Constant::from(31));
+        assert_eq!("-1".parse::<Constant>().unwrap(), Constant::from(-1));
+        assert!("1.5".parse::<Constant>().is_err());
+        assert!("-".parse::<Constant>().is_err());
+        assert!("+1".parse::<Constant>().is_err());
+
+        let expr: RecExpr<Math> = "(+ (* 2 a0) (* -1 xi))".parse().unwrap();
+        assert!(expr
+            .as_ref()
+            .iter()
+            .any(|node| *node == Math::Constant(Constant::from(2))));
+        assert!(expr
+            .as_ref()
+            .iter()
+            .any(|node| matches!(node, Math::Symbol(s) if s.as_str() == "xi")));
+    }
+}
@@ -0,0 +1,263 @@
+/*
+Improves the greedy DAG extraction with local search, for egraphs where the exact extractors
+time out.
+
+The greedy extractor prices every class on its own, so it misses choices that only pay off
+because a child is shared with another part of the DAG. Local search works on whole extractions
+and their real DAG cost instead:
+1) Descent: the classes of the current DAG are visited in a random order, and each is switched
+to whichever of its nodes gives the cheapest DAG, until a whole pass finds no improvement.
+Classes that aren't in the DAG keep the greedy choice, so a node that brings new classes in is
+priced with their greedy sub-DAGs.
+2) Perturbation: to get out of a local optimum, a random class of the best DAG and a few classes
+below it are switched to random nodes, then descent runs again (a large neighbourhood move).
+If that ends cheaper it becomes the best extraction, otherwise we start again from the best.
+
+Choices that make a cycle, or break a count cap of the constraints, are never taken, and
+forbidden nodes are never tried.
+
+The search stops at the timeout, or after `max_stale_rounds` perturbations in a row that
+didn't improve the best extraction. The random choices come from `seed`, so a run is
+reproducible, unless the timeout cuts it short.
+*/
+
+use std::time::{Duration, Instant};
+
+use egraph_serialize::*;
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::constraints::NodeConstraints;
+use crate::extractor_structures::*;
+use crate::faster_greedy_dag::FasterGreedyDagExtractor;
+use crate::rng::SplitMix64;
+
+// How many classes below the perturbed one are switched too.
+const PERTURBATION_DEPTH: usize = 3;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub seed: u64,
+    /// Perturbations in a row that don't improve the best extraction before giving up.
+    pub max_stale_rounds: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            seed: 0,
+            max_stale_rounds: 200,
+        }
+    }
+}
+
+pub struct LocalSearchDagExtractor {
+    pub timeout_in_seconds: u32,
+    pub config: Config,
+    pub constraints: NodeConstraints,
+}
+
+impl Extractor for LocalSearchDagExtractor {
+    fn extract(&self, egraph: &EGraph, roots: &[ClassId]) -> ExtractionResult {
+        let initial_result = FasterGreedyDagExtractor {
+            constraints: self.constraints.clone(),
+        }
+        .extract(egraph, roots);
+
+        let mut search = Search {
+            egraph,
+            roots,
+            constraints: &self.constraints,
+            alternatives: alternatives(egraph, &self.constraints),
+            rng: SplitMix64(self.config.seed),
+            deadline: Instant::now() + Duration::from_secs(self.timeout_in_seconds.into()),
+        };
+        let Some(initial_cost) = search.evaluate(&initial_result) else {
+            log::info!("Greedy extraction isn't usable, nothing to improve");
+            return initial_result;
+        };
+
+        let mut best = initial_result.clone();
+        let mut best_cost = search.descend(&mut best, initial_cost);
+        let mut stale_rounds = 0;
+        let mut rounds = 0;
+
+        while stale_rounds < self.config.max_stale_rounds && !search.timed_out() {
+            let mut current = best.clone();
+            let current_cost = match search.perturb(&mut current) {
+                Some(cost) => search.descend(&mut current, cost),
+                None => f64::INFINITY,
+            };
+            rounds += 1;
+
+            if current_cost < best_cost - EPSILON_ALLOWANCE {
+                best = current;
+                best_cost = current_cost;
+                stale_rounds = 0;
+            } else {
+                stale_rounds += 1;
+            }
+        }
+
+        log::info!(
+            "Local search ran {} rounds, greedy cost {}, best cost {}",
+            rounds,
+            initial_cost,
+            best_cost
+        );
+
+        if best_cost < initial_cost - EPSILON_ALLOWANCE {
+            best.status = ExtractionStatus::LocalSearch;
+            best
+        } else {
+            initial_result
+        }
+    }
+}
+
+// The allowed nodes of each class that has more than one.
+fn alternatives(egraph: &EGraph, constraints: &NodeConstraints) -> IndexMap<ClassId, Vec<NodeId>> {
+    egraph
+        .classes()
+        .values()
+        .map(|class| {
+            let nodes: Vec<NodeId> = class
+                .nodes
+                .iter()
+                .filter(|node| constraints.allows(node))
+                .cloned()
+                .collect();
+            (class.id.clone(), nodes)
+        })
+        .filter(|(_, nodes)| nodes.len() > 1)
+        .collect()
+}
+
+struct Search<'a> {
+    egraph: &'a EGraph,
+    roots: &'a [ClassId],
+    constraints: &'a NodeConstraints,
+    alternatives: IndexMap<ClassId, Vec<NodeId>>,
+    rng: SplitMix64,
+    deadline: Instant,
+}
+
+impl Search<'_> {
+    fn timed_out(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    // The DAG cost, if `result` is a valid extraction that meets the count caps.
+    fn evaluate(&self, result: &ExtractionResult) -> Option<f64> {
+        let cost = result.dag_cost(self.egraph, self.roots).ok()?;
+        if !self.constraints.caps.is_empty()
+            && !self.constraints.satisfied_by(result, self.egraph, self.roots)
+        {
+            return None;
+        }
+        Some(cost.into_inner())
+    }
+
+    // The classes of the DAG that have a choice to make, in a random order.
+    fn shuffled_classes(&mut self, result: &ExtractionResult) -> Vec<ClassId> {
+        let mut classes: Vec<ClassId> = result
+            .dag_nodes(self.egraph, self.roots)
+            .unwrap_or_default()
+            .iter()
+            .map(|node| self.egraph.nid_to_cid(node).clone())
+            .filter(|class| self.alternatives.contains_key(class))
+            .collect();
+        for i in (1..classes.len()).rev() {
+            classes.swap(i, self.rng.below(i + 1));
+        }
+        classes
+    }
+
+    // Switches classes to their best node until nothing improves, returns the final cost.
+    fn descend(&mut self, result: &mut ExtractionResult, mut cost: f64) -> f64 {
+        loop {
+            let mut improved = false;
+            for class in self.shuffled_classes(result) {
+                if self.timed_out() {
+                    return cost;
+                }
+                let current = result.choices[&class].clone();
+                let mut best = (current.clone(), cost);
+                for node in &self.alternatives[&class] {
+                    if *node == current {
+                        continue;
+                    }
+                    result.choose(class.clone(), node.clone());
+                    if let Some(node_cost) = self.evaluate(result) {
+                        if node_cost < best.1 - EPSILON_ALLOWANCE {
+                            best = (node.clone(), node_cost);
+                        }
+                    }
+                }
+                improved |= best.0 != current;
+                cost = best.1;
+                result.choose(class, best.0);
+            }
+            if !improved {
+                return cost;
+            }
+        }
+    }
+
+    // Switches a random class of the DAG and a few classes below it to random nodes.
+    // None if that doesn't give a valid extraction.
+    fn perturb(&mut self, result: &mut ExtractionResult) -> Option<f64> {
+        let classes = self.shuffled_classes(result);
+        let mut class = classes.first()?.clone();
+        for _ in 0..=PERTURBATION_DEPTH {
+            let nodes = &self.alternatives[&class];
+            let node = nodes[self.rng.below(nodes.len())].clone();
+            let children: Vec<ClassId> = self.egraph[&node]
+                .children
+                .iter()
+                .map(|child| self.egraph.nid_to_cid(child).clone())
+                .filter(|child| self.alternatives.contains_key(child))
+                .collect();
+            result.choose(class, node);
+            if children.is_empty() {
+                break;
+            }
+            class = children[self.rng.below(children.len())].clone();
+        }
+        self.evaluate(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extractor_structures::add_test_node;
+
+    #[test]
+    fn test_improves_on_greedy() {
+        // x is cheapest on its own as a leaf, but (g s) shares s with y.
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "s.0", "s.0", &[], 5.0);
+        add_test_node(&mut egraph, "x.0", "x.0", &[], 5.0);
+        add_test_node(&mut egraph, "x.1", "x.1", &["s"], 1.0);
+        add_test_node(&mut egraph, "y.0", "y.0", &["s"], 1.0);
+        add_test_node(&mut egraph, "r.0", "r.0", &["x", "y"], 1.0);
+        let roots = vec![ClassId::from("r".to_string())];
+
+        let greedy = FasterGreedyDagExtractor::default().extract(&egraph, &roots);
+        assert_eq!(greedy.dag_cost(&egraph, &roots).unwrap().into_inner(), 12.0);
+
+        // Switching x to (g s) is a descent step, so no perturbation is needed.
+        for max_stale_rounds in [0, 200] {
+            let result = LocalSearchDagExtractor {
+                timeout_in_seconds: 10,
+                config: Config { max_stale_rounds, ..Config::default() },
+                constraints: NodeConstraints::default(),
+            }
+            .extract(&egraph, &roots);
+            assert_eq!(result.dag_cost(&egraph, &roots).unwrap().into_inner(), 8.0);
+            assert_eq!(result.status, ExtractionStatus::LocalSearch);
+        }
+    }
+}
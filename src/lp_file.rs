@@ -0,0 +1,442 @@
+/*
+Writes the ILP extraction model as an LP or MPS file, and reads solutions back, so that the
+problem can be handed to other MILP solvers and attached to bug reports.
+
+`coin_cbc::Model` doesn't let us read back what was put into it, so `RecordingModel` wraps it
+and keeps a copy of every column, row, bound and objective coefficient in a `LinearProgram`.
+Columns can be given names (the ILP extractor names them after classes and nodes), everything
+else is numbered.
+
+Solutions are read as "name value" pairs, which covers the solution files of CBC
+(`index name value reduced_cost`), Gurobi and SCIP.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use coin_cbc::{Col, Model, Row, Solution};
+use rustc_hash::FxHashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LpFormat {
+    /// CPLEX LP format.
+    Lp,
+    /// Free MPS format.
+    Mps,
+}
+
+impl LpFormat {
+    /// From the file extension, `.lp` or `.mps`.
+    pub fn of_path(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("lp") => Ok(LpFormat::Lp),
+            Some("mps") => Ok(LpFormat::Mps),
+            _ => Err(format!(
+                "Expected a .lp or .mps model file, got: {}",
+                path.display()
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LpCol {
+    name: String,
+    lower: f64,
+    upper: f64,
+    integer: bool,
+    objective: f64,
+}
+
+#[derive(Debug, Clone)]
+struct LpRow {
+    lower: f64,
+    upper: f64,
+    weights: BTreeMap<usize, f64>,
+}
+
+/// A minimisation problem, as given to CBC.
+#[derive(Debug, Clone, Default)]
+pub struct LinearProgram {
+    cols: Vec<LpCol>,
+    rows: Vec<LpRow>,
+}
+
+impl LinearProgram {
+    fn add_col(&mut self, lower: f64, upper: f64, integer: bool) -> usize {
+        self.cols.push(LpCol {
+            name: format!("x{}", self.cols.len()),
+            lower,
+            upper,
+            integer,
+            objective: 0.0,
+        });
+        self.cols.len() - 1
+    }
+
+    fn add_row(&mut self) -> usize {
+        self.rows.push(LpRow {
+            lower: f64::NEG_INFINITY,
+            upper: f64::INFINITY,
+            weights: BTreeMap::new(),
+        });
+        self.rows.len() - 1
+    }
+
+    // Rows that constrain something, with their names.
+    fn named_rows(&self) -> impl Iterator<Item = (String, &LpRow)> {
+        self.rows
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| {
+                !row.weights.is_empty() && (row.lower.is_finite() || row.upper.is_finite())
+            })
+            .map(|(i, row)| (format!("r{}", i), row))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let text = match LpFormat::of_path(path)? {
+            LpFormat::Lp => self.to_lp(),
+            LpFormat::Mps => self.to_mps(),
+        };
+        fs::write(path, text).map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+
+    pub fn to_lp(&self) -> String {
+        let mut out = String::new();
+        let term = |out: &mut String, weight: f64, col: &LpCol| {
+            let sign = if weight < 0.0 { '-' } else { '+' };
+            write!(out, " {} {} {}", sign, weight.abs(), col.name).unwrap();
+        };
+
+        out.push_str("Minimize\n obj:");
+        let mut objective_terms = 0;
+        for col in self.cols.iter().filter(|col| col.objective != 0.0) {
+            term(&mut out, col.objective, col);
+            objective_terms += 1;
+        }
+        if objective_terms == 0 {
+            if let Some(col) = self.cols.first() {
+                write!(out, " 0 {}", col.name).unwrap();
+            }
+        }
+
+        out.push_str("\nSubject To\n");
+        for (name, row) in self.named_rows() {
+            let mut lhs = String::new();
+            for (&col, &weight) in &row.weights {
+                term(&mut lhs, weight, &self.cols[col]);
+            }
+            if row.lower == row.upper {
+                writeln!(out, " {}:{} = {}", name, lhs, row.upper).unwrap();
+                continue;
+            }
+            // A ranged row becomes two rows.
+            if row.lower.is_finite() && row.upper.is_finite() {
+                writeln!(out, " {}_lo:{} >= {}", name, lhs, row.lower).unwrap();
+                writeln!(out, " {}_up:{} <= {}", name, lhs, row.upper).unwrap();
+            } else if row.upper.is_finite() {
+                writeln!(out, " {}:{} <= {}", name, lhs, row.upper).unwrap();
+            } else {
+                writeln!(out, " {}:{} >= {}", name, lhs, row.lower).unwrap();
+            }
+        }
+
+        out.push_str("Bounds\n");
+        for col in &self.cols {
+            if col.lower == col.upper {
+                writeln!(out, " {} = {}", col.name, col.lower).unwrap();
+            } else {
+                writeln!(
+                    out,
+                    " {} <= {} <= {}",
+                    lp_bound(col.lower),
+                    col.name,
+                    lp_bound(col.upper)
+                )
+                .unwrap();
+            }
+        }
+
+        // Binaries are written as bounded integers, so tightened bounds are kept.
+        let integers: Vec<&str> = self
+            .cols
+            .iter()
+            .filter(|col| col.integer)
+            .map(|col| col.name.as_str())
+            .collect();
+        if !integers.is_empty() {
+            out.push_str("Generals\n");
+            for chunk in integers.chunks(8) {
+                writeln!(out, " {}", chunk.join(" ")).unwrap();
+            }
+        }
+        out.push_str("End\n");
+        out
+    }
+
+    pub fn to_mps(&self) -> String {
+        let mut out = String::from("NAME egraph_extraction\nROWS\n N obj\n");
+        let rows: Vec<(String, &LpRow)> = self.named_rows().collect();
+        for (name, row) in &rows {
+            let kind = if row.lower == row.upper {
+                'E'
+            } else if row.lower.is_finite() {
+                'G'
+            } else {
+                'L'
+            };
+            writeln!(out, " {} {}", kind, name).unwrap();
+        }
+
+        let mut entries: Vec<Vec<(&str, f64)>> = vec![vec![]; self.cols.len()];
+        for (name, row) in &rows {
+            for (&col, &weight) in &row.weights {
+                entries[col].push((name.as_str(), weight));
+            }
+        }
+
+        out.push_str("COLUMNS\n");
+        let mut in_integers = false;
+        for (col, entries) in self.cols.iter().zip(&entries) {
+            if col.integer != in_integers {
+                let marker = if col.integer { "INTORG" } else { "INTEND" };
+                writeln!(out, " MARKER 'MARKER' '{}'", marker).unwrap();
+                in_integers = col.integer;
+            }
+            // Every column is listed, even the ones without coefficients.
+            if col.objective != 0.0 || entries.is_empty() {
+                writeln!(out, " {} obj {}", col.name, col.objective).unwrap();
+            }
+            for (row, weight) in entries {
+                writeln!(out, " {} {} {}", col.name, row, weight).unwrap();
+            }
+        }
+        if in_integers {
+            out.push_str(" MARKER 'MARKER' 'INTEND'\n");
+        }
+
+        out.push_str("RHS\n");
+        for (name, row) in &rows {
+            let rhs = if row.lower.is_finite() { row.lower } else { row.upper };
+            if rhs != 0.0 {
+                writeln!(out, " RHS {} {}", name, rhs).unwrap();
+            }
+        }
+
+        out.push_str("RANGES\n");
+        for (name, row) in &rows {
+            if row.lower.is_finite() && row.upper.is_finite() && row.lower != row.upper {
+                writeln!(out, " RNG {} {}", name, row.upper - row.lower).unwrap();
+            }
+        }
+
+        out.push_str("BOUNDS\n");
+        for col in &self.cols {
+            if col.lower == col.upper {
+                writeln!(out, " FX BND {} {}", col.name, col.lower).unwrap();
+                continue;
+            }
+            if col.lower.is_finite() {
+                writeln!(out, " LO BND {} {}", col.name, col.lower).unwrap();
+            } else {
+                writeln!(out, " MI BND {}", col.name).unwrap();
+            }
+            if col.upper.is_finite() {
+                writeln!(out, " UP BND {} {}", col.name, col.upper).unwrap();
+            } else {
+                writeln!(out, " PL BND {}", col.name).unwrap();
+            }
+        }
+        out.push_str("ENDATA\n");
+        out
+    }
+}
+
+fn lp_bound(bound: f64) -> String {
+    if bound == f64::INFINITY {
+        "+inf".to_string()
+    } else if bound == f64::NEG_INFINITY {
+        "-inf".to_string()
+    } else {
+        bound.to_string()
+    }
+}
+
+/// A `coin_cbc::Model` that also records what is put into it, see `LinearProgram`.
+pub struct RecordingModel {
+    model: Model,
+    program: LinearProgram,
+    cols: FxHashMap<Col, usize>,
+    rows: FxHashMap<Row, usize>,
+}
+
+impl Default for RecordingModel {
+    fn default() -> Self {
+        RecordingModel {
+            model: Model::default(),
+            program: LinearProgram::default(),
+            cols: FxHashMap::default(),
+            rows: FxHashMap::default(),
+        }
+    }
+}
+
+impl RecordingModel {
+    pub fn program(&self) -> &LinearProgram {
+        &self.program
+    }
+
+    pub fn set_col_name(&mut self, col: Col, name: String) {
+        self.program.cols[self.cols[&col]].name = name;
+    }
+
+    pub fn add_binary(&mut self) -> Col {
+        let col = self.model.add_binary();
+        self.cols.insert(col, self.program.add_col(0.0, 1.0, true));
+        col
+    }
+
+    pub fn add_integer(&mut self) -> Col {
+        let col = self.model.add_integer();
+        self.cols
+            .insert(col, self.program.add_col(0.0, f64::INFINITY, true));
+        col
+    }
+
+    pub fn add_col(&mut self) -> Col {
+        let col = self.model.add_col();
+        self.cols
+            .insert(col, self.program.add_col(0.0, f64::INFINITY, false));
+        col
+    }
+
+    pub fn add_row(&mut self) -> Row {
+        let row = self.model.add_row();
+        self.rows.insert(row, self.program.add_row());
+        row
+    }
+
+    pub fn set_weight(&mut self, row: Row, col: Col, weight: f64) {
+        self.model.set_weight(row, col, weight);
+        self.program.rows[self.rows[&row]]
+            .weights
+            .insert(self.cols[&col], weight);
+    }
+
+    pub fn set_row_upper(&mut self, row: Row, upper: f64) {
+        self.model.set_row_upper(row, upper);
+        self.program.rows[self.rows[&row]].upper = upper;
+    }
+
+    pub fn set_row_lower(&mut self, row: Row, lower: f64) {
+        self.model.set_row_lower(row, lower);
+        self.program.rows[self.rows[&row]].lower = lower;
+    }
+
+    pub fn set_row_equal(&mut self, row: Row, value: f64) {
+        self.model.set_row_equal(row, value);
+        let row = &mut self.program.rows[self.rows[&row]];
+        row.lower = value;
+        row.upper = value;
+    }
+
+    pub fn set_col_upper(&mut self, col: Col, upper: f64) {
+        self.model.set_col_upper(col, upper);
+        self.program.cols[self.cols[&col]].upper = upper;
+    }
+
+    pub fn set_col_lower(&mut self, col: Col, lower: f64) {
+        self.model.set_col_lower(col, lower);
+        self.program.cols[self.cols[&col]].lower = lower;
+    }
+
+    pub fn set_obj_coeff(&mut self, col: Col, coeff: f64) {
+        self.model.set_obj_coeff(col, coeff);
+        self.program.cols[self.cols[&col]].objective = coeff;
+    }
+
+    pub fn set_col_initial_solution(&mut self, col: Col, value: f64) {
+        self.model.set_col_initial_solution(col, value);
+    }
+
+    pub fn set_parameter(&mut self, key: &str, value: &str) {
+        self.model.set_parameter(key, value);
+    }
+
+    pub fn solve(&mut self) -> Solution {
+        self.model.solve()
+    }
+}
+
+/// The column values of a solver's solution file, by name.
+pub fn parse_solution(text: &str) -> HashMap<String, f64> {
+    let mut values = HashMap::new();
+    for line in text.lines() {
+        // CBC marks the values that break a constraint with "**".
+        let tokens: Vec<&str> = line
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '*')
+            .split_whitespace()
+            .collect();
+        if tokens.first().is_none_or(|token| token.starts_with('#')) {
+            continue;
+        }
+        let (name, value) = match tokens.as_slice() {
+            [index, name, value, ..] if index.parse::<usize>().is_ok() => (name, value),
+            [name, value, ..] => (name, value),
+            _ => continue,
+        };
+        if let Ok(value) = value.parse::<f64>() {
+            values.insert(name.to_string(), value);
+        }
+    }
+    values
+}
+
+pub fn read_solution(path: &Path) -> Result<HashMap<String, f64>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e: io::Error| format!("Could not read {}: {}", path.display(), e))?;
+    Ok(parse_solution(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_back() {
+        // min 3 a + 2 b, a + b >= 1, a and b binary, with a fixed to 0.
+        let mut program = LinearProgram::default();
+        let a = program.add_col(0.0, 0.0, true);
+        let b = program.add_col(0.0, 1.0, true);
+        program.cols[a].objective = 3.0;
+        program.cols[b].objective = 2.0;
+        let row = program.add_row();
+        program.rows[row].lower = 1.0;
+        program.rows[row].weights.insert(a, 1.0);
+        program.rows[row].weights.insert(b, 1.0);
+        // A row without bounds is left out.
+        program.add_row();
+
+        let lp = program.to_lp();
+        assert!(lp.contains(" obj: + 3 x0 + 2 x1\n"));
+        assert!(lp.contains(" r0: + 1 x0 + 1 x1 >= 1\n"));
+        assert!(!lp.contains("r1"));
+        assert!(lp.contains(" x0 = 0\n"));
+        assert!(lp.contains(" 0 <= x1 <= 1\n"));
+
+        let mps = program.to_mps();
+        assert!(mps.contains(" G r0\n"));
+        assert!(mps.contains(" RHS r0 1\n"));
+        assert!(mps.contains(" FX BND x0 0\n"));
+
+        let cbc = "Optimal - objective value 2.00000000\n      1 x1  1  2\n";
+        assert_eq!(parse_solution(cbc).get("x1"), Some(&1.0));
+        let gurobi = "# Objective value = 2\nx0 0\nx1 1\n";
+        assert_eq!(parse_solution(gurobi).get("x0"), Some(&0.0));
+        assert!(!parse_solution(cbc).contains_key("objective"));
+    }
+}
@@ -1,44 +1,149 @@
 mod analysis;
+mod bnb_dag;
+mod budget;
+mod cli;
+mod config;
+mod constraints;
 mod cost;
+mod dot;
 mod extractor_structures;
 mod faster_greedy_dag;
+#[cfg(feature = "cbc")]
 mod faster_ilp_cbc;
 mod language;
+mod local_search_dag;
+#[cfg(feature = "cbc")]
+mod lp_file;
+#[cfg(feature = "cbc")]
+mod pareto_ilp_cbc;
+mod rng;
+mod rule_file;
 mod rules;
+mod soundness;
+mod test_case;
+mod tower;
 
 use std::collections::HashMap;
 use std::fs;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use egg::{Language, EGraph, Extractor, RecExpr, Runner};
-use egraph_serialize::ClassId;
+use egg::{Language, EGraph, Extractor, RecExpr, Rewrite, Runner, StopReason};
+use egraph_serialize::{ClassId, Cost, NodeId};
+use indexmap::IndexMap;
+use rustc_hash::FxHashSet;
 
 use analysis::{FieldType, TypeAnalysis};
-use cost::{MathCostFn, PairCostFn};
-use extractor_structures::Extractor as NewExtractor;
+use budget::TimeBudget;
+use cli::Options;
+use config::{ExtractorKind, RunConfig, StageConfig};
+use constraints::{resolve_levels, NodeConstraints, OpLabel};
+use cost::{CostModel, MathCostFn, PairCostFn};
+use extractor_structures::{
+    ExtractionResult, ExtractionStatus, Extractor as NewExtractor, ValidationError,
+};
 use language::Math;
-use rules::{rules, pair_rules};
+use rule_file::{all_rules, load_rules, select_rules, TaggedRule};
+use rules::{rules, extension_rules};
+use test_case::TestCase;
 
 fn main() {
     env_logger::init();
 
-    let mut symbol_types_file = "inputs/symbol_types.json";
-    let mut cost_model_file = "inputs/cost_model.json";
-    let mut test_case_file = "inputs/tests.txt";
-
     // Read command line arguments
     let args: Vec<String> = std::env::args().collect();
-    if args.len() == 1 {
-        ()
-    }
-    else if args.len() == 4 {
-        symbol_types_file = &args[1];
-        cost_model_file = &args[2];
-        test_case_file = &args[3];
-    } else {
-        eprintln!("Usage: {} <symbol_types.json> <cost_model.json> <tests.txt>", args[0]);
+    let options = Options::parse(&args).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        eprintln!("{}", Options::usage(&args[0]));
+        std::process::exit(1);
+    });
+    let symbol_types_file = options.symbol_types_file.as_str();
+    let cost_model_file = options.cost_model_file.as_str();
+    let test_case_file = options.test_case_file.as_str();
+
+    // --- load the run configuration (next to the cost model by default) ---
+    let mut run_config = RunConfig::load(options.run_config_file.as_deref(), cost_model_file)
+        .expect("Could not load run configuration");
+    for label in &options.forbid {
+        run_config.constraints.forbid(label.clone());
+    }
+    for (label, max) in &options.max_ops {
+        run_config.constraints.limit(label.clone(), *max);
+    }
+    if let Some(seed) = options.seed {
+        run_config.local_search.seed = seed;
+    }
+    if let Some(modulus) = &options.modulus {
+        run_config.modulus = Some(modulus.clone());
+    }
+    let modulus = run_config.modulus.clone().map(|p| p.0);
+    if modulus.as_ref().is_some_and(|p| !analysis::is_probable_prime(p)) {
+        eprintln!("The modulus must be a prime, got {}", modulus.unwrap());
+        std::process::exit(1);
+    }
+    if let Err(e) = run_config.tower.validate() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(path) = &options.rules_file {
+        run_config.rules_file = Some(path.clone());
+    }
+    if !options.rules.is_empty() {
+        run_config.rules = options.rules.clone();
+    }
+    if let Some(profile) = &options.profile {
+        run_config.profile = Some(profile.clone());
+    }
+    let extractor = options.extractor.unwrap_or(run_config.extractor);
+    let timeout = options.timeout.unwrap_or(run_config.timeout);
+    let budget_secs = options.budget.or(run_config.budget);
+    if !extractor.is_available() {
+        eprintln!("The ilp extractor needs the crate to be built with the `cbc` feature");
+        std::process::exit(1);
+    }
+    if (options.top_k.is_some() || options.pareto) && !cfg!(feature = "cbc") {
+        eprintln!("--top-k and --pareto need the crate to be built with the `cbc` feature");
+        std::process::exit(1);
+    }
+    if (options.export_model.is_some() || options.read_solution.is_some()) && !cfg!(feature = "cbc")
+    {
+        eprintln!(
+            "--export-model and --read-solution need the crate to be built with the `cbc` feature"
+        );
         std::process::exit(1);
     }
+    if options.export_model.is_some() && extractor != ExtractorKind::Ilp {
+        eprintln!("Only the ilp extractor builds a model to export, ignoring --export-model");
+    }
+    if let Some(path) = &options.load_egraph {
+        extract_saved_egraph(path, extractor, &run_config, &options, timeout, budget_secs);
+        return;
+    }
+    let lexicographic = CostModel::from_file(cost_model_file)
+        .expect("Could not load cost model")
+        .lexicographic;
+    let file_rules = match &run_config.rules_file {
+        Some(path) => load_rules(path),
+        None => Ok(vec![]),
+    };
+    let selected = file_rules.and_then(|file| {
+        let all = all_rules(rules(&run_config.tower), file)?;
+        select_rules(&all, &run_config.rules)
+    });
+    if options.check_rules {
+        check_rules_and_exit(&run_config, selected, modulus);
+    }
+    let stages = match selected.and_then(|selected| stage_rewrites(&run_config, &selected)) {
+        Ok(stages) => stages,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if !lexicographic.is_empty() && extractor != ExtractorKind::Ilp {
+        eprintln!("Lexicographic levels are only minimised by the ilp extractor, ignoring them");
+    }
 
     // --- load symbol types from JSON ---
     let sym_json =
@@ -49,6 +154,7 @@ fn main() {
     // --- read each test expression ---
     let reader = BufReader::new(fs::File::open(test_case_file).expect("Could not open tests.txt"));
 
+    let budget = TimeBudget::new(budget_secs.map(Duration::from_secs));
     let mut counter = 0;
     for line in reader.lines().filter_map(Result::ok) {
         let line = line.trim();
@@ -59,63 +165,69 @@ fn main() {
         counter += 1;
         println!("Optimizing_Test_Case {}: ", counter);
 
-        // parse into egg’s RecExpr
-        let orig_expr: RecExpr<Math> = line
-            .parse()
-            .unwrap_or_else(|_| panic!("Invalid expr: {}", line));
+        // parse into egg’s RecExprs, one per output of the test case
+        let test_case = match TestCase::parse(line) {
+            Ok(test_case) => test_case,
+            Err(e) => {
+                println!("{}, skipping test case", e);
+                continue;
+            }
+        };
 
-        let analysis = TypeAnalysis::new(symbol_map.clone());
+        let analysis = TypeAnalysis::new(symbol_map.clone())
+            .with_modulus(modulus.clone())
+            .with_tower(&run_config.tower);
 
-        let simplifier: Runner<Math, TypeAnalysis> = Runner::new(analysis.clone()) 
-            .with_expr(&orig_expr)
-            .run(&pair_rules());
-        let pair_costfn = PairCostFn;
-        let simplifier_extractor = Extractor::new(&simplifier.egraph, pair_costfn);
-        let (_expr_cost, expr) =
-            simplifier_extractor.find_best(simplifier.egraph.find(simplifier.roots[0]));
-        // println!("{}", _expr_cost);
+        let (exprs, mut cut_short) = flatten(&test_case.exprs, &analysis, &run_config, &budget);
 
         // 1. compute initial cost (no rewrites)
         let unopt_runner: Runner<Math, TypeAnalysis> =
-            Runner::new(analysis.clone()).with_expr(&expr).run(&[]);
+            with_exprs(Runner::new(analysis.clone()), &exprs).run(&[]);
 
         let unopt_tree_costfn =
             MathCostFn::from_file(&unopt_runner.egraph, cost_model_file).unwrap();
         let unopt_tree_extractor =
             Extractor::new(&unopt_runner.egraph, unopt_tree_costfn);
-        let (unopt_tree_cost, _) = unopt_tree_extractor
-            .find_best(unopt_runner.egraph.find(unopt_runner.roots[0]));
+        let unopt_tree_cost: f64 = unopt_runner
+            .roots
+            .iter()
+            .map(|&root| unopt_tree_extractor.find_best(unopt_runner.egraph.find(root)).0)
+            .sum();
 
         let unopt_dag_costfn =
             MathCostFn::from_file(&unopt_runner.egraph, cost_model_file).unwrap();
         let mut unopt_dag_serialized =
             egg_to_serialized_egraph(&unopt_runner.egraph, unopt_dag_costfn);
-        unopt_dag_serialized
-            .root_eclasses
-            .push(ClassId::from(format!(
-                "{}",
-                unopt_runner
-                    .egraph
-                    .find(unopt_runner.roots[0])
-            )));
-        let unopt_dag_extractor = faster_ilp_cbc::FasterCbcExtractorWithTimeout::<180>;
-        let unopt_dag_result = unopt_dag_extractor.extract(
+        unopt_dag_serialized.root_eclasses = serialized_roots(&unopt_runner);
+        let unopt_labels =
+            node_labels(&unopt_runner.egraph, cost_model_file, &run_config, &lexicographic);
+        let unopt_constraints = NodeConstraints::new(&run_config.constraints, &unopt_labels);
+        let unopt_dag_result = extract_dag(
             &unopt_dag_serialized,
-            &unopt_dag_serialized.root_eclasses,
-        );
-        unopt_dag_result.check(&unopt_dag_serialized);
-        let unopt_dag_cost = unopt_dag_result.dag_cost(
-            &unopt_dag_serialized,
-            &unopt_dag_serialized.root_eclasses,
-        );
+            extractor,
+            &run_config,
+            &unopt_constraints,
+            &resolve_levels(&lexicographic, &unopt_labels),
+            None,
+            timeout,
+            &budget,
+            cut_short,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        let unopt_dag_cost = match validated_dag_cost(&unopt_dag_result, &unopt_dag_serialized) {
+            Ok(cost) => cost,
+            Err(e) => {
+                println!("Invalid initial DAG extraction, skipping test case: {}", e);
+                continue;
+            }
+        };
 
         // 2. compute optimized cost (with rewrites)
-        let runner: Runner<Math, TypeAnalysis> =
-            Runner::new(analysis.clone())
-            .with_expr(&expr)
-            //.with_iter_limit(100)
-            //.with_node_limit(100_000)
-            .run(&rules());
+        let (runner, saturation_cut) = saturate(&stages, &analysis, &exprs, &budget);
+        cut_short |= saturation_cut;
         // for its in &runner.iterations {
         //     println!("{:?}", its.applied);
         // }
@@ -128,44 +240,502 @@ fn main() {
         let tree_costfn =
             MathCostFn::from_file(&runner.egraph, cost_model_file).unwrap();
         let tree_extractor = Extractor::new(&runner.egraph, tree_costfn);
-        let (best_tree_cost, best_tree_expr) = tree_extractor
-            .find_best(runner.egraph.find(runner.roots[0]));
+        let (best_tree_costs, best_tree_exprs): (Vec<f64>, Vec<RecExpr<Math>>) = runner
+            .roots
+            .iter()
+            .map(|&root| tree_extractor.find_best(runner.egraph.find(root)))
+            .unzip();
+        let best_tree_cost: f64 = best_tree_costs.iter().sum();
 
         let dag_costfn =
             MathCostFn::from_file(&runner.egraph, cost_model_file).unwrap();
         let mut dag_serialized =
             egg_to_serialized_egraph(&runner.egraph, dag_costfn);
-        dag_serialized
-            .root_eclasses
-            .push(ClassId::from(format!(
-                "{}",
-                runner.egraph.find(runner.roots[0])
-            )));
-        let dag_extractor = faster_ilp_cbc::FasterCbcExtractorWithTimeout::<180>;
-        let dag_result =
-            dag_extractor.extract(&dag_serialized, &dag_serialized.root_eclasses);
-        dag_result.check(&dag_serialized);
-        let best_dag_cost =
-            dag_result.dag_cost(&dag_serialized, &dag_serialized.root_eclasses);
-        let best_dag_expr = dag_result.dag_extracted_exprs(
-            &dag_serialized,
-            &dag_serialized.root_eclasses,
-        )[0]
-        .clone();
+        dag_serialized.root_eclasses = serialized_roots(&runner);
+        if let Some(path) = &options.dump_egraph {
+            let path = numbered_path(path, counter);
+            if let Err(e) = dag_serialized.to_json_file(&path) {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        let labels = node_labels(&runner.egraph, cost_model_file, &run_config, &lexicographic);
+        let constraints = NodeConstraints::new(&run_config.constraints, &labels);
+        let levels = resolve_levels(&lexicographic, &labels);
+        // A solution of the exported model from another solver replaces the extraction.
+        #[cfg(feature = "cbc")]
+        let solution = options.read_solution.as_deref().map(|path| {
+            faster_ilp_cbc::read_solution_file(
+                &dag_serialized,
+                &dag_serialized.root_eclasses,
+                &run_config.extraction,
+                &constraints,
+                &numbered_path(path, counter),
+            )
+        });
+        #[cfg(not(feature = "cbc"))]
+        let solution: Option<Result<ExtractionResult, String>> = None;
+        let dag_result = match solution {
+            Some(Ok(result)) => result,
+            Some(Err(e)) => {
+                println!("{}, skipping test case", e);
+                continue;
+            }
+            None => match extract_dag(
+                &dag_serialized,
+                extractor,
+                &run_config,
+                &constraints,
+                &levels,
+                options.export_model.as_deref().map(|path| numbered_path(path, counter)),
+                timeout,
+                &budget,
+                cut_short,
+            ) {
+                Ok(result) => result,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            },
+        };
+        let best_dag_cost = match validated_dag_cost(&dag_result, &dag_serialized) {
+            Ok(cost) => cost,
+            Err(e) => {
+                println!("Invalid optimized DAG extraction, skipping test case: {}", e);
+                continue;
+            }
+        };
+        if let Some(path) = &options.dot {
+            let path = numbered_path(path, counter);
+            let dot = dot::egraph_to_dot(
+                &runner.egraph,
+                MathCostFn::from_file(&runner.egraph, cost_model_file).unwrap(),
+                &dag_result,
+                &dag_serialized.root_eclasses,
+                options.dot_reachable,
+            );
+            if let Err(e) = fs::write(&path, dot) {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        let best_dag_program = match best_dag_cost {
+            Some(_) => dag_result.dag_program(&dag_serialized, &dag_serialized.root_eclasses),
+            None => vec![],
+        };
 
         println!(">>>");
+        if let Some(name) = &test_case.name {
+            println!("Group                : {}",   name);
+        }
         println!("Input expr           : {}",   line);
-        println!("Simplified expr      : {}\n", expr);
+        println!("Simplified expr      : {}\n", join_exprs(&exprs));
         println!("Tree: Initial cost   : {}",   unopt_tree_cost);
-        println!("Tree: Optimized expr : {}",   best_tree_expr);
+        println!("Tree: Optimized expr : {}",   join_exprs(&best_tree_exprs));
         println!("Tree: Optimized cost : {}\n", best_tree_cost);
-        println!("DAG:  Initial cost   : {}",   unopt_dag_cost);
-        println!("DAG:  Optimized expr : {}",   best_dag_expr);
-        println!("DAG:  Optimized cost : {}",   best_dag_cost);
+        println!("DAG:  Initial cost   : {}",   display_cost(unopt_dag_cost));
+        println!("DAG:  Initial via    : {}",   unopt_dag_result.status);
+        println!("DAG:  Optimized prog :");
+        for statement in &best_dag_program {
+            println!("  {}", statement);
+        }
+        if !levels.is_empty() && best_dag_cost.is_some() {
+            println!(
+                "DAG:  Optimized ops  : {}",
+                level_counts(&dag_result, &dag_serialized, &lexicographic, &levels)
+            );
+        }
+        println!("DAG:  Optimized cost : {}",   display_cost(best_dag_cost));
+        println!("DAG:  Optimized via  : {}",   dag_result.status);
+        #[cfg(feature = "cbc")]
+        if let Some(k) = options.top_k {
+            let top_k = faster_ilp_cbc::extract_top_k(
+                &dag_serialized,
+                &dag_serialized.root_eclasses,
+                &run_config.extraction,
+                &constraints,
+                budget.clamp_secs(timeout),
+                k,
+            );
+            println!("DAG:  Top {} programs :", k);
+            for (i, result) in top_k.iter().enumerate() {
+                match validated_dag_cost(result, &dag_serialized) {
+                    Ok(cost) => println!(
+                        "  #{} cost {}: {}",
+                        i + 1,
+                        display_cost(cost),
+                        result
+                            .dag_program(&dag_serialized, &dag_serialized.root_eclasses)
+                            .join("; ")
+                    ),
+                    Err(e) => println!("  #{} invalid: {}", i + 1, e),
+                }
+            }
+        }
+        #[cfg(feature = "cbc")]
+        if options.pareto {
+            let latencies = serialized_node_latencies(
+                &runner.egraph,
+                MathCostFn::from_file(&runner.egraph, cost_model_file).unwrap(),
+            );
+            let frontier = pareto_ilp_cbc::pareto_frontier(
+                &dag_serialized,
+                &dag_serialized.root_eclasses,
+                &latencies,
+                &constraints,
+                budget.clamp_secs(timeout),
+            );
+            if frontier.complete {
+                println!("DAG:  Pareto frontier (cost, latency) :");
+            } else {
+                println!("DAG:  Pareto frontier (cost, latency), incomplete after timeout :");
+            }
+            for point in &frontier.points {
+                println!(
+                    "  cost {}, latency {}: {}",
+                    point.cost,
+                    point.latency,
+                    point
+                        .result
+                        .dag_program(&dag_serialized, &dag_serialized.root_eclasses)
+                        .join("; ")
+                );
+            }
+        }
+        if budget.is_exhausted() {
+            println!("Time budget exhausted: results are the best found so far, not optimal");
+        }
         println!("<<<");
     }
 }
 
+/// The `--load-egraph` mode: runs the DAG extractor on a saved egraph, e.g.
+/// one written with `--dump-egraph`, without parsing or saturating anything.
+/// The operations of the nodes aren't known, so there are no constraints.
+fn extract_saved_egraph(
+    path: &str,
+    extractor: ExtractorKind,
+    run_config: &RunConfig,
+    options: &Options,
+    timeout: u32,
+    budget_secs: Option<u64>,
+) {
+    if !run_config.constraints.is_empty() {
+        eprintln!("Operation constraints need the test case, ignoring them for a saved egraph");
+    }
+    let serialized = match egraph_serialize::EGraph::from_json_file(path) {
+        Ok(serialized) => serialized,
+        Err(e) => {
+            eprintln!("Could not load {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let budget = TimeBudget::new(budget_secs.map(Duration::from_secs));
+    let result = extract_dag(
+        &serialized,
+        extractor,
+        run_config,
+        &NodeConstraints::default(),
+        &[],
+        options.export_model.as_deref().map(PathBuf::from),
+        timeout,
+        &budget,
+        false,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    println!(">>>");
+    println!("Egraph               : {}", path);
+    println!(
+        "Size                 : {} classes, {} nodes",
+        serialized.classes().len(),
+        serialized.nodes.len()
+    );
+    match validated_dag_cost(&result, &serialized) {
+        Ok(cost) => {
+            println!("DAG:  Optimized prog :");
+            for statement in result.dag_program(&serialized, &serialized.root_eclasses) {
+                println!("  {}", statement);
+            }
+            println!("DAG:  Optimized cost : {}", display_cost(cost));
+            println!("DAG:  Optimized via  : {}", result.status);
+        }
+        Err(e) => println!("Invalid DAG extraction: {}", e),
+    }
+    println!("<<<");
+}
+
+/// Adds every expression of a test case to the runner, as its roots.
+fn with_exprs(
+    runner: Runner<Math, TypeAnalysis>,
+    exprs: &[RecExpr<Math>],
+) -> Runner<Math, TypeAnalysis> {
+    exprs.iter().fold(runner, |runner, expr| runner.with_expr(expr))
+}
+
+/// The rewrites of each stage of the run configuration's schedule, picked
+/// from the `selected` rules.
+fn stage_rewrites(
+    run_config: &RunConfig,
+    selected: &[TaggedRule],
+) -> Result<Vec<(StageConfig, Vec<Rewrite<Math, TypeAnalysis>>)>, String> {
+    run_config
+        .schedule()?
+        .into_iter()
+        .map(|stage| {
+            let rewrites = select_rules(selected, &stage.rules)
+                .map_err(|e| format!("Stage {}: {}", stage.name, e))?
+                .into_iter()
+                .map(|rule| rule.rewrite)
+                .collect();
+            Ok((stage, rewrites))
+        })
+        .collect()
+}
+
+/// Flatten the pairs and triples of `exprs` into base-field arithmetic with
+/// the tower's extension rules, keeping the cheapest flattening of each.
+/// Also returns whether the budget cut the flattening short.
+fn flatten(
+    exprs: &[RecExpr<Math>],
+    analysis: &TypeAnalysis,
+    run_config: &RunConfig,
+    budget: &TimeBudget,
+) -> (Vec<RecExpr<Math>>, bool) {
+    let simplifier: Runner<Math, TypeAnalysis> = with_exprs(
+        run_config.runner.apply(Runner::new(analysis.clone()), budget),
+        exprs,
+    )
+    .run(&extension_rules(&run_config.tower));
+    let cut_short = stopped_by_budget(&simplifier, budget);
+    let pair_costfn = PairCostFn;
+    let simplifier_extractor = Extractor::new(&simplifier.egraph, pair_costfn);
+    let exprs = simplifier
+        .roots
+        .iter()
+        .map(|&root| simplifier_extractor.find_best(simplifier.egraph.find(root)).1)
+        .collect();
+    (exprs, cut_short)
+}
+
+/// Saturate `exprs` with each stage's rules and runner in turn, on the same
+/// egraph. Returns the last stage's runner, and whether the budget cut a stage short.
+fn saturate(
+    stages: &[(StageConfig, Vec<Rewrite<Math, TypeAnalysis>>)],
+    analysis: &TypeAnalysis,
+    exprs: &[RecExpr<Math>],
+    budget: &TimeBudget,
+) -> (Runner<Math, TypeAnalysis>, bool) {
+    let mut runner = with_exprs(Runner::new(analysis.clone()), exprs);
+    let mut cut_short = false;
+    for (i, (stage, rewrites)) in stages.iter().enumerate() {
+        if i > 0 {
+            let roots = runner.roots;
+            runner = Runner::new(analysis.clone()).with_egraph(runner.egraph);
+            runner.roots = roots;
+        }
+        runner = stage.runner.apply(runner, budget).run(rewrites);
+        log::info!(
+            "Stage {} stopped after {} iterations with {} nodes: {:?}",
+            stage.name,
+            runner.iterations.len(),
+            runner.egraph.total_number_of_nodes(),
+            runner.stop_reason
+        );
+        cut_short |= stopped_by_budget(&runner, budget);
+    }
+    (runner, cut_short)
+}
+
+/// Whether `runner` hit its time limit because the budget ran out.
+fn stopped_by_budget(runner: &Runner<Math, TypeAnalysis>, budget: &TimeBudget) -> bool {
+    matches!(runner.stop_reason, Some(StopReason::TimeLimit(_))) && budget.is_exhausted()
+}
+
+/// The runner's roots as classes of `egg_to_serialized_egraph`'s output.
+fn serialized_roots(runner: &Runner<Math, TypeAnalysis>) -> Vec<ClassId> {
+    runner
+        .roots
+        .iter()
+        .map(|&root| ClassId::from(format!("{}", runner.egraph.find(root))))
+        .collect()
+}
+
+/// `model.lp` becomes `model_3.lp` for the third test case.
+fn numbered_path(path: &str, counter: usize) -> PathBuf {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, counter, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, counter),
+    };
+    path.with_file_name(name)
+}
+
+fn join_exprs(exprs: &[RecExpr<Math>]) -> String {
+    exprs
+        .iter()
+        .map(|expr| expr.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// The DAG cost of `result`, once it is known to be a valid extraction of
+/// the egraph's roots. None if nothing was extracted, e.g. no extraction
+/// meets the operation constraints.
+fn validated_dag_cost(
+    result: &ExtractionResult,
+    serialized: &egraph_serialize::EGraph,
+) -> Result<Option<Cost>, ValidationError> {
+    if !result.status.has_extraction() {
+        return Ok(None);
+    }
+    result.check(serialized)?;
+    result.dag_cost(serialized, &serialized.root_eclasses).map(Some)
+}
+
+fn display_cost(cost: Option<Cost>) -> String {
+    match cost {
+        Some(cost) => cost.to_string(),
+        None => "none".to_string(),
+    }
+}
+
+/// The operation of each node of `egg_to_serialized_egraph`'s output, or
+/// nothing if there are no operation constraints or levels to resolve.
+fn node_labels(
+    egraph: &EGraph<Math, TypeAnalysis>,
+    cost_model_file: &str,
+    run_config: &RunConfig,
+    lexicographic: &[Vec<String>],
+) -> IndexMap<NodeId, OpLabel> {
+    if run_config.constraints.is_empty() && lexicographic.is_empty() {
+        return IndexMap::new();
+    }
+    serialized_node_labels(
+        egraph,
+        MathCostFn::from_file(egraph, cost_model_file).unwrap(),
+    )
+}
+
+/// How many operations of each lexicographic level the extraction uses,
+/// e.g. "3 */sq + 0 *const + 5 +/-".
+fn level_counts(
+    result: &ExtractionResult,
+    serialized: &egraph_serialize::EGraph,
+    lexicographic: &[Vec<String>],
+    levels: &[FxHashSet<NodeId>],
+) -> String {
+    let nodes = result
+        .dag_nodes(serialized, &serialized.root_eclasses)
+        .unwrap_or_default();
+    lexicographic
+        .iter()
+        .zip(levels)
+        .map(|(ops, level)| {
+            let count = nodes.iter().filter(|n| level.contains(n)).count();
+            format!("{} {}", count, ops.join("/"))
+        })
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+/// Runs the chosen extractor within what is left of the budget, or just the
+/// greedy extractor once less than a second of it is left, since the exact
+/// extractors' timeouts are in whole seconds. The result is marked timed out
+/// if the budget cut it short, or the egraph (`cut_short`), unless it is a
+/// proven optimum of a complete egraph. Fails if the model can't be exported.
+fn extract_dag(
+    serialized: &egraph_serialize::EGraph,
+    kind: ExtractorKind,
+    run_config: &RunConfig,
+    constraints: &NodeConstraints,
+    levels: &[FxHashSet<NodeId>],
+    export: Option<PathBuf>,
+    timeout: u32,
+    budget: &TimeBudget,
+    cut_short: bool,
+) -> Result<ExtractionResult, String> {
+    let exhausted = budget.is_under_a_second();
+    let kind = if exhausted { ExtractorKind::Greedy } else { kind };
+    let mut result =
+        dag_extractor(kind, run_config, constraints, levels, export, budget.clamp_secs(timeout))
+            .try_extract(serialized, &serialized.root_eclasses)?;
+    let cut_short = cut_short || exhausted || (budget.is_exhausted() && !result.status.is_optimal());
+    if cut_short && result.status.has_extraction() {
+        result.status = ExtractionStatus::TimedOut;
+    }
+    Ok(result)
+}
+
+#[cfg_attr(not(feature = "cbc"), allow(unused_variables))]
+fn dag_extractor(
+    kind: ExtractorKind,
+    run_config: &RunConfig,
+    constraints: &NodeConstraints,
+    levels: &[FxHashSet<NodeId>],
+    export: Option<PathBuf>,
+    timeout: u32,
+) -> Box<dyn NewExtractor> {
+    let constraints = constraints.clone();
+    match kind {
+        ExtractorKind::Greedy => {
+            faster_greedy_dag::FasterGreedyDagExtractor { constraints }.boxed()
+        }
+        #[cfg(feature = "cbc")]
+        ExtractorKind::Ilp => faster_ilp_cbc::FasterCbcExtractorWithTimeout {
+            timeout_in_seconds: timeout,
+            config: run_config.extraction.clone(),
+            constraints,
+            levels: levels.to_vec(),
+            export,
+        }
+        .boxed(),
+        #[cfg(not(feature = "cbc"))]
+        ExtractorKind::Ilp => unreachable!("checked by ExtractorKind::is_available"),
+        ExtractorKind::Bnb => bnb_dag::BranchAndBoundDagExtractor {
+            timeout_in_seconds: timeout,
+            constraints,
+        }
+        .boxed(),
+        ExtractorKind::Local => local_search_dag::LocalSearchDagExtractor {
+            timeout_in_seconds: timeout,
+            config: run_config.local_search.clone(),
+            constraints,
+        }
+        .boxed(),
+    }
+}
+
+/// `--check-rules`: try the selected rules, and those of the tower's
+/// extensions, on random instances, and exit with whether they all held.
+fn check_rules_and_exit(
+    run_config: &RunConfig,
+    selected: Result<Vec<TaggedRule>, String>,
+    modulus: Option<num_bigint::BigInt>,
+) -> ! {
+    let mut rewrites: Vec<Rewrite<Math, TypeAnalysis>> = match selected {
+        Ok(selected) => selected.into_iter().map(|rule| rule.rewrite).collect(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    rewrites.extend(extension_rules(&run_config.tower));
+    match soundness::check_rules(&rewrites, &run_config.tower, modulus, &run_config.soundness) {
+        Ok(true) => std::process::exit(0),
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 pub fn egg_to_serialized_egraph(
     egraph: &EGraph<Math, TypeAnalysis>,
     mut costfn: MathCostFn,
@@ -193,3 +763,314 @@ pub fn egg_to_serialized_egraph(
     }
     out
 }
+
+/// The latency of each node of `egg_to_serialized_egraph`'s output, by node id.
+#[cfg_attr(not(feature = "cbc"), allow(dead_code))]
+pub fn serialized_node_latencies(
+    egraph: &EGraph<Math, TypeAnalysis>,
+    mut costfn: MathCostFn,
+) -> IndexMap<NodeId, f64>
+{
+    let mut out = IndexMap::new();
+    for class in egraph.classes() {
+        for (i, node) in class.nodes.iter().enumerate() {
+            out.insert(
+                NodeId::from(format!("{}.{}", class.id, i)),
+                costfn.calc_enode_latency(node),
+            );
+        }
+    }
+    out
+}
+
+/// The operation of each node of `egg_to_serialized_egraph`'s output, by node id.
+pub fn serialized_node_labels(
+    egraph: &EGraph<Math, TypeAnalysis>,
+    costfn: MathCostFn,
+) -> IndexMap<NodeId, OpLabel>
+{
+    let mut out = IndexMap::new();
+    for class in egraph.classes() {
+        for (i, node) in class.nodes.iter().enumerate() {
+            out.insert(
+                NodeId::from(format!("{}.{}", class.id, i)),
+                costfn.enode_label(node),
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tower::Tower;
+
+    const COST_MODEL: &str = "inputs/cost_model.json";
+
+    fn symbols(types: &[(&str, &str)]) -> HashMap<String, FieldType> {
+        types
+            .iter()
+            .map(|(name, ty)| (name.to_string(), FieldType::from_str(ty).unwrap()))
+            .collect()
+    }
+
+    fn dag_cost(serialized: &egraph_serialize::EGraph) -> f64 {
+        let result = extract_dag(
+            serialized,
+            ExtractorKind::Bnb,
+            &RunConfig::default(),
+            &NodeConstraints::default(),
+            &[],
+            None,
+            60,
+            &TimeBudget::new(None),
+            false,
+        )
+        .unwrap();
+        validated_dag_cost(&result, serialized).unwrap().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_dumped_egraph_extracts_the_same() {
+        let analysis = TypeAnalysis::new(symbols(&[("a", "fp2"), ("b", "fp2")]));
+        let exprs: Vec<RecExpr<Math>> = vec!["(- (sq (+ a b)) (* a b))".parse().unwrap()];
+        let rewrites: Vec<Rewrite<Math, TypeAnalysis>> =
+            rules(&Tower::default()).into_iter().map(|rule| rule.rewrite).collect();
+        let runner = with_exprs(Runner::new(analysis), &exprs)
+            .with_iter_limit(5)
+            .run(&rewrites);
+        let costfn = MathCostFn::from_file(&runner.egraph, COST_MODEL).unwrap();
+        let mut serialized = egg_to_serialized_egraph(&runner.egraph, costfn);
+        serialized.root_eclasses = serialized_roots(&runner);
+
+        let path = std::env::temp_dir().join(format!("egraph_{}.json", std::process::id()));
+        serialized.to_json_file(&path).unwrap();
+        let loaded = egraph_serialize::EGraph::from_json_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.root_eclasses, serialized.root_eclasses);
+        assert_eq!(loaded.nodes.len(), serialized.nodes.len());
+        assert!((dag_cost(&loaded) - dag_cost(&serialized)).abs() < 1e-9);
+    }
+
+    // Saturates (* a (+ a b)) with the stages of `stages`, a JSON list.
+    fn staged(stages: &str) -> Runner<Math, TypeAnalysis> {
+        let run_config: RunConfig =
+            serde_json::from_str(&format!(r#"{{ "stages": {} }}"#, stages)).unwrap();
+        let all = rules(&Tower::default());
+        let stages = stage_rewrites(&run_config, &all).unwrap();
+        let analysis = TypeAnalysis::new(symbols(&[("a", "fp"), ("b", "fp")]));
+        let exprs: Vec<RecExpr<Math>> = vec!["(* a (+ a b))".parse().unwrap()];
+        let (runner, cut_short) = saturate(&stages, &analysis, &exprs, &TimeBudget::new(None));
+        assert!(!cut_short);
+        runner
+    }
+
+    #[test]
+    fn test_stages_run_in_order() {
+        // dist makes (* a a), which only a later sq stage turns into (sq a).
+        let expanded_square: RecExpr<Math> = "(+ (sq a) (* a b))".parse().unwrap();
+        let runner = staged(
+            r#"[{ "name": "expand", "rules": ["dist"] }, { "name": "square", "rules": ["sq"] }]"#,
+        );
+        assert_eq!(runner.roots.len(), 1);
+        let root = runner.egraph.find(runner.roots[0]);
+        assert_eq!(runner.egraph.lookup_expr(&expanded_square), Some(root));
+
+        let runner = staged(
+            r#"[{ "name": "square", "rules": ["sq"] }, { "name": "expand", "rules": ["dist"] }]"#,
+        );
+        assert_eq!(runner.roots.len(), 1);
+        assert_eq!(runner.egraph.lookup_expr(&expanded_square), None);
+    }
+}
+
+// Checks of the extractors against each other on the saturated benchmarks.
+#[cfg(all(test, feature = "cbc"))]
+mod benchmarks {
+    use super::*;
+
+    const COST_MODEL: &str = "inputs/cost_model.json";
+
+    /// A benchmark of `inputs/benchmarks.txt`.
+    struct Benchmark {
+        name: String,
+        symbols: HashMap<String, FieldType>,
+        profile: Option<String>,
+        /// "use -f option": wrapper.py runs it with the symbols split into pairs too.
+        full_search: bool,
+        test_case: String,
+    }
+
+    // Benchmarks are blocks separated by blank lines: a [title], the [Symbol Types],
+    // maybe a [Note] and a [Profile], and the test case on the last line.
+    fn benchmarks() -> Vec<Benchmark> {
+        let text = fs::read_to_string("inputs/benchmarks.txt").unwrap();
+        let text = text.split("// Other Tests").next().unwrap();
+        let base: HashMap<String, FieldType> =
+            serde_json::from_str(&fs::read_to_string("inputs/symbol_types.json").unwrap())
+                .unwrap();
+        text.split("\n\n")
+            .filter_map(|block| {
+                let block = block.trim().trim_start_matches("// Benchmarks").trim();
+                let test_case = block.lines().last()?.trim();
+                if !test_case.starts_with('(') {
+                    return None;
+                }
+                // `a0, a1: fp2, b0: fp` gives each name the type that follows it.
+                let mut symbols = base.clone();
+                let types = block.lines().find_map(|l| l.strip_prefix("[Symbol Types]"));
+                let mut names = vec![];
+                for item in types.unwrap_or_default().split(',') {
+                    match item.split_once(':') {
+                        Some((name, ty)) => {
+                            names.push(name.trim());
+                            let ty = FieldType::from_str(ty.trim()).unwrap();
+                            for name in names.drain(..) {
+                                symbols.insert(name.to_string(), ty.clone());
+                            }
+                        }
+                        None => names.push(item.trim()),
+                    }
+                }
+                let profile = block.lines().find_map(|l| {
+                    let name = l.strip_prefix("[Profile:")?.strip_suffix(']')?;
+                    Some(name.trim().to_string())
+                });
+                Some(Benchmark {
+                    name: block.lines().next()?.trim().to_string(),
+                    symbols,
+                    profile,
+                    full_search: block.contains("use -f option"),
+                    test_case: test_case.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    // What wrapper.py's `-f` runs as well: each extension field symbol of the test
+    // case split into a pair over the field of half its degree.
+    fn split_into_pairs(test_case: &str, symbols: &mut HashMap<String, FieldType>) -> String {
+        let spaced = test_case.replace('(', " ( ").replace(')', " ) ");
+        let tokens: Vec<String> = spaced
+            .split_whitespace()
+            .map(|token| {
+                let degree = symbols.get(token).map_or(0, |ty| ty.degree());
+                if degree < 2 {
+                    return token.to_string();
+                }
+                let half = match degree / 2 {
+                    1 => FieldType::Fp,
+                    n => FieldType::FpExt(n),
+                };
+                let (c0, c1) = (format!("t{}", token), format!("tu{}", token));
+                symbols.insert(c0.clone(), half.clone());
+                symbols.insert(c1.clone(), half);
+                format!("(pair {} {})", c0, c1)
+            })
+            .collect();
+        tokens.join(" ").replace("( ", "(").replace(" )", ")")
+    }
+
+    // Saturates a test case as `main` does, with all the rules and the run
+    // configuration's schedule.
+    fn saturated(
+        run_config: &RunConfig,
+        symbols: HashMap<String, FieldType>,
+        test_case: &str,
+    ) -> egraph_serialize::EGraph {
+        let budget = TimeBudget::new(None);
+        let analysis = TypeAnalysis::new(symbols).with_tower(&run_config.tower);
+        let all = all_rules(rules(&run_config.tower), vec![]).unwrap();
+        let selected = select_rules(&all, &run_config.rules).unwrap();
+        let stages = stage_rewrites(run_config, &selected).unwrap();
+        let test_case = TestCase::parse(test_case).unwrap();
+        let (exprs, _) = flatten(&test_case.exprs, &analysis, run_config, &budget);
+        let (runner, _) = saturate(&stages, &analysis, &exprs, &budget);
+        let costfn = MathCostFn::from_file(&runner.egraph, COST_MODEL).unwrap();
+        let mut serialized = egg_to_serialized_egraph(&runner.egraph, costfn);
+        serialized.root_eclasses = serialized_roots(&runner);
+        serialized
+    }
+
+    // Extracts each case of `benchmark` with ilp and bnb, and checks they agree
+    // where both proved their extraction optimal. Returns how many cases were
+    // compared, and how many there are.
+    fn compare_bnb_with_ilp(benchmark: &Benchmark, base: &RunConfig) -> (usize, usize) {
+        let run_config = RunConfig {
+            profile: benchmark.profile.clone(),
+            ..base.clone()
+        };
+        let mut cases = vec![(benchmark.test_case.clone(), benchmark.symbols.clone())];
+        if benchmark.full_search {
+            let mut symbols = benchmark.symbols.clone();
+            let split = split_into_pairs(&benchmark.test_case, &mut symbols);
+            cases.push((split, symbols));
+        }
+
+        let mut compared = 0;
+        for (test_case, symbols) in &cases {
+            let serialized = saturated(&run_config, symbols.clone(), test_case);
+            let extract = |kind| {
+                dag_extractor(kind, &run_config, &NodeConstraints::default(), &[], None, 180)
+                    .extract(&serialized, &serialized.root_eclasses)
+            };
+            let ilp = extract(ExtractorKind::Ilp);
+            let bnb = extract(ExtractorKind::Bnb);
+            let cost = |result: &ExtractionResult| {
+                validated_dag_cost(result, &serialized).unwrap().unwrap().into_inner()
+            };
+            println!(
+                "{} {}: ilp {} ({}), bnb {} ({})",
+                benchmark.name,
+                test_case,
+                cost(&ilp),
+                ilp.status,
+                cost(&bnb),
+                bnb.status
+            );
+            // Nothing to compare unless both proved their extraction optimal.
+            if ilp.status.is_optimal() && bnb.status.is_optimal() {
+                assert!(
+                    (cost(&ilp) - cost(&bnb)).abs() < 1e-6,
+                    "{}: {}",
+                    benchmark.name,
+                    test_case
+                );
+                compared += 1;
+            }
+        }
+        (compared, cases.len())
+    }
+
+    // The Fp2 additions and scalings saturate and extract well within the
+    // timeout, so both extractors must prove the same optimum.
+    #[test]
+    fn test_bnb_matches_ilp_on_small_benchmarks() {
+        let base = RunConfig::from_file("inputs/run_config.json").unwrap();
+        let small = ["[Algorithm 5]", "[Algorithm 6]", "[Algorithm 7]"];
+        let benchmarks: Vec<Benchmark> = benchmarks()
+            .into_iter()
+            .filter(|benchmark| small.iter().any(|name| benchmark.name.starts_with(name)))
+            .collect();
+        assert_eq!(benchmarks.len(), small.len());
+        for benchmark in &benchmarks {
+            let (compared, cases) = compare_bnb_with_ilp(benchmark, &base);
+            assert_eq!(compared, cases, "{}", benchmark.name);
+        }
+    }
+
+    // Every extraction may run into the timeout, so this one is run on demand:
+    // cargo test --release -- --ignored --nocapture test_bnb_matches_ilp_on_benchmarks
+    #[test]
+    #[ignore]
+    fn test_bnb_matches_ilp_on_benchmarks() {
+        let base = RunConfig::from_file("inputs/run_config.json").unwrap();
+        let compared: usize = benchmarks()
+            .iter()
+            .map(|benchmark| compare_bnb_with_ilp(benchmark, &base).0)
+            .sum();
+        assert!(compared > 0);
+    }
+}
@@ -0,0 +1,349 @@
+/*
+Finds the Pareto frontier of (DAG cost, critical-path latency) extractions of an Egraph.
+
+It uses the epsilon-constraint method on an ILP solved by COIN-OR CBC:
+1) Minimise the DAG cost, with the critical path bounded below the latency of the previous
+point.
+2) Holding the cost at that minimum, minimise the critical path. The result can't be beaten on
+both cost and latency, so it's on the frontier.
+3) Tighten the latency bound just below the new point and repeat, until nothing faster exists.
+
+Each point is more expensive and faster than the one before, so the frontier comes out sorted
+by cost.
+
+The critical path is encoded with a finish time per class. A selected node with latency l can't
+finish before l, nor before l after any of its children's classes:
+    t[class] >= l * node_active
+    t[class] >= t[child] + l - M * (1 - node_active)
+and the critical path is at least the finish time of every root.
+
+The model isn't simplified like in faster_ilp_cbc, that pruning only keeps the cheapest
+extractions and would remove faster but more expensive ones. For the same reason cycles
+are ruled out up front with a topological order on classes (as with
+`Acyclicity::TopologicalOrder`), rather than lazily; zero latency nodes could otherwise
+form cycles.
+*/
+
+use std::time::{Duration, Instant};
+
+use coin_cbc::{Col, Model, Solution};
+use egraph_serialize::*;
+use indexmap::{IndexMap, IndexSet};
+
+use crate::constraints::NodeConstraints;
+use crate::extractor_structures::*;
+
+pub struct ParetoPoint {
+    pub result: ExtractionResult,
+    pub cost: Cost,
+    pub latency: f64,
+}
+
+pub struct ParetoFrontier {
+    /// Sorted by increasing cost (and so decreasing latency).
+    pub points: Vec<ParetoPoint>,
+    /// False if a solve timed out, so faster points may be missing.
+    pub complete: bool,
+}
+
+struct ClassVars {
+    active: Col,
+    finish: Col,
+    level: Col,
+    members: Vec<NodeId>,
+    variables: Vec<Col>,
+}
+
+pub fn pareto_frontier(
+    egraph: &EGraph,
+    roots: &[ClassId],
+    latencies: &IndexMap<NodeId, f64>,
+    constraints: &NodeConstraints,
+    timeout: u32,
+) -> ParetoFrontier {
+    let deadline = Instant::now().checked_add(Duration::from_secs(timeout.into()));
+    let n2c = |nid: &NodeId| egraph.nid_to_cid(nid);
+    let latency = |nid: &NodeId| latencies.get(nid).copied().unwrap_or(0.0);
+
+    let mut reachable: IndexSet<ClassId> = IndexSet::new();
+    let mut todo: Vec<ClassId> = roots.to_vec();
+    while let Some(cid) = todo.pop() {
+        if reachable.insert(cid.clone()) {
+            for node in &egraph.classes()[&cid].nodes {
+                todo.extend(egraph[node].children.iter().map(|c| n2c(c).clone()));
+            }
+        }
+    }
+
+    // No path can be slower than going through every class with its slowest node.
+    let horizon: f64 = reachable
+        .iter()
+        .map(|cid| {
+            egraph.classes()[cid]
+                .nodes
+                .iter()
+                .map(latency)
+                .fold(0.0, f64::max)
+        })
+        .sum();
+    let n = reachable.len() as f64;
+
+    let mut model = Model::default();
+    //silence verbose stdout output
+    model.set_parameter("loglevel", "0");
+
+    let vars: IndexMap<ClassId, ClassVars> = reachable
+        .iter()
+        .map(|cid| {
+            let class = &egraph.classes()[cid];
+            let finish = model.add_col();
+            model.set_col_lower(finish, 0.0);
+            model.set_col_upper(finish, horizon);
+            let level = model.add_integer();
+            model.set_col_lower(level, 0.0);
+            model.set_col_upper(level, n - 1.0);
+            let cvars = ClassVars {
+                active: model.add_binary(),
+                finish,
+                level,
+                members: class.nodes.clone(),
+                variables: class.nodes.iter().map(|_| model.add_binary()).collect(),
+            };
+            (cid.clone(), cvars)
+        })
+        .collect();
+
+    let critical_path = model.add_col();
+    model.set_col_lower(critical_path, 0.0);
+    model.set_col_upper(critical_path, horizon);
+
+    // Holds the cost down while the latency is minimised, unbounded otherwise.
+    let cost_row = model.add_row();
+
+    for (class_id, class) in &vars {
+        // class active == some node active
+        let row = model.add_row();
+        model.set_row_equal(row, 0.0);
+        model.set_weight(row, class.active, -1.0);
+        for &node_active in &class.variables {
+            model.set_weight(row, node_active, 1.0);
+        }
+
+        for (node_id, &node_active) in class.members.iter().zip(&class.variables) {
+            let l = latency(node_id);
+            model.set_weight(cost_row, node_active, egraph[node_id].cost.into_inner());
+            if !constraints.allows(node_id) {
+                model.set_col_upper(node_active, 0.0);
+            }
+
+            // t[class] - l * node_active >= 0
+            let row = model.add_row();
+            model.set_row_lower(row, 0.0);
+            model.set_weight(row, class.finish, 1.0);
+            model.set_weight(row, node_active, -l);
+
+            let children: IndexSet<ClassId> = egraph[node_id]
+                .children
+                .iter()
+                .map(|c| n2c(c).clone())
+                .collect();
+            for child in &children {
+                if child == class_id {
+                    model.set_col_upper(node_active, 0.0);
+                    continue;
+                }
+                let child_vars = &vars[child];
+
+                //   node_active - child_active <= 0
+                let row = model.add_row();
+                model.set_row_upper(row, 0.0);
+                model.set_weight(row, node_active, 1.0);
+                model.set_weight(row, child_vars.active, -1.0);
+
+                // With M = horizon + l:
+                //   t[class] - t[child] - M * node_active >= -horizon
+                let row = model.add_row();
+                model.set_row_lower(row, -horizon);
+                model.set_weight(row, class.finish, 1.0);
+                model.set_weight(row, child_vars.finish, -1.0);
+                model.set_weight(row, node_active, -(horizon + l));
+
+                //   level[class] - level[child] - n * node_active >= 1 - n
+                let row = model.add_row();
+                model.set_row_lower(row, 1.0 - n);
+                model.set_weight(row, class.level, 1.0);
+                model.set_weight(row, child_vars.level, -1.0);
+                model.set_weight(row, node_active, -n);
+            }
+        }
+    }
+
+    for root in roots {
+        model.set_col_lower(vars[root].active, 1.0);
+
+        //   critical_path - t[root] >= 0
+        let row = model.add_row();
+        model.set_row_lower(row, 0.0);
+        model.set_weight(row, critical_path, 1.0);
+        model.set_weight(row, vars[root].finish, -1.0);
+    }
+
+    for cap in &constraints.caps {
+        //   sum(node_active for node in cap) <= max
+        let row = model.add_row();
+        model.set_row_upper(row, cap.max as f64);
+        for class in vars.values() {
+            for (node_id, &node_active) in class.members.iter().zip(&class.variables) {
+                if cap.nodes.contains(node_id) {
+                    model.set_weight(row, node_active, 1.0);
+                }
+            }
+        }
+    }
+
+    let set_objective = |model: &mut Model, minimise_cost: bool| {
+        for class in vars.values() {
+            for (node_id, &node_active) in class.members.iter().zip(&class.variables) {
+                let cost = if minimise_cost {
+                    egraph[node_id].cost.into_inner()
+                } else {
+                    0.0
+                };
+                model.set_obj_coeff(node_active, cost);
+            }
+        }
+        model.set_obj_coeff(critical_path, if minimise_cost { 0.0 } else { 1.0 });
+    };
+
+    let mut frontier = ParetoFrontier {
+        points: vec![],
+        complete: true,
+    };
+
+    loop {
+        // 1) The cheapest extraction within the latency bound.
+        set_objective(&mut model, true);
+        model.set_row_upper(cost_row, f64::INFINITY);
+        let Some(solution) = solve(&mut model, deadline, &mut frontier.complete) else {
+            break;
+        };
+        let cheapest = solution.raw().obj_value();
+
+        // 2) The fastest extraction at that cost.
+        set_objective(&mut model, false);
+        model.set_row_upper(cost_row, cheapest + EPSILON_ALLOWANCE);
+        let Some(solution) = solve(&mut model, deadline, &mut frontier.complete) else {
+            break;
+        };
+
+        let mut result = ExtractionResult::default();
+        for (class_id, class) in &vars {
+            if solution.col(class.active) > 0.5 {
+                let idx = class
+                    .variables
+                    .iter()
+                    .position(|&v| solution.col(v) > 0.5)
+                    .unwrap();
+                result.choose(class_id.clone(), class.members[idx].clone());
+            }
+        }
+        result.status = ExtractionStatus::Optimal;
+
+        // The topological order rules out cycles, so this only fails on a solver bug.
+        let cost = match result.dag_cost(egraph, roots) {
+            Ok(cost) => cost,
+            Err(e) => {
+                log::info!("Invalid Pareto point: {}", e);
+                frontier.complete = false;
+                break;
+            }
+        };
+        let latency = result.critical_path(egraph, roots, latencies);
+        log::info!("Pareto point: cost {}, latency {}", cost, latency);
+        frontier.points.push(ParetoPoint {
+            result,
+            cost,
+            latency,
+        });
+
+        // 3) Only look for faster extractions from now on.
+        if latency <= EPSILON_ALLOWANCE {
+            break;
+        }
+        model.set_col_upper(critical_path, latency - EPSILON_ALLOWANCE);
+    }
+
+    frontier
+}
+
+// Returns the solution if CBC proved it optimal. None if the problem is infeasible, or
+// if the solver timed out (or there is no time left to run it), which also marks the
+// frontier incomplete.
+fn solve(model: &mut Model, deadline: Option<Instant>, complete: &mut bool) -> Option<Solution> {
+    if let Some(deadline) = deadline {
+        let seconds = deadline.saturating_duration_since(Instant::now()).as_secs();
+        if seconds == 0 {
+            log::info!("No time left to solve, the frontier may be incomplete");
+            *complete = false;
+            return None;
+        }
+        model.set_parameter("seconds", &seconds.to_string());
+    }
+
+    let solution = model.solve();
+    log::info!(
+        "CBC status {:?}, {:?}, obj = {}",
+        solution.raw().status(),
+        solution.raw().secondary_status(),
+        solution.raw().obj_value(),
+    );
+
+    if solution.raw().is_proven_infeasible() {
+        return None;
+    }
+    if solution.raw().status() != coin_cbc::raw::Status::Finished
+        || !solution.raw().is_proven_optimal()
+    {
+        log::info!("CBC stopped before finishing, the frontier may be incomplete");
+        *complete = false;
+        return None;
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extractor_structures::add_test_node;
+
+    #[test]
+    fn test_cheap_slow_and_costly_fast() {
+        let mut egraph = EGraph::default();
+        add_test_node(&mut egraph, "a.0", "a.0", &[], 1.0);
+        add_test_node(&mut egraph, "r.0", "r.0", &["a"], 1.0);
+        add_test_node(&mut egraph, "r.1", "r.1", &["a"], 5.0);
+        let roots = vec![ClassId::from("r".to_string())];
+        let latencies: IndexMap<NodeId, f64> = [("a.0", 1.0), ("r.0", 10.0), ("r.1", 2.0)]
+            .into_iter()
+            .map(|(node, latency)| (NodeId::from(node.to_string()), latency))
+            .collect();
+
+        let frontier =
+            pareto_frontier(&egraph, &roots, &latencies, &NodeConstraints::default(), 60);
+        assert!(frontier.complete);
+        let points: Vec<(f64, f64)> = frontier
+            .points
+            .iter()
+            .map(|point| (point.cost.into_inner(), point.latency))
+            .collect();
+        assert_eq!(points, vec![(2.0, 11.0), (6.0, 3.0)]);
+        let root_choice = |i: usize| frontier.points[i].result.choices[&roots[0]].to_string();
+        assert_eq!(root_choice(0), "r.0");
+        assert_eq!(root_choice(1), "r.1");
+
+        // Without time left, nothing is solved.
+        let frontier = pareto_frontier(&egraph, &roots, &latencies, &NodeConstraints::default(), 0);
+        assert!(!frontier.complete);
+        assert!(frontier.points.is_empty());
+    }
+}
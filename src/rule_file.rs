@@ -0,0 +1,276 @@
+use std::fs;
+use std::str::FromStr;
+
+use egg::{ConditionalApplier, EGraph, Id, Pattern, Rewrite, Subst, Var};
+use serde::Deserialize;
+
+use crate::analysis::{FieldType, TypeAnalysis};
+use crate::language::{Constant, Math};
+
+/// Tag of the compiled-in rules, to select them next to a file's.
+pub const BUILTIN_TAG: &str = "builtin";
+
+/// A rewrite rule written in a rules file.
+///
+/// JSON format example:
+///
+/// [
+///   { "name": "diff-of-squares", "lhs": "(- (sq ?a) (sq ?b))", "rhs": "(* (+ ?a ?b) (- ?a ?b))",
+///     "bidirectional": true, "tags": ["squares"], "if": ["is_not_same ?a ?b"] },
+///   { "name": "neg-one", "lhs": "(* ?c ?a)", "rhs": "(- 0 ?a)", "if": ["is_constant ?c -1"] }
+/// ]
+///
+/// The conditions are those of the built-in rules, `is_not_same ?a ?b` and
+/// `is_same_field ?a ?b`, plus `is_field ?a fp2` and `is_constant ?a`, which
+/// can also require a value (compared modulo the characteristic if there is one).
+/// All of them must hold. A bidirectional rule also gets `<name>-rev`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleSpec {
+    pub name: String,
+    pub lhs: String,
+    pub rhs: String,
+    #[serde(default)]
+    pub bidirectional: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, rename = "if")]
+    pub conditions: Vec<String>,
+}
+
+/// A rule with the tags it can be selected by.
+#[derive(Clone)]
+pub struct TaggedRule {
+    pub rewrite: Rewrite<Math, TypeAnalysis>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleCondition {
+    NotSame(Var, Var),
+    SameField(Var, Var),
+    Field(Var, FieldType),
+    Constant(Var, Option<Constant>),
+}
+
+impl FromStr for RuleCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let var = |i: usize| -> Result<Var, String> {
+            let word = words.get(i).ok_or_else(|| format!("Missing variable in: {}", s))?;
+            word.parse()
+                .map_err(|_| format!("Invalid variable {} in: {}", word, s))
+        };
+        let condition = match words.first().copied() {
+            Some("is_not_same") if words.len() == 3 => RuleCondition::NotSame(var(1)?, var(2)?),
+            Some("is_same_field") if words.len() == 3 => RuleCondition::SameField(var(1)?, var(2)?),
+            Some("is_field") if words.len() == 3 => {
+                RuleCondition::Field(var(1)?, FieldType::from_str(words[2])?)
+            }
+            Some("is_constant") if words.len() == 2 => RuleCondition::Constant(var(1)?, None),
+            Some("is_constant") if words.len() == 3 => {
+                RuleCondition::Constant(var(1)?, Some(words[2].parse()?))
+            }
+            _ => return Err(format!("Unknown condition: {}", s)),
+        };
+        Ok(condition)
+    }
+}
+
+impl RuleCondition {
+    fn vars(&self) -> Vec<Var> {
+        match self {
+            RuleCondition::NotSame(a, b) | RuleCondition::SameField(a, b) => vec![*a, *b],
+            RuleCondition::Field(a, _) | RuleCondition::Constant(a, _) => vec![*a],
+        }
+    }
+
+    fn check(&self, egraph: &EGraph<Math, TypeAnalysis>, subst: &Subst) -> bool {
+        let class = |var: &Var| egraph.find(subst[*var]);
+        match self {
+            RuleCondition::NotSame(a, b) => class(a) != class(b),
+            RuleCondition::SameField(a, b) => egraph[class(a)].data.ty == egraph[class(b)].data.ty,
+            RuleCondition::Field(a, field) => egraph[class(a)].data.ty == *field,
+            RuleCondition::Constant(a, value) => match (&egraph[class(a)].data.constant, value) {
+                (Some(c), Some(value)) => c.0 == egraph.analysis.reduce(value.0.clone()),
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+        }
+    }
+}
+
+impl RuleSpec {
+    /// The rewrite, and its reverse if the rule is bidirectional.
+    pub fn to_rewrites(&self) -> Result<Vec<Rewrite<Math, TypeAnalysis>>, String> {
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|c| c.parse())
+            .collect::<Result<Vec<RuleCondition>, String>>()
+            .map_err(|e| format!("Rule {}: {}", self.name, e))?;
+        let lhs: Pattern<Math> = parse_pattern(&self.name, &self.lhs)?;
+        let rhs: Pattern<Math> = parse_pattern(&self.name, &self.rhs)?;
+
+        let mut rewrites = vec![rewrite(&self.name, lhs.clone(), rhs.clone(), &conditions)?];
+        if self.bidirectional {
+            let name = format!("{}-rev", self.name);
+            rewrites.push(rewrite(&name, rhs, lhs, &conditions)?);
+        }
+        Ok(rewrites)
+    }
+}
+
+fn parse_pattern(name: &str, pattern: &str) -> Result<Pattern<Math>, String> {
+    pattern
+        .parse()
+        .map_err(|e| format!("Rule {}: invalid pattern {}: {}", name, pattern, e))
+}
+
+fn rewrite(
+    name: &str,
+    lhs: Pattern<Math>,
+    rhs: Pattern<Math>,
+    conditions: &[RuleCondition],
+) -> Result<Rewrite<Math, TypeAnalysis>, String> {
+    // A condition on a variable the searcher doesn't bind would panic while matching.
+    let bound = lhs.vars();
+    for condition in conditions {
+        if let Some(var) = condition.vars().into_iter().find(|v| !bound.contains(v)) {
+            return Err(format!("Rule {}: {} is not bound by {}", name, var, lhs));
+        }
+    }
+    let conditions = conditions.to_vec();
+    let applier = ConditionalApplier {
+        condition: move |egraph: &mut EGraph<Math, TypeAnalysis>, _: Id, subst: &Subst| {
+            conditions.iter().all(|c| c.check(egraph, subst))
+        },
+        applier: rhs,
+    };
+    Rewrite::new(name, lhs, applier)
+}
+
+/// Read the rules of a rules file.
+pub fn load_rules(path: &str) -> Result<Vec<TaggedRule>, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let specs: Vec<RuleSpec> =
+        serde_json::from_str(&data).map_err(|e| format!("Invalid rules file {}: {}", path, e))?;
+    let mut rules = vec![];
+    for spec in specs {
+        for rewrite in spec.to_rewrites()? {
+            rules.push(TaggedRule {
+                rewrite,
+                tags: spec.tags.clone(),
+            });
+        }
+    }
+    Ok(rules)
+}
+
+/// The built-in rules, tagged `builtin` too, and a file's. Their names must be unique.
+pub fn all_rules(builtin: Vec<TaggedRule>, file: Vec<TaggedRule>) -> Result<Vec<TaggedRule>, String> {
+    let all: Vec<TaggedRule> = builtin
+        .into_iter()
+        .map(|mut rule| {
+            rule.tags.push(BUILTIN_TAG.to_string());
+            rule
+        })
+        .chain(file)
+        .collect();
+
+    let mut names: Vec<&str> = all.iter().map(|rule| rule.rewrite.name.as_str()).collect();
+    names.sort_unstable();
+    if let Some(name) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(format!("Two rules are named {}", name[0]));
+    }
+    Ok(all)
+}
+
+/// The rules of `rules` with a name or tag in `selection`, except those with
+/// one of its `!name` or `!tag` entries. Without positive entries every rule
+/// is in, so an empty selection keeps them all. The reverse of a bidirectional
+/// rule goes with it when selected by name.
+pub fn select_rules(rules: &[TaggedRule], selection: &[String]) -> Result<Vec<TaggedRule>, String> {
+    let selects = |rule: &TaggedRule, key: &str| {
+        let name = rule.rewrite.name.as_str();
+        name == key || name.strip_suffix("-rev") == Some(key) || rule.tags.iter().any(|t| t == key)
+    };
+    let mut keys = selection.iter().map(|entry| entry.strip_prefix('!').unwrap_or(entry));
+    if let Some(key) = keys.find(|key| !rules.iter().any(|rule| selects(rule, key))) {
+        return Err(format!("No rule is named or tagged {}", key));
+    }
+    let (excluded, included): (Vec<&String>, Vec<&String>) =
+        selection.iter().partition(|entry| entry.starts_with('!'));
+    Ok(rules
+        .iter()
+        .filter(|rule| included.is_empty() || included.iter().any(|key| selects(rule, key)))
+        .filter(|rule| !excluded.iter().any(|key| selects(rule, &key[1..])))
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigInt;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_rules_file() {
+        let specs: Vec<RuleSpec> = serde_json::from_str(
+            r#"[
+                { "name": "diff-of-squares", "lhs": "(- (sq ?a) (sq ?b))", "rhs": "(* (+ ?a ?b) (- ?a ?b))",
+                  "bidirectional": true, "tags": ["squares"], "if": ["is_not_same ?a ?b"] },
+                { "name": "neg-one", "lhs": "(* ?c ?a)", "rhs": "(- 0 ?a)", "if": ["is_constant ?c -1"] }
+            ]"#,
+        )
+        .unwrap();
+        let file: Vec<TaggedRule> = specs
+            .iter()
+            .flat_map(|spec| {
+                let tags = spec.tags.clone();
+                spec.to_rewrites()
+                    .unwrap()
+                    .into_iter()
+                    .map(move |rewrite| TaggedRule { rewrite, tags: tags.clone() })
+            })
+            .collect();
+        assert_eq!(file.len(), 3);
+
+        let all = all_rules(vec![], file).unwrap();
+        let selection = ["squares".to_string(), "neg-one".to_string()];
+        let rules: Vec<Rewrite<Math, TypeAnalysis>> = select_rules(&all, &selection)
+            .unwrap()
+            .into_iter()
+            .map(|rule| rule.rewrite)
+            .collect();
+        let no_squares = select_rules(&all, &["!squares".to_string()]).unwrap();
+        assert_eq!(no_squares.len(), 1);
+        assert!(select_rules(&all, &["!cubes".to_string()]).is_err());
+        let analysis = TypeAnalysis::new(HashMap::new()).with_modulus(Some(BigInt::from(7)));
+        let runner = egg::Runner::default()
+            .with_egraph(EGraph::new(analysis))
+            .with_expr(&"(- (sq x) (sq y))".parse().unwrap())
+            .with_expr(&"(* 6 z)".parse().unwrap())
+            .with_iter_limit(3)
+            .run(&rules);
+        let egraph = &runner.egraph;
+        let same = |id: Option<Id>, root: Id| id.map(|id| egraph.find(id)) == Some(egraph.find(root));
+        assert!(same(egraph.lookup_expr(&"(* (+ x y) (- x y))".parse().unwrap()), runner.roots[0]));
+        // 6 is -1 modulo 7
+        assert!(same(egraph.lookup_expr(&"(- 0 z)".parse().unwrap()), runner.roots[1]));
+
+        assert!("is_field ?a fp5".parse::<RuleCondition>().is_err());
+        assert!("is_constant ?a 2 3".parse::<RuleCondition>().is_err());
+        let unbound = RuleSpec {
+            name: "unbound".to_string(),
+            lhs: "(sq ?a)".to_string(),
+            rhs: "(* ?a ?a)".to_string(),
+            bidirectional: false,
+            tags: vec![],
+            conditions: vec!["is_constant ?b".to_string()],
+        };
+        assert!(unbound.to_rewrites().is_err());
+    }
+}
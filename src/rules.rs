@@ -1,6 +1,8 @@
 use crate::analysis::{TypeAnalysis};
 use crate::language::Math;
-use egg::{Rewrite, rewrite as rw, EGraph, Id, Subst};
+use crate::rule_file::TaggedRule;
+use crate::tower::{Tower, TowerLevel};
+use egg::{Rewrite, rewrite as rw, ConditionalApplier, EGraph, Id, Pattern, Subst, Var};
 
 fn is_not_same(a: &str, b: &str) -> impl Fn(&mut EGraph<Math, TypeAnalysis>, Id, &Subst) -> bool {
     let a = a.parse().unwrap();
@@ -14,40 +16,57 @@ fn is_same_field(a: &str, b: &str) -> impl Fn(&mut EGraph<Math, TypeAnalysis>, I
     let a = a.parse().unwrap();
     let b = b.parse().unwrap();
     move |egraph, _, subst| {
-        let ta = &egraph[egraph.find(subst[a])].data;
-        let tb = &egraph[egraph.find(subst[b])].data;
+        let ta = &egraph[egraph.find(subst[a])].data.ty;
+        let tb = &egraph[egraph.find(subst[b])].data.ty;
         *ta == *tb
     }
 }
 
-// Rules are actually automatically bidirectional once triggered by lhs
-pub fn rules() -> Vec<Rewrite<Math, TypeAnalysis>> {
-    vec![
-        // commutativity
+fn tagged(tag: &str, rewrites: Vec<Rewrite<Math, TypeAnalysis>>) -> Vec<TaggedRule> {
+    rewrites
+        .into_iter()
+        .map(|rewrite| TaggedRule {
+            rewrite,
+            tags: vec![tag.to_string()],
+        })
+        .collect()
+}
+
+// Rules are actually automatically bidirectional once triggered by lhs.
+// Each section is tagged, so that profiles can pick them by kind.
+pub fn rules(tower: &Tower) -> Vec<TaggedRule> {
+    let mut rules = vec![];
+    rules.extend(tagged("comm", vec![
         rw!("comm-add";     "(+ ?a ?b)"    => "(+ ?b ?a)"),
         rw!("comm-mul";     "(* ?a ?b)"    => "(* ?b ?a)"),
+    ]));
 
-        // associativity
+    rules.extend(tagged("assoc", vec![
         rw!("assoc-mul";    "(* ?a (* ?b ?c))"     => "(* (* ?a ?b) ?c)"),
         rw!("assoc-add";    "(+ ?a (+ ?b ?c))"     => "(+ (+ ?a ?b) ?c)"),
+    ]));
 
-        // squaring
+    rules.extend(tagged("sq", vec![
         rw!("sq-to-mul";    "(sq ?x)"       => "(* ?x ?x)"),
         rw!("mul-to-sq";    "(* ?x ?x)"     => "(sq ?x)"),
+    ]));
 
-        // addition, subtraction
+    // addition, subtraction
+    rules.extend(tagged("cleanup", vec![
         rw!("sub";          "(- (+ ?a ?b) ?b)"      => "?a"),
         rw!("add-same";     "(+ ?a ?a)"             => "(* 2 ?a)"),
         rw!("sub-same";     "(- ?a ?a)"             => "0"),
+    ]));
 
-        // distributivity
+    // distributivity, binomial
+    rules.extend(tagged("dist", vec![
         rw!("dist-left";        "(* ?a (+ ?b ?c))"          => "(+ (* ?a ?b) (* ?a ?c))" if is_not_same("?b", "?c")),
         rw!("dist-right-add";   "(+ (* ?a ?c) (* ?b ?c))"   => "(* (+ ?a ?b) ?c)" if is_not_same("?a", "?b")),
         rw!("dist-right-sub";   "(- (* ?a ?c) (* ?b ?c))"   => "(* (- ?a ?b) ?c)" if is_not_same("?a", "?b")),
-        
-        // binomial
         rw!("binomial";         "(sq (+ ?a ?b))"    => "(+ (+ (sq ?a) (* 2 (* ?a ?b))) (sq ?b))"),
+    ]));
 
+    rules.extend(tagged("karatsuba", vec![
         // Benchmark 1
         rw!("benchmark1";   "(+ (* ?a ?b) (* ?c ?d))"   => "(- (- (* (+ ?a ?c) (+ ?d ?b)) (* ?a ?d)) (* ?c ?b))"
                                                             if is_not_same("?a", "?b")
@@ -61,19 +80,306 @@ pub fn rules() -> Vec<Rewrite<Math, TypeAnalysis>> {
 
         // // Benchmark 2
         rw!("mul2-binomial";    "(* 2 (* ?a ?b))"   => "(- (- (sq (+ ?a ?b)) (sq ?a)) (sq ?b))"),
+    ]));
 
-    ]
+    let mut inversion = vec![
+        rw!("inv-mul";          "(inv (* ?a ?b))"               => "(* (inv ?a) (inv ?b))"),
+        rw!("mul-inv";          "(* (inv ?a) (inv ?b))"         => "(inv (* ?a ?b))"),
+        rw!("inv-sq";           "(inv (sq ?a))"                 => "(sq (inv ?a))"),
+        // batch inversion (Montgomery's trick): one inv of the product for both
+        rw!("batch-inv-pair";   "(pair (inv ?a) (inv ?b))"      => "(pair (* ?b (inv (* ?a ?b))) (* ?a (inv (* ?a ?b))))" if is_not_same("?a", "?b")),
+        rw!("batch-inv-add";    "(+ (inv ?a) (inv ?b))"         => "(* (+ ?a ?b) (inv (* ?a ?b)))" if is_not_same("?a", "?b")),
+        rw!("batch-inv-sub";    "(- (inv ?a) (inv ?b))"         => "(* (- ?b ?a) (inv (* ?a ?b)))" if is_not_same("?a", "?b")),
+    ];
+    // lets the cost model pick between inv in the extension and the norm forms
+    inversion.extend(tower.levels_of_degree(2).map(pair_inv));
+    inversion.extend(tower.levels_of_degree(3).map(triple_inv));
+    rules.extend(tagged("inv", inversion));
+    rules
 }
 
-pub fn pair_rules() -> Vec<Rewrite<Math, TypeAnalysis>> {
+// (a + b·u)^-1 = (a - b·u) / (a² - ξ·b²), one inv in the base field instead of Fp2
+fn pair_inv(level: &TowerLevel) -> Rewrite<Math, TypeAnalysis> {
+    let nr = level.non_residue.as_str();
+    let norm = minus_non_residue_times(nr, "(sq ?a)", "(sq ?b)");
+    level_rw(
+        level,
+        "pair-inv",
+        "(inv (pair ?a ?b))",
+        &format!("(pair (* ?a (inv {norm})) (- 0 (* ?b (inv {norm}))))"),
+        &["?a", "?b"],
+        always,
+    )
+}
+
+// ξ·x, where ξ = -1 is just a negation.
+fn times_non_residue(non_residue: &str, x: &str) -> String {
+    if non_residue == "-1" {
+        format!("(- 0 {})", x)
+    } else {
+        format!("(* {} {})", x, non_residue)
+    }
+}
+
+// x + ξ·y
+fn plus_non_residue_times(non_residue: &str, x: &str, y: &str) -> String {
+    if non_residue == "-1" {
+        format!("(- {} {})", x, y)
+    } else {
+        format!("(+ {} {})", x, times_non_residue(non_residue, y))
+    }
+}
+
+// x - ξ·y
+fn minus_non_residue_times(non_residue: &str, x: &str, y: &str) -> String {
+    if non_residue == "-1" {
+        format!("(+ {} {})", x, y)
+    } else {
+        format!("(- {} {})", x, times_non_residue(non_residue, y))
+    }
+}
+
+fn always(_: &mut EGraph<Math, TypeAnalysis>, _: Id, _: &Subst) -> bool {
+    true
+}
+
+// A rule of `level`: it only fires when one of the `components` is in the
+// field the level extends, so each level gets its own non-residue.
+fn level_rw<C>(
+    level: &TowerLevel,
+    name: &str,
+    lhs: &str,
+    rhs: &str,
+    components: &[&str],
+    condition: C,
+) -> Rewrite<Math, TypeAnalysis>
+where
+    C: Fn(&mut EGraph<Math, TypeAnalysis>, Id, &Subst) -> bool + Send + Sync + 'static,
+{
+    let lhs: Pattern<Math> = lhs.parse().unwrap();
+    let rhs: Pattern<Math> = rhs.parse().unwrap();
+    let over = level.over.clone();
+    let components: Vec<Var> = components.iter().map(|v| v.parse().unwrap()).collect();
+    let applier = ConditionalApplier {
+        condition: move |egraph: &mut EGraph<Math, TypeAnalysis>, id, subst: &Subst| {
+            let in_level = match &over {
+                Some(over) => components
+                    .iter()
+                    .any(|v| egraph[egraph.find(subst[*v])].data.ty == *over),
+                None => true,
+            };
+            in_level && condition(egraph, id, subst)
+        },
+        applier: rhs,
+    };
+    Rewrite::new(level.rule_name(name), lhs, applier).unwrap()
+}
+
+/// The rules of the extensions the simplifier flattens, pairs and triples,
+/// one set per level of `tower`.
+pub fn extension_rules(tower: &Tower) -> Vec<Rewrite<Math, TypeAnalysis>> {
+    let mut rules = vec![];
+    for level in tower.levels_of_degree(2) {
+        rules.extend(pair_rules(level));
+    }
+    for level in tower.levels_of_degree(3) {
+        rules.extend(triple_rules(level));
+    }
+    rules
+}
+
+/// The pair rules of the quadratic extension u² = ξ of `level`. With ξ = `-1`
+/// the products by it become negations.
+pub fn pair_rules(level: &TowerLevel) -> Vec<Rewrite<Math, TypeAnalysis>> {
+    let nr = level.non_residue.as_str();
+    let ab = &["?a", "?b"];
+    let rw = |name, lhs, rhs: &str| level_rw(level, name, lhs, rhs, ab, always);
     vec![
 
-    rw!("pair-add";         "(+ (pair ?a ?b) (pair ?c ?d))"     =>  "(pair (+ ?a ?c) (+ ?b ?d))"),
-    rw!("pair-sub";         "(- (pair ?a ?b) (pair ?c ?d))"     =>  "(pair (- ?a ?c) (- ?b ?d))"),
-    rw!("pair-mul-const";   "(* (pair ?a ?b) ?c)"               =>  "(pair (* ?a ?c) (* ?b ?c))" if is_same_field("?c", "?a")),
-    rw!("pair-sq";          "(sq (pair ?a ?b))"                 =>  "(* (pair ?a ?b) (pair ?a ?b))"),
+    rw("pair-add",          "(+ (pair ?a ?b) (pair ?c ?d))",    "(pair (+ ?a ?c) (+ ?b ?d))"),
+    rw("pair-sub",          "(- (pair ?a ?b) (pair ?c ?d))",    "(pair (- ?a ?c) (- ?b ?d))"),
+    level_rw(level, "pair-mul-const", "(* (pair ?a ?b) ?c)",    "(pair (* ?a ?c) (* ?b ?c))", ab, is_same_field("?c", "?a")),
+    rw("pair-sq",           "(sq (pair ?a ?b))",                "(* (pair ?a ?b) (pair ?a ?b))"),
     // (a+bU)*(c+dU) = (a*c + a*dU + bU*c + bU*dU)
-    rw!("pair-mul";         "(* (pair ?a ?b) (pair ?c ?d))"     =>  "(pair (+ (* ?a ?c) (* (* ?b ?d) xi)) (+ (* ?a ?d) (* ?b ?c)))"),
+    rw("pair-mul", "(* (pair ?a ?b) (pair ?c ?d))",
+        &format!("(pair {} (+ (* ?a ?d) (* ?b ?c)))", plus_non_residue_times(nr, "(* ?a ?c)", "(* ?b ?d)"))),
+    // Karatsuba: 3 multiplications, a*d + b*c = (a+b)(c+d) - a*c - b*d
+    rw("pair-mul-karatsuba", "(* (pair ?a ?b) (pair ?c ?d))",
+        &format!("(pair {} (- (- (* (+ ?a ?b) (+ ?c ?d)) (* ?a ?c)) (* ?b ?d)))", plus_non_residue_times(nr, "(* ?a ?c)", "(* ?b ?d)"))),
+    // complex squaring: 2 multiplications, a² + ξb² = (a+b)(a+ξb) - ab - ξab
+    rw("pair-sq-complex", "(sq (pair ?a ?b))",
+        &if nr == "-1" {
+            "(pair (* (+ ?a ?b) (- ?a ?b)) (+ (* ?a ?b) (* ?a ?b)))".to_string()
+        } else {
+            format!("(pair (- (- (* (+ ?a ?b) {}) (* ?a ?b)) {}) (+ (* ?a ?b) (* ?a ?b)))",
+                plus_non_residue_times(nr, "?a", "?b"), times_non_residue(nr, "(* ?a ?b)"))
+        }),
+    pair_inv(level),
 
     ]
 }
+
+// (a0 + a1·v + a2·v²)^-1 over v³ = ξ: the cofactors t0, t1, t2 divided by the
+// norm a0·t0 + ξ(a2·t1 + a1·t2), one inv in the subfield instead of Fp6
+fn triple_inv(level: &TowerLevel) -> Rewrite<Math, TypeAnalysis> {
+    let nr = level.non_residue.as_str();
+    let t0 = minus_non_residue_times(nr, "(sq ?a0)", "(* ?a1 ?a2)");
+    let t1 = format!("(- {} (* ?a0 ?a1))", times_non_residue(nr, "(sq ?a2)"));
+    let t2 = "(- (sq ?a1) (* ?a0 ?a2))".to_string();
+    let norm = plus_non_residue_times(
+        nr,
+        &format!("(* ?a0 {t0})"),
+        &format!("(+ (* ?a2 {t1}) (* ?a1 {t2}))"),
+    );
+    level_rw(
+        level,
+        "triple-inv",
+        "(inv (triple ?a0 ?a1 ?a2))",
+        &format!("(triple (* {t0} (inv {norm})) (* {t1} (inv {norm})) (* {t2} (inv {norm})))"),
+        &["?a0", "?a1", "?a2"],
+        always,
+    )
+}
+
+/// The triple rules of the cubic extension v³ = ξ of `level`, written like
+/// the pair ones.
+pub fn triple_rules(level: &TowerLevel) -> Vec<Rewrite<Math, TypeAnalysis>> {
+    let nr = level.non_residue.as_str();
+    let xi = |x: &str, y: &str| plus_non_residue_times(nr, x, y);
+    let a = &["?a0", "?a1", "?a2"];
+    let rw = |name, lhs, rhs: &str| level_rw(level, name, lhs, rhs, a, always);
+    vec![
+
+    rw("triple-add",        "(+ (triple ?a0 ?a1 ?a2) (triple ?b0 ?b1 ?b2))",    "(triple (+ ?a0 ?b0) (+ ?a1 ?b1) (+ ?a2 ?b2))"),
+    rw("triple-sub",        "(- (triple ?a0 ?a1 ?a2) (triple ?b0 ?b1 ?b2))",    "(triple (- ?a0 ?b0) (- ?a1 ?b1) (- ?a2 ?b2))"),
+    level_rw(level, "triple-mul-const", "(* (triple ?a0 ?a1 ?a2) ?c)",          "(triple (* ?a0 ?c) (* ?a1 ?c) (* ?a2 ?c))", a, is_same_field("?c", "?a0")),
+    rw("triple-sq",         "(sq (triple ?a0 ?a1 ?a2))",                        "(* (triple ?a0 ?a1 ?a2) (triple ?a0 ?a1 ?a2))"),
+    rw("triple-c0",         "(c0 (triple ?a0 ?a1 ?a2))",                        "?a0"),
+    rw("triple-c1",         "(c1 (triple ?a0 ?a1 ?a2))",                        "?a1"),
+    rw("triple-c2",         "(c2 (triple ?a0 ?a1 ?a2))",                        "?a2"),
+    // schoolbook, with v³ = ξ folding the v³ and v⁴ terms back
+    rw("triple-mul", "(* (triple ?a0 ?a1 ?a2) (triple ?b0 ?b1 ?b2))",
+        &format!("(triple {} {} {})",
+            xi("(* ?a0 ?b0)", "(+ (* ?a1 ?b2) (* ?a2 ?b1))"),
+            xi("(+ (* ?a0 ?b1) (* ?a1 ?b0))", "(* ?a2 ?b2)"),
+            "(+ (+ (* ?a0 ?b2) (* ?a1 ?b1)) (* ?a2 ?b0))")),
+    // Karatsuba: 6 multiplications, with v0 = a0*b0, v1 = a1*b1, v2 = a2*b2
+    rw("triple-mul-karatsuba", "(* (triple ?a0 ?a1 ?a2) (triple ?b0 ?b1 ?b2))",
+        &format!("(triple {} {} {})",
+            xi("(* ?a0 ?b0)", "(- (- (* (+ ?a1 ?a2) (+ ?b1 ?b2)) (* ?a1 ?b1)) (* ?a2 ?b2))"),
+            xi("(- (- (* (+ ?a0 ?a1) (+ ?b0 ?b1)) (* ?a0 ?b0)) (* ?a1 ?b1))", "(* ?a2 ?b2)"),
+            "(- (+ (- (* (+ ?a0 ?a2) (+ ?b0 ?b2)) (* ?a0 ?b0)) (* ?a1 ?b1)) (* ?a2 ?b2))")),
+    // Chung-Hasan squaring: 2 multiplications and 3 squarings
+    rw("triple-sq-chung-hasan", "(sq (triple ?a0 ?a1 ?a2))",
+        &format!("(triple {} {} {})",
+            xi("(sq ?a0)", "(+ (* ?a1 ?a2) (* ?a1 ?a2))"),
+            xi("(+ (* ?a0 ?a1) (* ?a0 ?a1))", "(sq ?a2)"),
+            "(- (- (+ (+ (+ (* ?a0 ?a1) (* ?a0 ?a1)) (sq (+ (- ?a0 ?a1) ?a2))) (+ (* ?a1 ?a2) (* ?a1 ?a2))) (sq ?a0)) (sq ?a2))")),
+    triple_inv(level),
+
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::FieldType;
+    use crate::cost::{CostModel, MathCostFn, PairCostFn};
+    use egg::{Extractor, Runner};
+    use std::collections::HashMap;
+
+    fn level(degree: u32, non_residue: &str) -> TowerLevel {
+        TowerLevel {
+            over: None,
+            degree,
+            non_residue: non_residue.to_string(),
+        }
+    }
+
+    fn pair_products(expr: &str, non_residue: &str) -> usize {
+        products(expr, pair_rules(&level(2, non_residue)))
+    }
+
+    fn products(expr: &str, rules: Vec<Rewrite<Math, TypeAnalysis>>) -> usize {
+        let symbols = ["a", "b", "c", "d", "e", "f", "xi"]
+            .iter()
+            .map(|s| (s.to_string(), FieldType::Fp))
+            .collect::<HashMap<_, _>>();
+        let runner = Runner::default()
+            .with_egraph(EGraph::new(TypeAnalysis::new(symbols)))
+            .with_expr(&expr.parse().unwrap())
+            .with_iter_limit(5)
+            .run(&rules);
+        let extractor = Extractor::new(&runner.egraph, PairCostFn);
+        let (cost, _) = extractor.find_best(runner.roots[0]);
+        assert_eq!(cost.pairs, 10);
+        cost.products.len()
+    }
+
+    #[test]
+    fn test_karatsuba_and_complex_squaring() {
+        // a*c, b*d, (a+b)*(c+d) and the product by xi
+        assert_eq!(pair_products("(* (pair a b) (pair c d))", "xi"), 4);
+        assert_eq!(pair_products("(* (pair a b) (pair c d))", "-1"), 3);
+        // (a+b)*(a-b) and a*b
+        assert_eq!(pair_products("(sq (pair a b))", "-1"), 2);
+    }
+
+    #[test]
+    fn test_cubic_karatsuba() {
+        // 6 products and the two by xi, instead of 9 and two
+        let product = "(* (triple a b c) (triple d e f))";
+        assert_eq!(products(product, triple_rules(&level(3, "xi"))), 8);
+        assert_eq!(products(product, triple_rules(&level(3, "-1"))), 6);
+    }
+
+    #[test]
+    fn test_tower_levels() {
+        // Fp2 = Fp[u]/(u²+1) under Fp4 = Fp2[w]/(w²-xi): pairs of fp use -1
+        let tower = Tower {
+            levels: vec![
+                TowerLevel { over: Some(FieldType::Fp), ..level(2, "-1") },
+                TowerLevel { over: Some(FieldType::FpExt(2)), ..level(2, "xi") },
+            ],
+        };
+        assert_eq!(products("(* (pair a b) (pair c d))", extension_rules(&tower)), 3);
+    }
+
+    // The cheapest of (inv (pair a b)) under a model pricing inv in Fp2 at `fp2_inv`.
+    fn cheapest_inverse(fp2_inv: f64) -> String {
+        let symbols = HashMap::from([
+            ("a".to_string(), FieldType::Fp),
+            ("b".to_string(), FieldType::Fp),
+        ]);
+        let level = TowerLevel { over: Some(FieldType::Fp), ..level(2, "-1") };
+        let runner = Runner::default()
+            .with_egraph(EGraph::new(TypeAnalysis::new(symbols)))
+            .with_expr(&"(inv (pair a b))".parse().unwrap())
+            .with_iter_limit(5)
+            .run(&[pair_inv(&level)]);
+        let cost_model: CostModel = serde_json::from_str(&format!(
+            r#"{{
+                "costs": {{
+                    "fp":  {{ "+": 1, "-": 1, "*": 3, "inv": 10, "sq": 2 }},
+                    "fp2": {{ "inv": {} }}
+                }},
+                "default_costs": {{}}
+            }}"#,
+            fp2_inv
+        ))
+        .unwrap();
+        let costfn = MathCostFn::new(&runner.egraph, cost_model);
+        let (_, best) = Extractor::new(&runner.egraph, costfn).find_best(runner.roots[0]);
+        best.to_string()
+    }
+
+    #[test]
+    fn test_pair_inverse_norm_form() {
+        // The norm a² + b² is inverted once in Fp, for 37 against 50 in Fp2
+        assert_eq!(
+            cheapest_inverse(50.0),
+            "(pair (* a (inv (+ (sq a) (sq b)))) (- 0 (* b (inv (+ (sq a) (sq b))))))"
+        );
+        assert_eq!(cheapest_inverse(20.0), "(inv (pair a b))");
+    }
+}
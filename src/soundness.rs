@@ -0,0 +1,650 @@
+/*
+Checks that rewrite rules are sound by trying them on random elements of a concrete field.
+
+For each rule, the variables of its left-hand side are given random field types (the base
+field and the extensions of the tower) and random values of those types. The instance is
+added to a small egraph, with the types in the `TypeAnalysis` and the same characteristic,
+and the rule is applied to it, so its real conditions decide whether it fires and the
+constant folding of the analysis runs too. Then every e-node is evaluated: all the e-nodes
+of an e-class must have the same value, or the rule (or the folding) is unsound.
+
+The field is Fp for the characteristic of the run (BN254's by default), with each tower level
+built as Fp[u]/(u^degree - non_residue). Products of extension elements are reduced with the
+level's non-residue, inverses are found by solving the linear system of the product, so they
+don't depend on the inversion formulas being checked. A level without a field is taken over Fp.
+The tower doesn't have to be made of fields for this: the identities hold in any commutative
+ring, and instances whose inverse doesn't exist are skipped.
+
+Instances that our arithmetic can't type (say, a pair of an Fp2 and an Fp3 element) are
+skipped too, which is why each rule gets several attempts per sample.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+
+use egg::{AstSize, EGraph, ENodeOrVar, Extractor, Id, Language, RecExpr, Rewrite, Symbol};
+use num_bigint::BigInt;
+use num_traits::{One, Signed, Zero};
+use serde::Deserialize;
+
+use crate::analysis::{FieldType, TypeAnalysis};
+use crate::language::Math;
+use crate::rng::SplitMix64;
+use crate::tower::Tower;
+
+/// BN254's base field characteristic, used when the run has no modulus.
+pub const DEFAULT_CHARACTERISTIC: &str =
+    "21888242871839275222246405745257275088696311157297823662689037894645226208583";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Instances each rule is tried on.
+    pub samples: usize,
+    /// Seed of the non-residue values and of the instances.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            samples: 20,
+            seed: 0,
+        }
+    }
+}
+
+// Attempts per wanted sample, for rules whose instances are often ill-typed or rejected.
+const ATTEMPTS_PER_SAMPLE: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Shape {
+    Base,
+    Ext(usize, Box<Shape>),
+}
+
+impl Shape {
+    fn field_type(&self) -> FieldType {
+        match self {
+            Shape::Base => FieldType::Fp,
+            Shape::Ext(degree, sub) => FieldType::FpExt(*degree as u32 * sub.field_type().degree()),
+        }
+    }
+
+    fn dimension(&self) -> usize {
+        match self {
+            Shape::Base => 1,
+            Shape::Ext(degree, sub) => degree * sub.dimension(),
+        }
+    }
+}
+
+/// An element of the concrete tower: a residue, or the coefficients of an
+/// element of an extension level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Elem {
+    Base(BigInt),
+    Ext(Vec<Elem>),
+}
+
+impl Elem {
+    fn shape(&self) -> Shape {
+        match self {
+            Elem::Base(_) => Shape::Base,
+            Elem::Ext(coefficients) => {
+                Shape::Ext(coefficients.len(), Box::new(coefficients[0].shape()))
+            }
+        }
+    }
+
+    fn coordinates(&self, out: &mut Vec<BigInt>) {
+        match self {
+            Elem::Base(x) => out.push(x.clone()),
+            Elem::Ext(coefficients) => coefficients.iter().for_each(|c| c.coordinates(out)),
+        }
+    }
+}
+
+// Written like the terms: (pair 1 2), (triple ...).
+impl fmt::Display for Elem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Elem::Base(x) => write!(f, "{}", x),
+            Elem::Ext(coefficients) => {
+                let op = if coefficients.len() == 2 { "pair" } else { "triple" };
+                write!(f, "({}", op)?;
+                for c in coefficients {
+                    write!(f, " {}", c)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+struct Level {
+    degree: usize,
+    over: Shape,
+    non_residue: Elem,
+}
+
+/// The concrete field tower the rules are evaluated in.
+pub struct Field {
+    p: BigInt,
+    levels: Vec<Level>,
+    /// Values of the non-residue symbols.
+    symbols: HashMap<String, Elem>,
+}
+
+impl Field {
+    /// The tower over Fp, with random values for its symbol non-residues.
+    pub fn new(p: BigInt, tower: &Tower, rng: &mut SplitMix64) -> Result<Self, String> {
+        let mut field = Field {
+            p,
+            levels: vec![],
+            symbols: HashMap::new(),
+        };
+        for level in &tower.levels {
+            let over = match &level.over {
+                None => Shape::Base,
+                Some(ty) => field
+                    .shape_of(ty)
+                    .ok_or_else(|| format!("No earlier tower level builds {}", ty.to_string()))?,
+            };
+            let non_residue = match level.non_residue_symbol() {
+                Some(symbol) => match field.symbols.get(symbol) {
+                    Some(value) => field.embed(value, &over),
+                    None => {
+                        let value = field.random_nonzero(&over, rng);
+                        field.symbols.insert(symbol.to_string(), value.clone());
+                        Some(value)
+                    }
+                },
+                None => level
+                    .non_residue
+                    .parse::<RecExpr<Math>>()
+                    .ok()
+                    .and_then(|expr| field.eval_expr(&expr))
+                    .and_then(|value| field.embed(&value, &over)),
+            }
+            .ok_or_else(|| format!("Can't evaluate the non-residue {}", level.non_residue))?;
+            field.levels.push(Level {
+                degree: level.degree as usize,
+                over,
+                non_residue,
+            });
+        }
+        Ok(field)
+    }
+
+    fn shape_of(&self, ty: &FieldType) -> Option<Shape> {
+        match ty {
+            FieldType::Fp => Some(Shape::Base),
+            _ => self
+                .levels
+                .iter()
+                .map(|level| Shape::Ext(level.degree, Box::new(level.over.clone())))
+                .find(|shape| shape.field_type() == *ty),
+        }
+    }
+
+    // Fp and every level of the tower.
+    fn shapes(&self) -> Vec<Shape> {
+        let mut shapes = vec![Shape::Base];
+        for level in &self.levels {
+            let shape = Shape::Ext(level.degree, Box::new(level.over.clone()));
+            if !shapes.contains(&shape) {
+                shapes.push(shape);
+            }
+        }
+        shapes
+    }
+
+    fn level(&self, degree: usize, over: &Shape) -> Option<&Level> {
+        self.levels
+            .iter()
+            .find(|level| level.degree == degree && level.over == *over)
+    }
+
+    fn reduce(&self, x: BigInt) -> BigInt {
+        let x = x % &self.p;
+        if x.is_negative() { x + &self.p } else { x }
+    }
+
+    fn random(&self, shape: &Shape, rng: &mut SplitMix64) -> Elem {
+        match shape {
+            Shape::Base => {
+                let words: Vec<u64> = (0..self.p.bits().div_ceil(64) + 1).map(|_| rng.next()).collect();
+                let x = words.iter().fold(BigInt::zero(), |x, w| (x << 64) + BigInt::from(*w));
+                Elem::Base(self.reduce(x))
+            }
+            Shape::Ext(degree, sub) => Elem::Ext((0..*degree).map(|_| self.random(sub, rng)).collect()),
+        }
+    }
+
+    fn random_nonzero(&self, shape: &Shape, rng: &mut SplitMix64) -> Elem {
+        loop {
+            let x = self.random(shape, rng);
+            if x != self.zero(shape) {
+                return x;
+            }
+        }
+    }
+
+    fn zero(&self, shape: &Shape) -> Elem {
+        match shape {
+            Shape::Base => Elem::Base(BigInt::zero()),
+            Shape::Ext(degree, sub) => Elem::Ext(vec![self.zero(sub); *degree]),
+        }
+    }
+
+    fn one(&self, shape: &Shape) -> Elem {
+        self.embed(&Elem::Base(BigInt::one()), shape).unwrap()
+    }
+
+    // `x` as an element of `shape`, through the constant coefficients.
+    fn embed(&self, x: &Elem, shape: &Shape) -> Option<Elem> {
+        if x.shape() == *shape {
+            return Some(x.clone());
+        }
+        match shape {
+            Shape::Base => None,
+            Shape::Ext(degree, sub) => {
+                let mut coefficients = vec![self.embed(x, sub)?];
+                coefficients.extend((1..*degree).map(|_| self.zero(sub)));
+                Some(Elem::Ext(coefficients))
+            }
+        }
+    }
+
+    // Both in the larger of their fields.
+    fn unify(&self, a: &Elem, b: &Elem) -> Option<(Elem, Elem)> {
+        if let Some(a) = self.embed(a, &b.shape()) {
+            return Some((a, b.clone()));
+        }
+        Some((a.clone(), self.embed(b, &a.shape())?))
+    }
+
+    fn same(&self, a: &Elem, b: &Elem) -> bool {
+        self.unify(a, b).is_some_and(|(a, b)| a == b)
+    }
+
+    fn add(&self, a: &Elem, b: &Elem, sign: i32) -> Option<Elem> {
+        match self.unify(a, b)? {
+            (Elem::Base(x), Elem::Base(y)) => Some(Elem::Base(self.reduce(x + y * sign))),
+            (Elem::Ext(xs), Elem::Ext(ys)) => Some(Elem::Ext(
+                xs.iter()
+                    .zip(&ys)
+                    .map(|(x, y)| self.add(x, y, sign))
+                    .collect::<Option<_>>()?,
+            )),
+            _ => None,
+        }
+    }
+
+    fn mul(&self, a: &Elem, b: &Elem) -> Option<Elem> {
+        let (xs, ys) = match self.unify(a, b)? {
+            (Elem::Base(x), Elem::Base(y)) => return Some(Elem::Base(self.reduce(x * y))),
+            (Elem::Ext(xs), Elem::Ext(ys)) => (xs, ys),
+            _ => return None,
+        };
+        let degree = xs.len();
+        let sub = xs[0].shape();
+        let level = self.level(degree, &sub)?;
+        // Schoolbook product, then u^(degree + k) = non_residue * u^k.
+        let mut product = vec![self.zero(&sub); 2 * degree - 1];
+        for (i, x) in xs.iter().enumerate() {
+            for (j, y) in ys.iter().enumerate() {
+                product[i + j] = self.add(&product[i + j], &self.mul(x, y)?, 1)?;
+            }
+        }
+        let mut coefficients = product[..degree].to_vec();
+        for k in degree..2 * degree - 1 {
+            let folded = self.mul(&level.non_residue, &product[k])?;
+            coefficients[k - degree] = self.add(&coefficients[k - degree], &folded, 1)?;
+        }
+        Some(Elem::Ext(coefficients))
+    }
+
+    // Solves a * x = 1 over Fp, None if a isn't a unit.
+    fn inv(&self, a: &Elem) -> Option<Elem> {
+        let shape = a.shape();
+        let n = shape.dimension();
+        let basis = |j: usize| {
+            let mut coordinates = vec![BigInt::zero(); n];
+            coordinates[j] = BigInt::one();
+            self.from_coordinates(&shape, &mut coordinates.into_iter())
+        };
+        // Rows of [M | 1], where column j of M is a * e_j.
+        let mut rows = vec![vec![BigInt::zero(); n + 1]; n];
+        for j in 0..n {
+            let mut column = vec![];
+            self.mul(a, &basis(j))?.coordinates(&mut column);
+            for (i, x) in column.into_iter().enumerate() {
+                rows[i][j] = x;
+            }
+        }
+        let mut one = vec![];
+        self.one(&shape).coordinates(&mut one);
+        for (row, x) in rows.iter_mut().zip(one) {
+            row[n] = x;
+        }
+
+        for col in 0..n {
+            let pivot = (col..n).find(|&r| !rows[r][col].is_zero())?;
+            rows.swap(col, pivot);
+            let scale = rows[col][col].modinv(&self.p)?;
+            for x in rows[col].iter_mut() {
+                *x = self.reduce(&*x * &scale);
+            }
+            for r in 0..n {
+                if r != col && !rows[r][col].is_zero() {
+                    let factor = rows[r][col].clone();
+                    for c in 0..=n {
+                        let x = &rows[r][c] - &factor * &rows[col][c];
+                        rows[r][c] = self.reduce(x);
+                    }
+                }
+            }
+        }
+        let mut solution = rows.into_iter().map(|row| row[n].clone());
+        Some(self.from_coordinates(&shape, &mut solution))
+    }
+
+    fn from_coordinates(&self, shape: &Shape, coordinates: &mut impl Iterator<Item = BigInt>) -> Elem {
+        match shape {
+            Shape::Base => Elem::Base(coordinates.next().unwrap()),
+            Shape::Ext(degree, sub) => {
+                Elem::Ext((0..*degree).map(|_| self.from_coordinates(sub, coordinates)).collect())
+            }
+        }
+    }
+
+    // A pair or triple, if the tower has that level.
+    // The element of the smallest level of that degree every part embeds into, so
+    // constant coefficients are lifted into the level's field: (triple 0 1 0) is
+    // built over Fp2 in the BN254 tower.
+    fn construct(&self, parts: &[&Elem]) -> Option<Elem> {
+        self.levels
+            .iter()
+            .filter(|level| level.degree == parts.len())
+            .filter_map(|level| {
+                parts
+                    .iter()
+                    .map(|x| self.embed(x, &level.over))
+                    .collect::<Option<Vec<Elem>>>()
+                    .map(|coefficients| (level.over.dimension(), coefficients))
+            })
+            .min_by_key(|(dimension, _)| *dimension)
+            .map(|(_, coefficients)| Elem::Ext(coefficients))
+    }
+
+    fn project(&self, x: &Elem, degree: usize, i: usize) -> Option<Elem> {
+        match x {
+            Elem::Ext(coefficients) if coefficients.len() == degree => Some(coefficients[i].clone()),
+            _ => None,
+        }
+    }
+
+    /// The value of `node` from its children's, None if it is ill-typed or
+    /// divides by a non-unit.
+    fn eval(
+        &self,
+        node: &Math,
+        symbols: &HashMap<String, Elem>,
+        child: impl Fn(Id) -> Option<Elem>,
+    ) -> Option<Elem> {
+        match node {
+            Math::Add([a, b]) => self.add(&child(*a)?, &child(*b)?, 1),
+            Math::Sub([a, b]) => self.add(&child(*a)?, &child(*b)?, -1),
+            Math::Mul([a, b]) => self.mul(&child(*a)?, &child(*b)?),
+            Math::Sq(x) => {
+                let x = child(*x)?;
+                self.mul(&x, &x)
+            }
+            Math::Inv(x) => self.inv(&child(*x)?),
+            Math::Pair([a, b]) => self.construct(&[&child(*a)?, &child(*b)?]),
+            Math::Triple([a, b, c]) => self.construct(&[&child(*a)?, &child(*b)?, &child(*c)?]),
+            Math::Fst(x) => self.project(&child(*x)?, 2, 0),
+            Math::Snd(x) => self.project(&child(*x)?, 2, 1),
+            Math::C0(x) => self.project(&child(*x)?, 3, 0),
+            Math::C1(x) => self.project(&child(*x)?, 3, 1),
+            Math::C2(x) => self.project(&child(*x)?, 3, 2),
+            Math::Constant(c) => Some(Elem::Base(self.reduce(c.0.clone()))),
+            Math::Symbol(s) => symbols.get(s.as_str()).cloned(),
+        }
+    }
+
+    fn eval_expr(&self, expr: &RecExpr<Math>) -> Option<Elem> {
+        let mut values: Vec<Option<Elem>> = vec![];
+        for node in expr.as_ref() {
+            let value = self.eval(node, &self.symbols, |id| values[usize::from(id)].clone());
+            values.push(value);
+        }
+        values.pop().flatten()
+    }
+}
+
+/// How a rule fared.
+pub enum Verdict {
+    /// Applied to this many instances without a disagreement.
+    Sound(usize),
+    /// Never applied to a well-typed instance.
+    NotApplied,
+    Unsound(String),
+}
+
+/// Try `rewrite` on up to `samples` random instances of its left-hand side.
+pub fn check_rule(
+    rewrite: &Rewrite<Math, TypeAnalysis>,
+    field: &Field,
+    tower: &Tower,
+    samples: usize,
+    rng: &mut SplitMix64,
+) -> Verdict {
+    let Some(ast) = rewrite.searcher.get_pattern_ast() else {
+        return Verdict::NotApplied;
+    };
+    let shapes = field.shapes();
+    let mut applied = 0;
+    for _ in 0..samples * ATTEMPTS_PER_SAMPLE {
+        if applied == samples {
+            break;
+        }
+        // The instance, with each variable turned into a symbol of a random field.
+        let mut expr = RecExpr::default();
+        let mut symbols = field.symbols.clone();
+        let mut symbol_types = HashMap::new();
+        let mut assignment = vec![];
+        for node in ast.as_ref() {
+            match node {
+                ENodeOrVar::ENode(node) => expr.add(node.clone()),
+                ENodeOrVar::Var(var) => {
+                    let name = var.to_string();
+                    if !symbols.contains_key(&name) {
+                        let shape = &shapes[rng.below(shapes.len())];
+                        let value = field.random(shape, rng);
+                        assignment.push(format!("{} = {}", name, value));
+                        symbols.insert(name.clone(), value);
+                        symbol_types.insert(name.clone(), shape.field_type());
+                    }
+                    expr.add(Math::Symbol(Symbol::from(name.as_str())))
+                }
+            };
+        }
+
+        let analysis = TypeAnalysis::new(symbol_types)
+            .with_modulus(Some(field.p.clone()))
+            .with_tower(tower);
+        let mut egraph = EGraph::new(analysis);
+        let root = egraph.add_expr(&expr);
+        egraph.rebuild();
+        if evaluate(field, &egraph, &symbols).get(&egraph.find(root)).is_none() {
+            continue;
+        }
+        let matches = rewrite.search(&egraph);
+        if rewrite.apply(&mut egraph, &matches).is_empty() {
+            continue;
+        }
+        egraph.rebuild();
+        applied += 1;
+
+        if let Some(disagreement) = disagreement(field, &egraph, &symbols) {
+            return Verdict::Unsound(format!(
+                "{} with {}: {}",
+                expr,
+                assignment.join(", "),
+                disagreement
+            ));
+        }
+    }
+    if applied == 0 { Verdict::NotApplied } else { Verdict::Sound(applied) }
+}
+
+// The value of every e-class that has a well-typed e-node.
+fn evaluate(
+    field: &Field,
+    egraph: &EGraph<Math, TypeAnalysis>,
+    symbols: &HashMap<String, Elem>,
+) -> HashMap<Id, Elem> {
+    let mut values: HashMap<Id, Elem> = HashMap::new();
+    loop {
+        let mut changed = false;
+        for class in egraph.classes() {
+            if values.contains_key(&class.id) {
+                continue;
+            }
+            let value = class.nodes.iter().find_map(|node| {
+                field.eval(node, symbols, |id| values.get(&egraph.find(id)).cloned())
+            });
+            if let Some(value) = value {
+                values.insert(class.id, value);
+                changed = true;
+            }
+        }
+        if !changed {
+            return values;
+        }
+    }
+}
+
+// An e-node whose value isn't its e-class's.
+fn disagreement(
+    field: &Field,
+    egraph: &EGraph<Math, TypeAnalysis>,
+    symbols: &HashMap<String, Elem>,
+) -> Option<String> {
+    let values = evaluate(field, egraph, symbols);
+    let extractor = Extractor::new(egraph, AstSize);
+    for class in egraph.classes() {
+        let Some(expected) = values.get(&class.id) else {
+            continue;
+        };
+        for node in &class.nodes {
+            let value = field.eval(node, symbols, |id| values.get(&egraph.find(id)).cloned());
+            if let Some(value) = value.filter(|value| !field.same(value, expected)) {
+                let (_, term) = extractor.find_best(class.id);
+                let other = node.join_recexprs(|id| extractor.find_best(id).1);
+                return Some(format!("{} = {} but {} = {}", term, expected, other, value));
+            }
+        }
+    }
+    None
+}
+
+/// Check every rule, print a line for each, and whether they were all sound.
+pub fn check_rules(
+    rewrites: &[Rewrite<Math, TypeAnalysis>],
+    tower: &Tower,
+    modulus: Option<BigInt>,
+    config: &Config,
+) -> Result<bool, String> {
+    let p = modulus.unwrap_or_else(|| DEFAULT_CHARACTERISTIC.parse().unwrap());
+    let mut rng = SplitMix64(config.seed);
+    let field = Field::new(p, tower, &mut rng)?;
+    for (symbol, value) in &field.symbols {
+        println!("Non-residue {} = {}", symbol, value);
+    }
+
+    let mut sound = true;
+    for rewrite in rewrites {
+        match check_rule(rewrite, &field, tower, config.samples, &mut rng) {
+            Verdict::Sound(applied) => println!("{}: ok on {} instances", rewrite.name, applied),
+            Verdict::NotApplied => println!("{}: never applied", rewrite.name),
+            Verdict::Unsound(counterexample) => {
+                sound = false;
+                println!("{}: UNSOUND, {}", rewrite.name, counterexample);
+            }
+        }
+    }
+    Ok(sound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::{extension_rules, rules};
+    use egg::rewrite as rw;
+
+    #[test]
+    fn test_rules_are_sound() {
+        let tower = Tower::default();
+        let mut rng = SplitMix64(1);
+        let field = Field::new(BigInt::from(1_000_003), &tower, &mut rng).unwrap();
+        let mut rewrites: Vec<_> = rules(&tower).into_iter().map(|rule| rule.rewrite).collect();
+        rewrites.extend(extension_rules(&tower));
+        for rewrite in &rewrites {
+            if let Verdict::Unsound(counterexample) = check_rule(rewrite, &field, &tower, 3, &mut rng) {
+                panic!("{} is unsound: {}", rewrite.name, counterexample);
+            }
+        }
+
+        let wrong: Rewrite<Math, TypeAnalysis> = rw!("wrong"; "(sq (+ ?a ?b))" => "(+ (sq ?a) (sq ?b))");
+        assert!(matches!(check_rule(&wrong, &field, &tower, 3, &mut rng), Verdict::Unsound(_)));
+    }
+
+    #[test]
+    fn test_readme_tower() {
+        // The non-residue of Fp12 is a triple of constants, lifted into Fp2.
+        let tower: Tower = serde_json::from_str(
+            r#"[
+                { "over": "fp",  "degree": 2, "non_residue": "-1" },
+                { "over": "fp2", "degree": 3, "non_residue": "xi" },
+                { "over": "fp6", "degree": 2, "non_residue": "(triple 0 1 0)" }
+            ]"#,
+        )
+        .unwrap();
+        let mut rng = SplitMix64(1);
+        let field = Field::new(BigInt::from(1_000_003), &tower, &mut rng).unwrap();
+        let mut applied = 0;
+        for rewrite in &extension_rules(&tower) {
+            match check_rule(rewrite, &field, &tower, 3, &mut rng) {
+                Verdict::Unsound(counterexample) => {
+                    panic!("{} is unsound: {}", rewrite.name, counterexample)
+                }
+                Verdict::Sound(_) => applied += 1,
+                Verdict::NotApplied => {}
+            }
+        }
+        assert!(applied > 0);
+    }
+
+    #[test]
+    fn test_extension_arithmetic() {
+        // Fp2 = F7[u]/(u² + 1): u * u = -1, and (1 + u)^-1 = (1 - u) / 2
+        let tower = Tower {
+            levels: vec![crate::tower::TowerLevel {
+                over: Some(FieldType::Fp),
+                degree: 2,
+                non_residue: "-1".to_string(),
+            }],
+        };
+        let field = Field::new(BigInt::from(7), &tower, &mut SplitMix64(0)).unwrap();
+        let base = |x: i64| Elem::Base(BigInt::from(x));
+        let u = Elem::Ext(vec![base(0), base(1)]);
+        assert_eq!(field.mul(&u, &u), Some(Elem::Ext(vec![base(6), base(0)])));
+        let inverse = field.inv(&Elem::Ext(vec![base(1), base(1)])).unwrap();
+        assert_eq!(inverse, Elem::Ext(vec![base(4), base(3)]));
+        assert_eq!(field.inv(&Elem::Ext(vec![base(0), base(0)])), None);
+    }
+}
@@ -0,0 +1,115 @@
+use egg::RecExpr;
+
+use crate::language::Math;
+
+/// One line of the tests file: either a single expression, or a named group
+/// of expressions that are optimized jointly, so intermediate values shared
+/// between the outputs are only paid for once.
+///
+/// A group is written as
+///
+/// (group fp6_mul (+ (* a0 b0) ...) (+ (* a0 b1) ...) (+ (* a0 b2) ...))
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: Option<String>,
+    pub exprs: Vec<RecExpr<Math>>,
+}
+
+impl TestCase {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let Some(body) = group_body(line) else {
+            let expr = line
+                .parse()
+                .map_err(|_| format!("Invalid expr: {}", line))?;
+            return Ok(TestCase {
+                name: None,
+                exprs: vec![expr],
+            });
+        };
+
+        let mut items = split_top_level(body)?.into_iter();
+        let name = items
+            .next()
+            .filter(|name| !name.starts_with('('))
+            .ok_or_else(|| format!("Group without a name: {}", line))?;
+        let exprs = items
+            .map(|item| item.parse().map_err(|_| format!("Invalid expr: {}", item)))
+            .collect::<Result<Vec<RecExpr<Math>>, String>>()?;
+        if exprs.is_empty() {
+            return Err(format!("Empty group: {}", name));
+        }
+        Ok(TestCase {
+            name: Some(name.to_string()),
+            exprs,
+        })
+    }
+}
+
+// What's inside `(group ...)`, or None if the line isn't a group.
+fn group_body(line: &str) -> Option<&str> {
+    let inner = line.strip_prefix('(')?.strip_suffix(')')?.trim_start();
+    let rest = inner.strip_prefix("group")?;
+    rest.starts_with(char::is_whitespace).then_some(rest)
+}
+
+// Splits a list of atoms and s-expressions at the top level.
+fn split_top_level(body: &str) -> Result<Vec<&str>, String> {
+    let mut items = vec![];
+    let mut depth = 0usize;
+    let mut start = None;
+    for (i, c) in body.char_indices() {
+        match c {
+            '(' => {
+                if depth == 0 {
+                    start.get_or_insert(i);
+                }
+                depth += 1;
+            }
+            ')' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| format!("Unbalanced parentheses: {}", body))?;
+                if depth == 0 {
+                    items.push(&body[start.take().unwrap()..=i]);
+                }
+            }
+            _ if c.is_whitespace() => {
+                if depth == 0 {
+                    if let Some(s) = start.take() {
+                        items.push(&body[s..i]);
+                    }
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if depth != 0 {
+        return Err(format!("Unbalanced parentheses: {}", body));
+    }
+    if let Some(s) = start {
+        items.push(&body[s..]);
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_group() {
+        let case = TestCase::parse("(group g (* a b) c (+ (* a b) d))").unwrap();
+        assert_eq!(case.name.as_deref(), Some("g"));
+        let exprs: Vec<String> = case.exprs.iter().map(|e| e.to_string()).collect();
+        assert_eq!(exprs, vec!["(* a b)", "c", "(+ (* a b) d)"]);
+
+        let case = TestCase::parse("(* a b)").unwrap();
+        assert!(case.name.is_none());
+        assert_eq!(case.exprs.len(), 1);
+
+        assert!(TestCase::parse("(group (* a b))").is_err());
+        assert!(TestCase::parse("(group g)").is_err());
+    }
+}
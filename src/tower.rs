@@ -0,0 +1,134 @@
+use egg::Pattern;
+use serde::Deserialize;
+
+use crate::analysis::FieldType;
+use crate::language::Math;
+
+/// One step of an extension tower: `over[u] / (u^degree - non_residue)`.
+///
+/// JSON format example, the BN254 tower Fp2 = Fp[u]/(u²+1),
+/// Fp6 = Fp2[v]/(v³-ξ), Fp12 = Fp6[w]/(w²-v):
+///
+/// "tower": [
+///   { "over": "fp",  "degree": 2, "non_residue": "-1" },
+///   { "over": "fp2", "degree": 3, "non_residue": "xi" },
+///   { "over": "fp6", "degree": 2, "non_residue": "(triple 0 1 0)" }
+/// ]
+///
+/// Levels of degree 2 are written with `pair`, those of degree 3 with
+/// `triple`. Without `over` a level applies to pairs or triples of any field,
+/// which is how the default tower, `xi` for both, behaves.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TowerLevel {
+    /// The field being extended, which the non-residue belongs to.
+    #[serde(default)]
+    pub over: Option<FieldType>,
+    pub degree: u32,
+    /// A symbol, a constant or a term of `over`.
+    pub non_residue: String,
+}
+
+impl TowerLevel {
+    /// `name`, qualified by the field when there may be several levels.
+    pub fn rule_name(&self, name: &str) -> String {
+        match &self.over {
+            Some(over) => format!("{}@{}", name, over.to_string()),
+            None => name.to_string(),
+        }
+    }
+
+    /// The non-residue, if it is a plain symbol.
+    pub fn non_residue_symbol(&self) -> Option<&str> {
+        let is_symbol = !self.non_residue.is_empty()
+            && !self.non_residue.starts_with('(')
+            && !self.non_residue.contains(char::is_whitespace)
+            && self.non_residue.parse::<crate::language::Constant>().is_err();
+        is_symbol.then_some(self.non_residue.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Tower {
+    pub levels: Vec<TowerLevel>,
+}
+
+impl Default for Tower {
+    fn default() -> Self {
+        let any = |degree| TowerLevel {
+            over: None,
+            degree,
+            non_residue: "xi".to_string(),
+        };
+        Tower {
+            levels: vec![any(2), any(3)],
+        }
+    }
+}
+
+impl Tower {
+    pub fn validate(&self) -> Result<(), String> {
+        for level in &self.levels {
+            if level.degree != 2 && level.degree != 3 {
+                return Err(format!(
+                    "Tower levels must have degree 2 or 3, got {} over {:?}",
+                    level.degree, level.over
+                ));
+            }
+            if level.non_residue.contains('?') {
+                return Err(format!("The non-residue can't be a pattern: {}", level.non_residue));
+            }
+            level
+                .non_residue
+                .parse::<Pattern<Math>>()
+                .map_err(|e| format!("Invalid non-residue {}: {}", level.non_residue, e))?;
+        }
+        let mut seen = Vec::new();
+        for level in &self.levels {
+            let key = (level.over.clone(), level.degree);
+            if seen.contains(&key) {
+                return Err(format!(
+                    "Two tower levels of degree {} over {:?}",
+                    level.degree, level.over
+                ));
+            }
+            seen.push(key);
+        }
+        Ok(())
+    }
+
+    /// The levels written with `pair` (degree 2) or `triple` (degree 3).
+    pub fn levels_of_degree(&self, degree: u32) -> impl Iterator<Item = &TowerLevel> {
+        self.levels.iter().filter(move |level| level.degree == degree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tower_spec() {
+        let tower: Tower = serde_json::from_str(
+            r#"[
+                { "over": "fp",  "degree": 2, "non_residue": "-1" },
+                { "over": "fp2", "degree": 3, "non_residue": "xi" },
+                { "over": "fp6", "degree": 2, "non_residue": "(triple 0 1 0)" }
+            ]"#,
+        )
+        .unwrap();
+        assert!(tower.validate().is_ok());
+        assert_eq!(tower.levels[1].over, Some(FieldType::FpExt(2)));
+        assert_eq!(tower.levels[0].non_residue_symbol(), None);
+        assert_eq!(tower.levels[1].non_residue_symbol(), Some("xi"));
+        assert_eq!(tower.levels[2].non_residue_symbol(), None);
+        assert_eq!(tower.levels[2].rule_name("pair-mul"), "pair-mul@fp6");
+
+        let mut twice = tower.clone();
+        twice.levels.push(tower.levels[0].clone());
+        assert!(twice.validate().is_err());
+        let mut quartic = tower;
+        quartic.levels[0].degree = 4;
+        assert!(quartic.validate().is_err());
+    }
+}
@@ -56,7 +56,7 @@ def ast_to_sexpr(ast):
 def run_optimizer(test_case_file, cost_model_file, symbol_types_file):
     # Build and run the Rust project using Cargo
     result = subprocess.run(
-        ["cargo", "run", "--release", "--", symbol_types_file, cost_model_file, test_case_file],
+        ["cargo", "run", "--release", "--", symbol_types_file, cost_model_file, test_case_file] + optimizer_flags,
         capture_output=True,
         text=True
     )
@@ -68,8 +68,70 @@ parser.add_argument("-t", "--tests", default="inputs/tests.txt", help="Path to t
 parser.add_argument("-c", "--cost_model", default="inputs/cost_model.json", help="Path to cost model JSON file")
 parser.add_argument("-s", "--symbol_types", default="inputs/symbol_types.json", help="Path to symbol types JSON file")
 parser.add_argument('-f', '--full_search', action='store_true', help='Enable full search in other finite fields (towering)')
+parser.add_argument("-r", "--run_config", help="Path to run configuration JSON file (default: run_config.json next to the cost model)")
+parser.add_argument("-e", "--extractor", choices=["greedy", "ilp", "bnb", "local"], help="DAG extractor to use")
+parser.add_argument("--timeout", type=int, help="Timeout of each DAG extraction in seconds")
+parser.add_argument("--seed", type=int, help="Random seed of the local search extractor")
+parser.add_argument("--modulus", metavar="P", help="Characteristic of the field, constants are folded modulo P (decimal or 0x hex)")
+parser.add_argument("--rules_file", metavar="FILE", help="JSON file of extra rewrite rules, added to the built-in ones")
+parser.add_argument("--rules", action="append", default=[], metavar="NAME|TAG", help="Only saturate with the rules of this name or tag, e.g. builtin (repeatable)")
+parser.add_argument("--profile", metavar="NAME", help="Saturation profile of the run configuration, e.g. fp12_mul")
+parser.add_argument("--check_rules", action="store_true", help="Only check the selected rewrite rules on random field elements, and report unsound ones")
+parser.add_argument("--budget", type=int, help="Wall-clock budget for the whole run in seconds")
+parser.add_argument("-k", "--top_k", type=int, help="Also print the k cheapest distinct DAG programs")
+parser.add_argument("-p", "--pareto", action="store_true", help="Also print the (cost, latency) Pareto frontier")
+parser.add_argument("--forbid", action="append", default=[], metavar="FIELD:OP", help="Never extract this operation, e.g. fp2:inv (repeatable)")
+parser.add_argument("--max_ops", action="append", default=[], metavar="FIELD:OP=N", help="Extract this operation at most N times, e.g. fp2:*=3 (repeatable)")
+parser.add_argument("--export_model", metavar="FILE", help="Write each test case's ILP model to FILE (.lp or .mps), numbered by test case")
+parser.add_argument("--read_solution", metavar="FILE", help="Read each test case's optimized DAG from a solution FILE of the exported model, numbered the same way")
+parser.add_argument("--dump_egraph", metavar="FILE", help="Write each test case's saturated egraph to FILE as egraph-serialize JSON, numbered by test case")
+parser.add_argument("--load_egraph", metavar="FILE", help="Only run the DAG extractor on a saved egraph-serialize JSON FILE")
+parser.add_argument("--dot", metavar="FILE", help="Render each saturated egraph with the extracted DAG highlighted to a Graphviz FILE, numbered by test case")
+parser.add_argument("--dot_reachable", action="store_true", help="Only render the classes of the extracted DAG")
 args = parser.parse_args()
 
+optimizer_flags = []
+if args.run_config is not None:
+    optimizer_flags += ["--run-config", args.run_config]
+if args.extractor is not None:
+    optimizer_flags += ["--extractor", args.extractor]
+if args.timeout is not None:
+    optimizer_flags += ["--timeout", str(args.timeout)]
+if args.seed is not None:
+    optimizer_flags += ["--seed", str(args.seed)]
+if args.modulus is not None:
+    optimizer_flags += ["--modulus", args.modulus]
+if args.rules_file is not None:
+    optimizer_flags += ["--rules-file", args.rules_file]
+for rule in args.rules:
+    optimizer_flags += ["--rules", rule]
+if args.profile is not None:
+    optimizer_flags += ["--profile", args.profile]
+if args.check_rules:
+    optimizer_flags += ["--check-rules"]
+if args.budget is not None:
+    optimizer_flags += ["--budget", str(args.budget)]
+if args.top_k is not None:
+    optimizer_flags += ["--top-k", str(args.top_k)]
+if args.pareto:
+    optimizer_flags += ["--pareto"]
+for label in args.forbid:
+    optimizer_flags += ["--forbid", label]
+for limit in args.max_ops:
+    optimizer_flags += ["--max-ops", limit]
+if args.export_model is not None:
+    optimizer_flags += ["--export-model", args.export_model]
+if args.read_solution is not None:
+    optimizer_flags += ["--read-solution", args.read_solution]
+if args.dump_egraph is not None:
+    optimizer_flags += ["--dump-egraph", args.dump_egraph]
+if args.load_egraph is not None:
+    optimizer_flags += ["--load-egraph", args.load_egraph]
+if args.dot is not None:
+    optimizer_flags += ["--dot", args.dot]
+if args.dot_reachable:
+    optimizer_flags += ["--dot-reachable"]
+
 test_case_file = args.tests
 cost_model_file = args.cost_model
 symbol_types_file = args.symbol_types